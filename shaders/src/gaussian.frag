@@ -1,12 +1,52 @@
 #version 450
 
+layout(set = 2, binding = 0) uniform DisplayData {
+    float gamma;
+    float exposure;
+} u_Display;
+
+layout(set = 2, binding = 1) uniform ClipData {
+    vec4 origin;
+    // Unit normal of the slice's planes
+    vec4 direction;
+    // The distances of the planes along the direction, and whether the slice is enabled
+    vec4 range;
+} u_Clip;
+
+layout(location = 0) in vec3 f_WorldPosition;
+
 layout(set = 3, binding = 1) uniform ColorData {
-    vec3 color;
+    // The alpha is the point's opacity
+    vec4 color;
 } u_Color;
 
 layout(location = 0) out vec4 f_Color;
 
+// Thresholds of a 4x4 ordered dithering pattern
+const float BAYER_4X4[16] = float[](
+    0.0, 8.0, 2.0, 10.0,
+    12.0, 4.0, 14.0, 6.0,
+    3.0, 11.0, 1.0, 9.0,
+    15.0, 7.0, 13.0, 5.0
+);
+
 void main() {
-    f_Color = vec4(u_Color.color, 1.0);
-}
+    if (u_Clip.range.z > 0.0) {
+        float distance = dot(f_WorldPosition - u_Clip.origin.xyz, u_Clip.direction.xyz);
+        if (distance < u_Clip.range.x || distance > u_Clip.range.y) {
+            discard;
+        }
+    }
+
+    // Screen-door transparency rather than blending: every point is its own entity, drawn in no
+    // particular order, so blended points would show through or hide each other depending on that
+    // order. Only a share of each point's fragments matching its opacity is drawn and the others are
+    // discarded, which keeps the depth test exact.
+    ivec2 cell = ivec2(gl_FragCoord.xy) % 4;
+    if (u_Color.color.a < (BAYER_4X4[cell.y * 4 + cell.x] + 0.5) / 16.0) {
+        discard;
+    }
 
+    vec3 color = clamp(u_Color.color.rgb * u_Display.exposure, 0.0, 1.0);
+    f_Color = vec4(pow(color, vec3(1.0 / u_Display.gamma)), 1.0);
+}
@@ -2,6 +2,8 @@
 
 layout(location = 0) in vec3 v_Position;
 
+layout(location = 0) out vec3 f_WorldPosition;
+
 layout(push_constant) uniform CameraData {
     mat4 viewProjection;
     vec4 worldPos;
@@ -12,7 +14,7 @@ layout(set = 3, binding = 0) uniform ModelData { mat4 modelMatrix; }
 u_ModelData;
 
 void main() {
-    mat4 transform = pc_CameraData.viewProjection * u_ModelData.modelMatrix;
-    gl_Position = transform * vec4(v_Position, 1);
+    vec4 worldPosition = u_ModelData.modelMatrix * vec4(v_Position, 1);
+    f_WorldPosition = worldPosition.xyz;
+    gl_Position = pc_CameraData.viewProjection * worldPosition;
 }
-
@@ -0,0 +1,55 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::camera::CameraState;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Bookmark {
+    pub name: String,
+    pub camera: CameraState,
+}
+
+/// The viewer state saved to the bookmarks file.
+#[derive(Default, Serialize, Deserialize)]
+pub struct ViewerSettings {
+    #[serde(default)]
+    pub bookmarks: Vec<Bookmark>,
+    #[serde(default)]
+    pub background_color: Option<[f32; 3]>,
+}
+
+/// Files written before the background color was saved only contain the bookmarks.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SettingsFile {
+    Settings(ViewerSettings),
+    Bookmarks(Vec<Bookmark>),
+}
+
+/// Reads the settings saved in the file, or returns the defaults if it doesn't exist yet.
+pub fn load_settings(path: &Path) -> Result<ViewerSettings> {
+    if !path.exists() {
+        return Ok(ViewerSettings::default());
+    }
+
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read bookmarks file {}", path.to_string_lossy()))?;
+    let file = serde_json::from_str(&contents)
+        .with_context(|| format!("Invalid bookmarks file {}", path.to_string_lossy()))?;
+
+    Ok(match file {
+        SettingsFile::Settings(settings) => settings,
+        SettingsFile::Bookmarks(bookmarks) => ViewerSettings {
+            bookmarks,
+            ..Default::default()
+        },
+    })
+}
+
+pub fn save_settings(path: &Path, settings: &ViewerSettings) -> Result<()> {
+    let contents = serde_json::to_string_pretty(settings)?;
+    std::fs::write(path, contents)
+        .with_context(|| format!("Failed to write bookmarks file {}", path.to_string_lossy()))
+}
@@ -0,0 +1,244 @@
+use anyhow::Result;
+use cv::prelude::*;
+use morrigu::math_types::{Mat3, Vec2, Vec3};
+use opencv as cv;
+
+use crate::{pose, render_state::Point, tracks::Observation};
+
+/// Minimum number of points a camera must see for its pose to be refined.
+const MIN_CAMERA_POINTS: usize = 6;
+/// Gauss-Newton steps taken on each point per iteration.
+const POINT_STEPS: usize = 3;
+
+/// A camera of the adjustment, P = K * [R|t].
+struct Camera {
+    k: Mat3,
+    rotation: Mat3,
+    translation: Vec3,
+}
+
+impl Camera {
+    /// The projection of the point in pixels, and the jacobian of its coordinates with respect to
+    /// the point. Returns `None` for points behind (or in the plane of) the camera.
+    fn project(&self, position: Vec3) -> Option<(Vec2, [Vec3; 2])> {
+        let kr = self.k * self.rotation;
+        let projected = kr * position + self.k * self.translation;
+        if projected.z <= f32::EPSILON {
+            return None;
+        }
+
+        let pixel = Vec2::new(projected.x, projected.y) / projected.z;
+        let (row0, row1, row2) = (kr.row(0), kr.row(1), kr.row(2));
+        let jacobian = [
+            (row0 - pixel.x * row2) / projected.z,
+            (row1 - pixel.y * row2) / projected.z,
+        ];
+
+        Some((pixel, jacobian))
+    }
+}
+
+fn keypoint_position(
+    keypoints: &[cv::core::Vector<cv::core::KeyPoint>],
+    &(image_idx, keypoint_idx): &Observation,
+) -> Result<Vec2> {
+    let keypoint = keypoints[image_idx].get(keypoint_idx)?.pt();
+    Ok(Vec2::new(keypoint.x, keypoint.y))
+}
+
+/// Mean reprojection error of the point over its views, in pixels.
+fn point_error(
+    cameras: &[Camera],
+    keypoints: &[cv::core::Vector<cv::core::KeyPoint>],
+    track: &[Observation],
+    position: Vec3,
+) -> Result<f32> {
+    let mut error = 0.0;
+    for observation in track {
+        let observed = keypoint_position(keypoints, observation)?;
+        error += match cameras[observation.0].project(position) {
+            Some((pixel, _)) => pixel.distance(observed),
+            None => f32::INFINITY,
+        };
+    }
+
+    Ok(error / track.len() as f32)
+}
+
+/// Mean of the errors, leaving out the points behind one of their cameras.
+fn mean_error(errors: &[f32]) -> f32 {
+    let finite_errors = errors.iter().filter(|error| error.is_finite());
+    finite_errors.clone().sum::<f32>() / finite_errors.count().max(1) as f32
+}
+
+/// Structure-only step: moves the point to reduce its reprojection error in its views, the cameras
+/// being fixed. Steps that would increase the error are discarded.
+fn refine_point(
+    cameras: &[Camera],
+    keypoints: &[cv::core::Vector<cv::core::KeyPoint>],
+    track: &[Observation],
+    position: &mut Vec3,
+) -> Result<()> {
+    let mut error = point_error(cameras, keypoints, track, *position)?;
+    for _ in 0..POINT_STEPS {
+        // Normal equations of the Gauss-Newton step, J^T J dx = -J^T r
+        let mut jtj = Mat3::ZERO;
+        let mut jtr = Vec3::ZERO;
+        for observation in track {
+            let Some((pixel, jacobian)) = cameras[observation.0].project(*position) else {
+                return Ok(());
+            };
+            let residual = pixel - keypoint_position(keypoints, observation)?;
+            for (row, residual) in jacobian.iter().zip(residual.to_array()) {
+                jtj += Mat3::from_cols(*row * row.x, *row * row.y, *row * row.z);
+                jtr += *row * residual;
+            }
+        }
+        if jtj.determinant().abs() <= f32::EPSILON {
+            return Ok(());
+        }
+
+        let candidate = *position - jtj.inverse() * jtr;
+        let candidate_error = point_error(cameras, keypoints, track, candidate)?;
+        if !candidate.is_finite() || candidate_error >= error {
+            return Ok(());
+        }
+        *position = candidate;
+        error = candidate_error;
+    }
+
+    Ok(())
+}
+
+/// Motion-only step: refines the pose of the camera against the points it sees with OpenCV's
+/// Levenberg-Marquardt PnP refinement, the intrinsics and the points being fixed.
+fn refine_camera(camera: &mut Camera, points: &[Vec3], pixels: &[Vec2]) -> Result<()> {
+    let object_points = points
+        .iter()
+        .map(|point| cv::core::Point3f::new(point.x, point.y, point.z))
+        .collect::<cv::core::Vector<_>>();
+    let image_points = pixels
+        .iter()
+        .map(|pixel| cv::core::Point2f::new(pixel.x, pixel.y))
+        .collect::<cv::core::Vector<_>>();
+
+    let to_f64 = |values: Vec<f32>| values.into_iter().map(f64::from).collect::<Vec<_>>();
+    let k = cv::core::Mat::from_slice_rows_cols(
+        &to_f64(camera.k.transpose().to_cols_array().to_vec()),
+        3,
+        3,
+    )?;
+    let rotation = cv::core::Mat::from_slice_rows_cols(
+        &to_f64(camera.rotation.transpose().to_cols_array().to_vec()),
+        3,
+        3,
+    )?;
+    let mut rotation_vector = cv::core::Mat::default();
+    cv::calib3d::rodrigues_def(&rotation, &mut rotation_vector)?;
+    let mut translation =
+        cv::core::Mat::from_slice_rows_cols(&to_f64(camera.translation.to_array().to_vec()), 3, 1)?;
+
+    cv::calib3d::solve_pnp_refine_lm_def(
+        &object_points,
+        &image_points,
+        &k,
+        &cv::core::no_array(),
+        &mut rotation_vector,
+        &mut translation,
+    )?;
+
+    let mut rotation = cv::core::Mat::default();
+    cv::calib3d::rodrigues_def(&rotation_vector, &mut rotation)?;
+    let rotation = Mat3::from_cols_slice(&pose::mat_values(&rotation)?).transpose();
+    let translation = Vec3::from_slice(&pose::mat_values(&translation)?);
+    if rotation.is_finite() && translation.is_finite() {
+        camera.rotation = rotation;
+        camera.translation = translation;
+    }
+
+    Ok(())
+}
+
+/// Refines the poses and the points of the tracks together, to minimize the reprojection error of
+/// all the observations. Each iteration alternates a structure-only step (every point is refined with
+/// the cameras fixed) and a motion-only step (every camera is refined with the points fixed). The
+/// intrinsics are kept, and so is the first camera, which anchors the reconstruction. The reprojection
+/// errors of the points are updated.
+pub fn bundle_adjust(
+    poses: &mut [cv::core::Mat],
+    keypoints: &[cv::core::Vector<cv::core::KeyPoint>],
+    tracks: &[&[Observation]],
+    points: &mut [Point],
+    iterations: usize,
+) -> Result<()> {
+    let mut cameras = poses
+        .iter()
+        .map(|pose| -> Result<Camera> {
+            let (k, rotation, translation) = pose::decompose_projection(pose)?;
+            Ok(Camera {
+                k,
+                rotation,
+                translation,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let errors = |cameras: &[Camera], points: &[Point]| -> Result<Vec<f32>> {
+        tracks
+            .iter()
+            .zip(points)
+            .map(|(track, point)| point_error(cameras, keypoints, track, point.position))
+            .collect()
+    };
+    let initial_error = mean_error(&errors(&cameras, points)?);
+
+    for iteration in 0..iterations {
+        for (track, point) in tracks.iter().zip(points.iter_mut()) {
+            refine_point(&cameras, keypoints, track, &mut point.position)?;
+        }
+
+        for (camera_idx, camera) in cameras.iter_mut().enumerate().skip(1) {
+            let mut camera_points = vec![];
+            let mut camera_pixels = vec![];
+            for (track, point) in tracks.iter().zip(points.iter()) {
+                for observation in track
+                    .iter()
+                    .filter(|(image_idx, _)| *image_idx == camera_idx)
+                {
+                    camera_points.push(point.position);
+                    camera_pixels.push(keypoint_position(keypoints, observation)?);
+                }
+            }
+            if camera_points.len() >= MIN_CAMERA_POINTS {
+                refine_camera(camera, &camera_points, &camera_pixels)?;
+            }
+        }
+
+        log::debug!(
+            "\tbundle adjustment iteration {}: mean reprojection error {:.3}px",
+            iteration + 1,
+            mean_error(&errors(&cameras, points)?)
+        );
+    }
+
+    let final_errors = errors(&cameras, points)?;
+    for (point, error) in points.iter_mut().zip(&final_errors) {
+        point.reproj_error = Some(*error);
+    }
+    for (pose, camera) in poses.iter_mut().zip(&cameras) {
+        *pose = pose::projection_matrix(
+            &cv::core::Mat::from_slice_rows_cols(&camera.k.transpose().to_cols_array(), 3, 3)?,
+            &camera.rotation,
+            &camera.translation,
+        )?;
+    }
+    log::info!(
+        "Bundle adjustment over {} points and {} cameras: mean reprojection error {:.3}px -> {:.3}px",
+        points.len(),
+        cameras.len(),
+        initial_error,
+        mean_error(&final_errors)
+    );
+
+    Ok(())
+}
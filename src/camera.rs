@@ -1,31 +1,161 @@
-use std::time::Duration;
+use std::{borrow::Cow, time::Duration};
 
 use morrigu::{
-    components::camera::Camera,
-    math_types::{EulerRot, Quat, Vec2, Vec3},
-    winit::keyboard::KeyCode,
+    components::camera::{Camera, PerspectiveData, Projection},
+    math_types::{EulerRot, Mat3, Quat, Vec2, Vec3},
 };
+use serde::{Deserialize, Serialize};
 use winit_input_helper::WinitInputHelper;
 
+use crate::controls::{Controls, Key};
+
+/// Distance between the camera and its focal point when the viewer starts.
+const DEFAULT_DISTANCE: f32 = 7.0;
+/// Bounds of the base movement speed, adjusted with Ctrl + scroll.
+pub const MIN_MOVE_SPEED: f32 = 0.1;
+pub const MAX_MOVE_SPEED: f32 = 100.0;
+/// Factor applied to the movement speed while Shift is held.
+const SPRINT_MULTIPLIER: f32 = 4.0;
+
+/// A key or mouse input and what it does, listed in the viewer's help.
+#[derive(Clone)]
+pub struct Binding {
+    pub input: Cow<'static, str>,
+    pub action: &'static str,
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Forward,
+    Backward,
+    Left,
+    Right,
+    Down,
+    Up,
+}
+
+impl Direction {
+    fn vector(self, camera: &Camera) -> Vec3 {
+        match self {
+            Direction::Forward => camera.forward_vector(),
+            Direction::Backward => -camera.forward_vector(),
+            Direction::Left => camera.right_vector(),
+            Direction::Right => -camera.right_vector(),
+            Direction::Down => -camera.up_vector(),
+            Direction::Up => camera.up_vector(),
+        }
+    }
+}
+
+/// The controls handled by `ViewerCamera::on_update` that can't be remapped.
+const CAMERA_BINDINGS: [Binding; 6] = [
+    Binding {
+        input: Cow::Borrowed("Left drag"),
+        action: "Orbit around the focal point (orbit camera), look around (free camera)",
+    },
+    Binding {
+        input: Cow::Borrowed("Right drag"),
+        action: "Zoom (orbit camera)",
+    },
+    Binding {
+        input: Cow::Borrowed("Middle drag"),
+        action: "Pan (orbit camera)",
+    },
+    Binding {
+        input: Cow::Borrowed("Scroll"),
+        action: "Zoom (orbit camera)",
+    },
+    Binding {
+        input: Cow::Borrowed("Ctrl + scroll"),
+        action: "Change the movement speed",
+    },
+    Binding {
+        input: Cow::Borrowed("Shift"),
+        action: "Hold to move faster",
+    },
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CameraMode {
+    /// Rotates around and moves a focal point, which stays at the center of the view.
+    Orbit,
+    /// Moves the camera itself, rotating in place.
+    Free,
+}
+
+/// Everything needed to restore a view of the scene.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct CameraState {
+    pub position: [f32; 3],
+    pub pitch: f32,
+    pub yaw: f32,
+    pub roll: f32,
+    pub focal_point: [f32; 3],
+    pub distance: f32,
+}
+
+/// A copy of the camera with another perspective projection, at the same pose.
+fn with_perspective(camera: &Camera, perspective: PerspectiveData) -> Camera {
+    let size = camera.size();
+    let mut new_camera = Camera::builder().build(
+        Projection::Perspective(perspective),
+        &Vec2::new(size.x, size.y),
+    );
+    new_camera.set_pitch(camera.pitch());
+    new_camera.set_yaw(camera.yaw());
+    new_camera.set_roll(camera.roll());
+    new_camera.set_position(camera.position());
+
+    new_camera
+}
+
+/// Orientation of a camera looking along `forward`, with its up vector as close as possible to `up`.
+/// Cameras look down their -Z axis, with +Y up and +X to the right. If `forward` and `up` are
+/// parallel, an arbitrary perpendicular up vector is used.
+pub fn look_at_rotation(forward: Vec3, up: Vec3) -> Quat {
+    let forward = forward.normalize();
+    let right = forward
+        .cross(up)
+        .try_normalize()
+        .unwrap_or_else(|| forward.any_orthonormal_vector());
+    let up = right.cross(forward);
+
+    Quat::from_mat3(&Mat3::from_cols(right, up, -forward))
+}
+
 pub struct ViewerCamera {
     pub mrg_camera: Camera,
     pub move_speed: f32,
     pub distance: f32,
     pub mouse_input_factor: f32,
+    /// Flip the horizontal and vertical mouse movements before they rotate, pan or zoom the camera.
+    pub invert_x: bool,
+    pub invert_y: bool,
+    /// Must match the projection of `mrg_camera`, used to map points to screen space.
+    pub horizontal_fov: f32,
 
     focal_point: Vec3,
+    initial_camera: Camera,
+    mode: CameraMode,
+    controls: Controls,
 }
 
 impl ViewerCamera {
-    pub fn new(mrg_camera: Camera) -> Self {
+    pub fn new(mrg_camera: Camera, horizontal_fov: f32, controls: Controls) -> Self {
         let focal_point = Default::default();
 
         let mut new_camera = Self {
             mrg_camera,
             move_speed: 4.0,
-            distance: 7.0,
+            distance: DEFAULT_DISTANCE,
             mouse_input_factor: 0.003,
+            invert_x: false,
+            invert_y: false,
+            horizontal_fov,
             focal_point,
+            initial_camera: mrg_camera,
+            mode: CameraMode::Orbit,
+            controls,
         };
 
         new_camera.set_focal_point(&focal_point);
@@ -44,23 +174,151 @@ impl ViewerCamera {
         self.mrg_camera.set_position(&new_position);
     }
 
-    pub fn lookat_temp(&mut self, new_focal_point: &Vec3) {
-        let current_forward = self.mrg_camera.forward_vector();
-        let desired_forward = *new_focal_point - *self.mrg_camera.position();
-        let axis = current_forward.cross(desired_forward);
-        let angle = current_forward.angle_between(desired_forward);
+    pub fn state(&self) -> CameraState {
+        CameraState {
+            position: self.mrg_camera.position().to_array(),
+            pitch: self.mrg_camera.pitch(),
+            yaw: self.mrg_camera.yaw(),
+            roll: self.mrg_camera.roll(),
+            focal_point: self.focal_point.to_array(),
+            distance: self.distance,
+        }
+    }
 
-        let rotation = Quat::from_axis_angle(axis, angle);
-        let (pitch, yaw, roll) = rotation.to_euler(EulerRot::YZX);
+    pub fn restore(&mut self, state: &CameraState) {
+        self.mrg_camera.set_pitch(state.pitch);
+        self.mrg_camera.set_yaw(state.yaw);
+        self.mrg_camera.set_roll(state.roll);
+        self.mrg_camera
+            .set_position(&Vec3::from_array(state.position));
+        self.focal_point = Vec3::from_array(state.focal_point);
+        self.distance = state.distance;
+    }
 
-        self.distance = desired_forward.length();
+    pub fn controls(&self) -> &Controls {
+        &self.controls
+    }
 
-        self.mrg_camera.set_pitch(self.mrg_camera.pitch() + pitch);
-        self.mrg_camera.set_yaw(self.mrg_camera.yaw() + yaw);
-        self.mrg_camera.set_roll(self.mrg_camera.roll() + roll);
+    pub fn mode(&self) -> CameraMode {
+        self.mode
+    }
 
-        let new_position = *self.focal_point() - self.mrg_camera.forward_vector() * self.distance;
-        self.mrg_camera.set_position(&new_position);
+    pub fn set_mode(&mut self, mode: CameraMode) {
+        if mode == CameraMode::Orbit && self.mode == CameraMode::Free {
+            // Orbit around the point in front of the camera, without moving it
+            self.focal_point =
+                *self.mrg_camera.position() + self.mrg_camera.forward_vector() * self.distance;
+        }
+        self.mode = mode;
+    }
+
+    /// Changes the field of view (in radians) and the clipping planes of the camera, keeping its pose.
+    /// The camera keeps them when it is reset.
+    pub fn set_perspective(&mut self, horizontal_fov: f32, near_plane: f32, far_plane: f32) {
+        let perspective = || PerspectiveData {
+            horizontal_fov,
+            near_plane,
+            far_plane,
+        };
+        self.mrg_camera = with_perspective(&self.mrg_camera, perspective());
+        self.initial_camera = with_perspective(&self.initial_camera, perspective());
+        self.horizontal_fov = horizontal_fov;
+    }
+
+    /// Restores the orientation the camera had when it was created, looking at the origin from the
+    /// default distance.
+    pub fn reset(&mut self) {
+        let size = self.mrg_camera.size();
+        self.mrg_camera = self.initial_camera;
+        self.mrg_camera.on_resize(size.x as u32, size.y as u32);
+        self.distance = DEFAULT_DISTANCE;
+        self.set_focal_point(&Vec3::ZERO);
+    }
+
+    /// Moves the focal point to the center of the bounding box, and backs the camera up so that the
+    /// whole box is in frame, keeping the current orientation.
+    pub fn fit_to_bounds(&mut self, min: &Vec3, max: &Vec3) {
+        let center = (*min + *max) / 2.0;
+        let radius = (*max - *min).length() / 2.0;
+
+        let size = self.mrg_camera.size();
+        let vertical_fov = 2.0 * ((self.horizontal_fov / 2.0).tan() * size.y / size.x).atan();
+        let fov = self.horizontal_fov.min(vertical_fov);
+
+        // The bounding sphere of the box fits in the narrowest field of view
+        self.distance = (radius / (fov / 2.0).sin()).max(f32::EPSILON);
+        self.set_focal_point(&center);
+    }
+
+    /// Moves the camera to `eye` and orients it towards `target`, with its up vector as close as
+    /// possible to `up`. The orientation is set directly rather than rotated from the current one, so
+    /// it doesn't depend on where the camera was looking before. The target becomes the focal point.
+    pub fn look_at(&mut self, eye: &Vec3, target: &Vec3, up: &Vec3) {
+        let (pitch, yaw, roll) = look_at_rotation(*target - *eye, *up).to_euler(EulerRot::YZX);
+        self.mrg_camera.set_pitch(pitch);
+        self.mrg_camera.set_yaw(yaw);
+        self.mrg_camera.set_roll(roll);
+        self.mrg_camera.set_position(eye);
+
+        self.focal_point = *target;
+        self.distance = eye.distance(*target).max(f32::EPSILON);
+    }
+
+    /// Projects a world space point to window coordinates (in pixels, from the top left corner), or
+    /// returns `None` if the point is behind the camera.
+    pub fn project_to_screen(&self, point: &Vec3) -> Option<Vec2> {
+        let forward = self.mrg_camera.forward_vector();
+        let up = self.mrg_camera.up_vector();
+        let right = forward.cross(up);
+
+        let relative = *point - *self.mrg_camera.position();
+        let depth = relative.dot(forward);
+        if depth <= 0.0 {
+            return None;
+        }
+
+        let size = self.mrg_camera.size();
+        let half_width = (self.horizontal_fov / 2.0).tan() * depth;
+        let half_height = half_width * size.y / size.x;
+        let ndc = Vec2::new(
+            relative.dot(right) / half_width,
+            relative.dot(up) / half_height,
+        );
+
+        Some(Vec2::new(
+            (ndc.x + 1.0) * 0.5 * size.x,
+            (1.0 - ndc.y) * 0.5 * size.y,
+        ))
+    }
+
+    /// The keys moving the camera in free mode, and its focal point in orbit mode. `on_update` and the
+    /// help both read this table, so they can't get out of sync.
+    fn movement_keys(&self) -> [(Key, Direction, &'static str); 6] {
+        [
+            (self.controls.forward, Direction::Forward, "Move forward"),
+            (self.controls.backward, Direction::Backward, "Move backward"),
+            (self.controls.left, Direction::Left, "Move left"),
+            (self.controls.right, Direction::Right, "Move right"),
+            (self.controls.down, Direction::Down, "Move down"),
+            (self.controls.up, Direction::Up, "Move up"),
+        ]
+    }
+
+    /// Every control of the camera, for the viewer's help.
+    pub fn bindings(&self) -> impl Iterator<Item = Binding> {
+        let toggle_mode = Binding {
+            input: self.controls.toggle_mode.to_string().into(),
+            action: "Switch between the orbit and free cameras",
+        };
+        let movement = self.movement_keys().map(|(key, _, action)| Binding {
+            input: key.to_string().into(),
+            action,
+        });
+
+        CAMERA_BINDINGS
+            .into_iter()
+            .chain([toggle_mode])
+            .chain(movement)
     }
 
     pub fn on_resize(&mut self, width: u32, height: u32) {
@@ -68,8 +326,21 @@ impl ViewerCamera {
     }
 
     pub fn on_update(&mut self, dt: Duration, input: &WinitInputHelper) {
+        if input.key_pressed(self.controls.toggle_mode.0) {
+            self.set_mode(match self.mode {
+                CameraMode::Orbit => CameraMode::Free,
+                CameraMode::Free => CameraMode::Orbit,
+            });
+        }
+
         let diff = input.mouse_diff();
-        let mouse_delta = Vec2::new(diff.0, -diff.1) * self.mouse_input_factor;
+        let mut mouse_delta = Vec2::new(diff.0, -diff.1) * self.mouse_input_factor;
+        if self.invert_x {
+            mouse_delta.x = -mouse_delta.x;
+        }
+        if self.invert_y {
+            mouse_delta.y = -mouse_delta.y;
+        }
 
         #[repr(usize)]
         enum MouseButton {
@@ -78,65 +349,68 @@ impl ViewerCamera {
             Middle = 2,
         }
         if input.mouse_held(MouseButton::Left as usize) {
-            self.mouse_rotate(&mouse_delta);
-        }
-        if input.mouse_held(MouseButton::Right as usize) {
-            self.mouse_zoom(mouse_delta.y * 5.0);
+            match self.mode {
+                CameraMode::Orbit => self.mouse_rotate(&mouse_delta),
+                CameraMode::Free => self.mouse_look(&mouse_delta),
+            }
         }
-        if input.mouse_held(MouseButton::Middle as usize) {
-            self.mouse_pan(&mouse_delta);
+        if self.mode == CameraMode::Orbit {
+            if input.mouse_held(MouseButton::Right as usize) {
+                self.mouse_zoom(mouse_delta.y * 5.0);
+            }
+            if input.mouse_held(MouseButton::Middle as usize) {
+                self.mouse_pan(&mouse_delta);
+            }
         }
 
         let scroll = input.scroll_diff().1;
         if scroll != 0.0 {
-            self.mouse_zoom(scroll * 0.4);
-        }
-
-        if input.key_held(KeyCode::KeyW) {
-            let forward = self.mrg_camera.forward_vector();
-            let new_focal_point =
-                *self.focal_point() + forward * dt.as_secs_f32() * self.move_speed;
-            self.set_focal_point(&new_focal_point);
-        }
-
-        if input.key_held(KeyCode::KeyS) {
-            let forward = self.mrg_camera.forward_vector();
-            let new_focal_point =
-                *self.focal_point() - forward * dt.as_secs_f32() * self.move_speed;
-            self.set_focal_point(&new_focal_point);
-        }
-
-        if input.key_held(KeyCode::KeyA) {
-            let right = self.mrg_camera.right_vector();
-            let new_focal_point = *self.focal_point() + right * dt.as_secs_f32() * self.move_speed;
-            self.set_focal_point(&new_focal_point);
+            if input.held_control() {
+                self.move_speed =
+                    (self.move_speed * 1.1_f32.powf(scroll)).clamp(MIN_MOVE_SPEED, MAX_MOVE_SPEED);
+            } else if self.mode == CameraMode::Orbit {
+                self.mouse_zoom(scroll * 0.4);
+            }
         }
 
-        if input.key_held(KeyCode::KeyD) {
-            let right = self.mrg_camera.right_vector();
-            let new_focal_point = *self.focal_point() - right * dt.as_secs_f32() * self.move_speed;
-            self.set_focal_point(&new_focal_point);
-        }
+        let move_speed = match input.held_shift() {
+            true => self.move_speed * SPRINT_MULTIPLIER,
+            false => self.move_speed,
+        };
 
-        if input.key_held(KeyCode::KeyQ) {
-            let up = self.mrg_camera.up_vector();
-            let new_focal_point = *self.focal_point() - up * dt.as_secs_f32() * self.move_speed;
-            self.set_focal_point(&new_focal_point);
+        for (key, direction, _) in self.movement_keys() {
+            if input.key_held(key.0) {
+                let offset = direction.vector(&self.mrg_camera);
+                self.translate(offset * dt.as_secs_f32() * move_speed);
+            }
         }
+    }
 
-        if input.key_held(KeyCode::KeyE) {
-            let up = self.mrg_camera.up_vector();
-            let new_focal_point = *self.focal_point() + up * dt.as_secs_f32() * self.move_speed;
-            self.set_focal_point(&new_focal_point);
+    /// Moves the focal point in orbit mode, and the camera itself in free mode.
+    fn translate(&mut self, offset: Vec3) {
+        match self.mode {
+            CameraMode::Orbit => {
+                let new_focal_point = *self.focal_point() + offset;
+                self.set_focal_point(&new_focal_point);
+            }
+            CameraMode::Free => {
+                let new_position = *self.mrg_camera.position() + offset;
+                self.mrg_camera.set_position(&new_position);
+            }
         }
     }
 
-    fn mouse_rotate(&mut self, delta: &Vec2) {
+    /// Rotates the camera in place, on the same axes as `mouse_rotate`.
+    fn mouse_look(&mut self, delta: &Vec2) {
         let new_pitch = self.mrg_camera.pitch() + -delta.x * 0.8;
         self.mrg_camera.set_pitch(new_pitch);
 
         let new_roll = self.mrg_camera.roll() + delta.y * 0.8;
         self.mrg_camera.set_roll(new_roll);
+    }
+
+    fn mouse_rotate(&mut self, delta: &Vec2) {
+        self.mouse_look(delta);
 
         let new_position = *self.focal_point() - self.mrg_camera.forward_vector() * self.distance;
         self.mrg_camera.set_position(&new_position);
@@ -165,3 +439,59 @@ impl ViewerCamera {
         self.set_focal_point(&new_focal_point);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOLERANCE: f32 = 1e-4;
+
+    fn viewer_camera() -> ViewerCamera {
+        let horizontal_fov = 70.0f32.to_radians();
+        let camera = Camera::builder().build(
+            Projection::Perspective(PerspectiveData {
+                horizontal_fov,
+                near_plane: 0.001,
+                far_plane: 1000.0,
+            }),
+            &Vec2::new(1280.0, 720.0),
+        );
+
+        ViewerCamera::new(camera, horizontal_fov, Controls::default())
+    }
+
+    #[test]
+    fn look_at_rotation_faces_forward() {
+        let forward = Vec3::new(1.0, -2.0, 0.5).normalize();
+        let rotation = look_at_rotation(forward, Vec3::Y);
+
+        assert!((rotation * Vec3::NEG_Z).abs_diff_eq(forward, TOLERANCE));
+        // The camera's right vector stays horizontal
+        assert!((rotation * Vec3::X).y.abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn look_at_points_the_camera_at_the_target() {
+        let mut camera = viewer_camera();
+        for (eye, target) in [
+            (Vec3::new(0.0, 0.0, 5.0), Vec3::ZERO),
+            (Vec3::new(3.0, 2.0, 5.0), Vec3::ZERO),
+            (Vec3::new(-1.0, 0.5, 0.0), Vec3::new(4.0, -1.0, 2.0)),
+            (Vec3::new(0.0, 0.0, -6.0), Vec3::new(0.0, 1.0, 0.0)),
+        ] {
+            camera.look_at(&eye, &target, &Vec3::Y);
+
+            let forward = camera.mrg_camera.forward_vector();
+            assert!(
+                forward.abs_diff_eq((target - eye).normalize(), TOLERANCE),
+                "{:?} from {:?} to {:?}",
+                forward,
+                eye,
+                target
+            );
+            assert!(camera.mrg_camera.position().abs_diff_eq(eye, TOLERANCE));
+            assert_eq!(*camera.focal_point(), target);
+            assert!((camera.distance - eye.distance(target)).abs() < TOLERANCE);
+        }
+    }
+}
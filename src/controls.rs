@@ -0,0 +1,162 @@
+use std::{fmt, path::Path};
+
+use anyhow::{anyhow, bail, Context, Result};
+use morrigu::winit::keyboard::KeyCode;
+use serde::Deserialize;
+
+/// The names of the keys that can be bound in the controls file. Keys are physical: the letters
+/// are those of a US QWERTY keyboard, so the default W/A/S/D (and Q/E) already sit under
+/// Z/Q/S/D (and A/E) on an AZERTY keyboard. This table lets users pick other keys altogether.
+const KEY_NAMES: [(&str, KeyCode); 57] = [
+    ("A", KeyCode::KeyA),
+    ("B", KeyCode::KeyB),
+    ("C", KeyCode::KeyC),
+    ("D", KeyCode::KeyD),
+    ("E", KeyCode::KeyE),
+    ("F", KeyCode::KeyF),
+    ("G", KeyCode::KeyG),
+    ("H", KeyCode::KeyH),
+    ("I", KeyCode::KeyI),
+    ("J", KeyCode::KeyJ),
+    ("K", KeyCode::KeyK),
+    ("L", KeyCode::KeyL),
+    ("M", KeyCode::KeyM),
+    ("N", KeyCode::KeyN),
+    ("O", KeyCode::KeyO),
+    ("P", KeyCode::KeyP),
+    ("Q", KeyCode::KeyQ),
+    ("R", KeyCode::KeyR),
+    ("S", KeyCode::KeyS),
+    ("T", KeyCode::KeyT),
+    ("U", KeyCode::KeyU),
+    ("V", KeyCode::KeyV),
+    ("W", KeyCode::KeyW),
+    ("X", KeyCode::KeyX),
+    ("Y", KeyCode::KeyY),
+    ("Z", KeyCode::KeyZ),
+    ("0", KeyCode::Digit0),
+    ("1", KeyCode::Digit1),
+    ("2", KeyCode::Digit2),
+    ("3", KeyCode::Digit3),
+    ("4", KeyCode::Digit4),
+    ("5", KeyCode::Digit5),
+    ("6", KeyCode::Digit6),
+    ("7", KeyCode::Digit7),
+    ("8", KeyCode::Digit8),
+    ("9", KeyCode::Digit9),
+    ("Up", KeyCode::ArrowUp),
+    ("Down", KeyCode::ArrowDown),
+    ("Left", KeyCode::ArrowLeft),
+    ("Right", KeyCode::ArrowRight),
+    ("Space", KeyCode::Space),
+    ("Home", KeyCode::Home),
+    ("End", KeyCode::End),
+    ("PageUp", KeyCode::PageUp),
+    ("PageDown", KeyCode::PageDown),
+    ("Insert", KeyCode::Insert),
+    ("Delete", KeyCode::Delete),
+    ("Numpad0", KeyCode::Numpad0),
+    ("Numpad1", KeyCode::Numpad1),
+    ("Numpad2", KeyCode::Numpad2),
+    ("Numpad3", KeyCode::Numpad3),
+    ("Numpad4", KeyCode::Numpad4),
+    ("Numpad5", KeyCode::Numpad5),
+    ("Numpad6", KeyCode::Numpad6),
+    ("Numpad7", KeyCode::Numpad7),
+    ("Numpad8", KeyCode::Numpad8),
+    ("Numpad9", KeyCode::Numpad9),
+];
+
+/// A key of the keyboard, named as in `KEY_NAMES`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Key(pub KeyCode);
+
+impl TryFrom<String> for Key {
+    type Error = anyhow::Error;
+
+    fn try_from(name: String) -> Result<Self> {
+        KEY_NAMES
+            .iter()
+            .find(|(key_name, _)| key_name.eq_ignore_ascii_case(&name))
+            .map(|(_, code)| Key(*code))
+            .ok_or_else(|| anyhow!("Unknown key \"{}\"", name))
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = KEY_NAMES
+            .iter()
+            .find(|(_, code)| *code == self.0)
+            .map_or("?", |(name, _)| name);
+        write!(f, "{}", name)
+    }
+}
+
+/// The keys of the camera's keyboard controls. Any of them can be left out of the controls file to
+/// keep its default.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Controls {
+    pub forward: Key,
+    pub backward: Key,
+    pub left: Key,
+    pub right: Key,
+    pub down: Key,
+    pub up: Key,
+    /// Switches between the orbit and free cameras.
+    pub toggle_mode: Key,
+}
+
+impl Default for Controls {
+    fn default() -> Self {
+        Self {
+            forward: Key(KeyCode::KeyW),
+            backward: Key(KeyCode::KeyS),
+            left: Key(KeyCode::KeyA),
+            right: Key(KeyCode::KeyD),
+            down: Key(KeyCode::KeyQ),
+            up: Key(KeyCode::KeyE),
+            toggle_mode: Key(KeyCode::KeyF),
+        }
+    }
+}
+
+impl Controls {
+    fn keys(&self) -> [(&'static str, Key); 7] {
+        [
+            ("forward", self.forward),
+            ("backward", self.backward),
+            ("left", self.left),
+            ("right", self.right),
+            ("down", self.down),
+            ("up", self.up),
+            ("toggle_mode", self.toggle_mode),
+        ]
+    }
+}
+
+/// Reads the controls from a JSON file mapping actions to key names, e.g.
+/// `{"forward": "Up", "backward": "Down"}`. Binding a key to two actions is an error.
+pub fn load_controls(path: &Path) -> Result<Controls> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read controls file {}", path.to_string_lossy()))?;
+    let controls: Controls = serde_json::from_str(&contents)
+        .with_context(|| format!("Invalid controls file {}", path.to_string_lossy()))?;
+
+    let keys = controls.keys();
+    for (idx, (action, key)) in keys.iter().enumerate() {
+        if let Some((other_action, _)) = keys[..idx].iter().find(|(_, other)| other == key) {
+            bail!(
+                "Invalid controls file {}: {} is bound to both {} and {}",
+                path.to_string_lossy(),
+                key,
+                other_action,
+                action
+            );
+        }
+    }
+
+    Ok(controls)
+}
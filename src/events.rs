@@ -0,0 +1,86 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::timings;
+
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable log lines.
+    Human,
+    /// Newline-delimited JSON progress events, meant to be parsed by a supervising process.
+    Json,
+}
+
+/// Progress events emitted on stdout when using the JSON log format.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    StageStarted {
+        stage: &'a str,
+    },
+    StageFinished {
+        stage: &'a str,
+        elapsed_ms: f64,
+    },
+    PairProcessed {
+        left: usize,
+        right: usize,
+        matches: usize,
+        points: usize,
+    },
+    Summary {
+        images: usize,
+        points: usize,
+    },
+}
+
+pub fn set_log_format(log_format: LogFormat) {
+    JSON_OUTPUT.store(log_format == LogFormat::Json, Ordering::Relaxed);
+}
+
+pub fn json_output() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+pub fn emit(event: Event) {
+    if !json_output() {
+        return;
+    }
+
+    match serde_json::to_string(&event) {
+        Ok(line) => println!("{}", line),
+        Err(error) => log::error!("Failed to serialize progress event: {}", error),
+    }
+}
+
+pub struct Stage {
+    name: &'static str,
+    start: Instant,
+}
+
+impl Stage {
+    pub fn start(name: &'static str) -> Self {
+        emit(Event::StageStarted { stage: name });
+
+        Self {
+            name,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn finish(self) {
+        let elapsed = self.start.elapsed();
+        timings::record(self.name, elapsed);
+        emit(Event::StageFinished {
+            stage: self.name,
+            elapsed_ms: elapsed.as_secs_f64() * 1000.0,
+        });
+    }
+}
@@ -0,0 +1,689 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use morrigu::math_types::{Mat3, Quat, Vec3};
+use opencv as cv;
+
+use crate::{
+    pose::{decompose_projection, CameraView},
+    render_state::Point,
+};
+
+fn color_to_u8(channel: f32) -> u8 {
+    (channel.clamp(0.0, 1.0) * u8::MAX as f32).round() as u8
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum PlyFormat {
+    /// Human-readable, but larger and slower to read.
+    #[default]
+    Ascii,
+    /// Little-endian binary.
+    Binary,
+}
+
+/// The optional vertex properties declared in the header, and written for every point.
+#[derive(Clone, Copy)]
+struct VertexProperties {
+    normals: bool,
+    confidence: bool,
+    reproj_error: bool,
+    num_views: bool,
+}
+
+impl VertexProperties {
+    fn of(points: &[Point]) -> Self {
+        Self {
+            normals: points.iter().any(|point| point.normal.is_some()),
+            confidence: points.iter().any(|point| point.confidence.is_some()),
+            reproj_error: points.iter().any(|point| point.reproj_error.is_some()),
+            num_views: points.iter().any(|point| point.num_views.is_some()),
+        }
+    }
+}
+
+fn write_ascii_vertex(
+    writer: &mut impl Write,
+    point: &Point,
+    properties: VertexProperties,
+) -> Result<()> {
+    write!(
+        writer,
+        "{} {} {}",
+        point.position.x, point.position.y, point.position.z
+    )?;
+    if properties.normals {
+        let normal = point.normal.unwrap_or(Vec3::ZERO);
+        write!(writer, " {} {} {}", normal.x, normal.y, normal.z)?;
+    }
+    write!(
+        writer,
+        " {} {} {}",
+        color_to_u8(point.color.x),
+        color_to_u8(point.color.y),
+        color_to_u8(point.color.z),
+    )?;
+    if properties.confidence {
+        write!(writer, " {}", point.confidence.unwrap_or(f32::NAN))?;
+    }
+    if properties.reproj_error {
+        write!(writer, " {}", point.reproj_error.unwrap_or(f32::NAN))?;
+    }
+    if properties.num_views {
+        write!(writer, " {}", point.num_views.unwrap_or(0))?;
+    }
+    writeln!(writer)?;
+
+    Ok(())
+}
+
+fn write_binary_vertex(
+    writer: &mut impl Write,
+    point: &Point,
+    properties: VertexProperties,
+) -> Result<()> {
+    for value in point.position.to_array() {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    if properties.normals {
+        for value in point.normal.unwrap_or(Vec3::ZERO).to_array() {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+    }
+    writer.write_all(&point.color.to_array().map(color_to_u8))?;
+    if properties.confidence {
+        writer.write_all(&point.confidence.unwrap_or(f32::NAN).to_le_bytes())?;
+    }
+    if properties.reproj_error {
+        writer.write_all(&point.reproj_error.unwrap_or(f32::NAN).to_le_bytes())?;
+    }
+    if properties.num_views {
+        writer.write_all(&point.num_views.unwrap_or(0).to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+fn write_vertex(
+    writer: &mut impl Write,
+    point: &Point,
+    format: PlyFormat,
+    properties: VertexProperties,
+) -> Result<()> {
+    match format {
+        PlyFormat::Ascii => write_ascii_vertex(writer, point, properties),
+        PlyFormat::Binary => write_binary_vertex(writer, point, properties),
+    }
+}
+
+fn write_header(
+    writer: &mut impl Write,
+    format: PlyFormat,
+    properties: VertexProperties,
+    vertex_count: &str,
+) -> Result<()> {
+    writeln!(writer, "ply")?;
+    match format {
+        PlyFormat::Ascii => writeln!(writer, "format ascii 1.0")?,
+        PlyFormat::Binary => writeln!(writer, "format binary_little_endian 1.0")?,
+    }
+    writeln!(writer, "comment generated by sfm")?;
+    writeln!(writer, "element vertex {}", vertex_count)?;
+    writeln!(writer, "property float x")?;
+    writeln!(writer, "property float y")?;
+    writeln!(writer, "property float z")?;
+    if properties.normals {
+        writeln!(writer, "property float nx")?;
+        writeln!(writer, "property float ny")?;
+        writeln!(writer, "property float nz")?;
+    }
+    writeln!(writer, "property uchar red")?;
+    writeln!(writer, "property uchar green")?;
+    writeln!(writer, "property uchar blue")?;
+    if properties.confidence {
+        writeln!(writer, "property float confidence")?;
+    }
+    if properties.reproj_error {
+        writeln!(writer, "property float reproj_error")?;
+    }
+    if properties.num_views {
+        writeln!(writer, "property uint num_views")?;
+    }
+    writeln!(writer, "end_header")?;
+
+    Ok(())
+}
+
+/// Writes the point cloud as a PLY file, in the ASCII or little-endian binary format.
+///
+/// Normals are written after the position if at least one point has one, points without a normal
+/// get a zero one.
+///
+/// The optional per-point diagnostics (`confidence`, `reproj_error`, `num_views`) are only declared in
+/// the header if at least one point carries them, in which case points missing the value are written
+/// as NaN (or 0 for `num_views`). Tools like CloudCompare load these extra vertex properties as
+/// scalar fields.
+pub fn write_ply(path: &Path, points: &[Point], format: PlyFormat) -> Result<()> {
+    let file = File::create(path)
+        .with_context(|| format!("Failed to create PLY file {}", path.to_string_lossy()))?;
+    let mut writer = BufWriter::new(file);
+
+    let properties = VertexProperties::of(points);
+    write_header(&mut writer, format, properties, &points.len().to_string())?;
+    for point in points {
+        write_vertex(&mut writer, point, format, properties)?;
+    }
+
+    writer.flush()?;
+    log::info!(
+        "Exported {} points to {}",
+        points.len(),
+        path.to_string_lossy()
+    );
+
+    Ok(())
+}
+
+/// Digits of the vertex count placeholder of `PlyStreamWriter`.
+const STREAMED_COUNT_WIDTH: usize = 12;
+
+/// Writes a PLY file batch by batch, for clouds too large to be kept in memory. The vertex count is
+/// only known at the end, so the header is written with a zero-padded placeholder that `finish`
+/// overwrites. The optional properties are those of the first batch, later points missing one of
+/// them get the same placeholder values as in `write_ply`.
+pub struct PlyStreamWriter {
+    path: PathBuf,
+    format: PlyFormat,
+    writer: BufWriter<File>,
+    /// The properties declared in the header and the offset of its vertex count, once written.
+    header: Option<(VertexProperties, u64)>,
+    point_count: usize,
+}
+
+impl PlyStreamWriter {
+    pub fn create(path: &Path, format: PlyFormat) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create PLY file {}", path.to_string_lossy()))?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            format,
+            writer: BufWriter::new(file),
+            header: None,
+            point_count: 0,
+        })
+    }
+
+    fn write_header(&mut self, properties: VertexProperties) -> Result<(VertexProperties, u64)> {
+        let mut header = vec![];
+        write_header(
+            &mut header,
+            self.format,
+            properties,
+            &"0".repeat(STREAMED_COUNT_WIDTH),
+        )?;
+        let count_prefix = b"element vertex ";
+        let count_offset = header
+            .windows(count_prefix.len())
+            .position(|window| window == count_prefix)
+            .context("Missing vertex element in the PLY header")?
+            + count_prefix.len();
+        self.writer.write_all(&header)?;
+        let header = (properties, count_offset as u64);
+        self.header = Some(header);
+
+        Ok(header)
+    }
+
+    pub fn write_points(&mut self, points: &[Point]) -> Result<()> {
+        if points.is_empty() {
+            return Ok(());
+        }
+        let properties = match self.header {
+            Some((properties, _)) => properties,
+            None => self.write_header(VertexProperties::of(points))?.0,
+        };
+
+        for point in points {
+            write_vertex(&mut self.writer, point, self.format, properties)?;
+        }
+        self.point_count += points.len();
+
+        Ok(())
+    }
+
+    /// Writes the vertex count in the header, and returns it.
+    pub fn finish(mut self) -> Result<usize> {
+        let count_offset = match self.header {
+            Some((_, count_offset)) => count_offset,
+            None => self.write_header(VertexProperties::of(&[]))?.1,
+        };
+        if self.point_count.to_string().len() > STREAMED_COUNT_WIDTH {
+            bail!(
+                "Too many points to stream to a PLY file: {}",
+                self.point_count
+            );
+        }
+
+        let mut file = self
+            .writer
+            .into_inner()
+            .map_err(|error| error.into_error())?;
+        file.seek(SeekFrom::Start(count_offset))?;
+        write!(
+            file,
+            "{:0width$}",
+            self.point_count,
+            width = STREAMED_COUNT_WIDTH
+        )?;
+        file.flush()?;
+        log::info!(
+            "Exported {} points to {}",
+            self.point_count,
+            self.path.to_string_lossy()
+        );
+
+        Ok(self.point_count)
+    }
+}
+
+/// Splits the projection matrix of the camera into its intrinsics K (normalized so that K[2][2] is 1),
+/// its rotation R and its translation t, such that P = K * [R|t].
+fn decompose_camera(camera: &CameraView) -> Result<(Mat3, Mat3, Vec3)> {
+    let projection_vals = (0..3)
+        .flat_map(|row| camera.projection.row(row).to_array())
+        .collect::<Vec<_>>();
+    let projection = cv::core::Mat::from_slice_rows_cols(&projection_vals, 3, 4)?;
+
+    decompose_projection(&projection)
+}
+
+fn create_colmap_file(dir: &Path, name: &str) -> Result<BufWriter<File>> {
+    let path = dir.join(name);
+    let file = File::create(&path)
+        .with_context(|| format!("Failed to create {}", path.to_string_lossy()))?;
+
+    Ok(BufWriter::new(file))
+}
+
+/// Writes the reconstruction as a COLMAP text model (`cameras.txt`, `images.txt` and
+/// `points3D.txt`), with one PINHOLE camera per image. The 2D observations of the points are not
+/// tracked, so the images' keypoints and the points' tracks are left empty.
+pub fn write_colmap(
+    dir: &Path,
+    points: &[Point],
+    cameras: &[CameraView],
+    image_names: &[String],
+) -> Result<()> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create directory {}", dir.to_string_lossy()))?;
+
+    let mut cameras_writer = create_colmap_file(dir, "cameras.txt")?;
+    let mut images_writer = create_colmap_file(dir, "images.txt")?;
+    writeln!(
+        cameras_writer,
+        "# Camera list with one line of data per camera:"
+    )?;
+    writeln!(
+        cameras_writer,
+        "#   CAMERA_ID, MODEL, WIDTH, HEIGHT, PARAMS[]"
+    )?;
+    writeln!(cameras_writer, "# Number of cameras: {}", cameras.len())?;
+    writeln!(
+        images_writer,
+        "# Image list with two lines of data per image:"
+    )?;
+    writeln!(
+        images_writer,
+        "#   IMAGE_ID, QW, QX, QY, QZ, TX, TY, TZ, CAMERA_ID, NAME"
+    )?;
+    writeln!(images_writer, "#   POINTS2D[] as (X, Y, POINT3D_ID)")?;
+    writeln!(images_writer, "# Number of images: {}", cameras.len())?;
+    for (idx, (camera, name)) in cameras.iter().zip(image_names).enumerate() {
+        let id = idx + 1;
+        let (k, r, t) = decompose_camera(camera)?;
+        writeln!(
+            cameras_writer,
+            "{} PINHOLE {} {} {} {} {} {}",
+            id,
+            camera.image_size.x,
+            camera.image_size.y,
+            k.x_axis.x,
+            k.y_axis.y,
+            k.z_axis.x,
+            k.z_axis.y
+        )?;
+
+        let rotation = Quat::from_mat3(&r).normalize();
+        writeln!(
+            images_writer,
+            "{} {} {} {} {} {} {} {} {} {}",
+            id, rotation.w, rotation.x, rotation.y, rotation.z, t.x, t.y, t.z, id, name
+        )?;
+        writeln!(images_writer)?;
+    }
+    cameras_writer.flush()?;
+    images_writer.flush()?;
+
+    let mut points_writer = create_colmap_file(dir, "points3D.txt")?;
+    writeln!(
+        points_writer,
+        "# 3D point list with one line of data per point:"
+    )?;
+    writeln!(
+        points_writer,
+        "#   POINT3D_ID, X, Y, Z, R, G, B, ERROR, TRACK[] as (IMAGE_ID, POINT2D_IDX)"
+    )?;
+    writeln!(points_writer, "# Number of points: {}", points.len())?;
+    for (idx, point) in points.iter().enumerate() {
+        writeln!(
+            points_writer,
+            "{} {} {} {} {} {} {} {}",
+            idx + 1,
+            point.position.x,
+            point.position.y,
+            point.position.z,
+            color_to_u8(point.color.x),
+            color_to_u8(point.color.y),
+            color_to_u8(point.color.z),
+            // COLMAP uses -1 for points whose error is unknown
+            point.reproj_error.unwrap_or(-1.0)
+        )?;
+    }
+    points_writer.flush()?;
+
+    log::info!(
+        "Exported {} cameras and {} points to the COLMAP model in {}",
+        cameras.len(),
+        points.len(),
+        dir.to_string_lossy()
+    );
+
+    Ok(())
+}
+
+/// Reads back the points of a COLMAP `points3D.txt` file, e.g. one written by `write_colmap`. The
+/// tracks are ignored, and errors of -1 are read as unknown.
+pub fn read_colmap_points(dir: &Path) -> Result<Vec<Point>> {
+    let path = dir.join("points3D.txt");
+    let contents = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.to_string_lossy()))?;
+
+    let mut points = vec![];
+    for (line_idx, line) in contents.lines().enumerate() {
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        if tokens.is_empty() || tokens[0].starts_with('#') {
+            continue;
+        }
+        if tokens.len() < 8 {
+            bail!("Incomplete point on line {} of points3D.txt", line_idx + 1);
+        }
+
+        let values = tokens[1..8]
+            .iter()
+            .map(|token| token.parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Invalid point on line {} of points3D.txt", line_idx + 1))?;
+        points.push(Point {
+            position: Vec3::new(values[0], values[1], values[2]),
+            color: Vec3::new(values[3], values[4], values[5]) / u8::MAX as f32,
+            reproj_error: (values[6] >= 0.0).then_some(values[6]),
+            ..Default::default()
+        });
+    }
+    log::info!(
+        "Read {} points from the COLMAP model in {}",
+        points.len(),
+        dir.to_string_lossy()
+    );
+
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads back the header's vertex properties (type and name) and the vertices of a PLY file.
+    fn read_ply(path: &Path) -> Result<(Vec<(String, String)>, Vec<Vec<f64>>)> {
+        let bytes = std::fs::read(path)?;
+        let header_end = b"end_header\n";
+        let body_start = bytes
+            .windows(header_end.len())
+            .position(|window| window == header_end)
+            .context("Missing end_header")?
+            + header_end.len();
+        let header = std::str::from_utf8(&bytes[..body_start])?;
+
+        let mut binary = false;
+        let mut vertex_count = 0;
+        let mut properties = vec![];
+        for line in header.lines() {
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            match words.as_slice() {
+                ["format", format, "1.0"] => binary = *format == "binary_little_endian",
+                ["element", "vertex", count] => vertex_count = count.parse()?,
+                ["property", kind, name] => properties.push((kind.to_string(), name.to_string())),
+                _ => {}
+            }
+        }
+
+        let body = &bytes[body_start..];
+        let vertices = if binary {
+            let mut offset = 0;
+            let mut read = |size: usize| {
+                let value = &body[offset..offset + size];
+                offset += size;
+                value
+            };
+            (0..vertex_count)
+                .map(|_| {
+                    properties
+                        .iter()
+                        .map(|(kind, _)| match kind.as_str() {
+                            "float" => f32::from_le_bytes(read(4).try_into().unwrap()) as f64,
+                            "uchar" => read(1)[0] as f64,
+                            "uint" => u32::from_le_bytes(read(4).try_into().unwrap()) as f64,
+                            _ => panic!("Unexpected property type {}", kind),
+                        })
+                        .collect()
+                })
+                .collect()
+        } else {
+            std::str::from_utf8(body)?
+                .lines()
+                .map(|line| {
+                    line.split_whitespace()
+                        .map(|value| value.parse::<f64>())
+                        .collect()
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        };
+
+        Ok((properties, vertices))
+    }
+
+    fn round_trip(points: &[Point], format: PlyFormat) -> Result<(Vec<String>, Vec<Vec<f64>>)> {
+        let path = std::env::temp_dir().join(format!(
+            "sfm_export_test_{}_{:?}.ply",
+            std::process::id(),
+            format
+        ));
+        write_ply(&path, points, format)?;
+        let result = read_ply(&path);
+        std::fs::remove_file(&path)?;
+
+        let (properties, vertices) = result?;
+        Ok((
+            properties.into_iter().map(|(_, name)| name).collect(),
+            vertices,
+        ))
+    }
+
+    #[test]
+    fn round_trips_optional_properties() -> Result<()> {
+        let points = [
+            Point {
+                position: Vec3::new(1.0, -2.5, 3.25),
+                color: Vec3::new(1.0, 0.0, 0.5),
+                confidence: Some(0.75),
+                normal: Some(Vec3::Z),
+                ..Default::default()
+            },
+            Point {
+                position: Vec3::new(-0.5, 0.0, 10.0),
+                color: Vec3::new(0.2, 0.4, 0.6),
+                ..Default::default()
+            },
+        ];
+
+        for format in [PlyFormat::Ascii, PlyFormat::Binary] {
+            let (properties, vertices) = round_trip(&points, format)?;
+            assert_eq!(
+                properties,
+                [
+                    "x",
+                    "y",
+                    "z",
+                    "nx",
+                    "ny",
+                    "nz",
+                    "red",
+                    "green",
+                    "blue",
+                    "confidence"
+                ],
+                "{:?}",
+                format
+            );
+
+            assert_eq!(vertices.len(), points.len(), "{:?}", format);
+            assert_eq!(
+                vertices[0],
+                [1.0, -2.5, 3.25, 0.0, 0.0, 1.0, 255.0, 0.0, 128.0, 0.75],
+                "{:?}",
+                format
+            );
+            // Points without a normal or a confidence get a zero normal and a NaN confidence
+            assert_eq!(
+                vertices[1][..9],
+                [-0.5, 0.0, 10.0, 0.0, 0.0, 0.0, 51.0, 102.0, 153.0],
+                "{:?}",
+                format
+            );
+            assert!(vertices[1][9].is_nan(), "{:?}", format);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn streamed_file_matches_written_file() -> Result<()> {
+        let points = (0..5)
+            .map(|idx| Point {
+                position: Vec3::splat(idx as f32),
+                color: Vec3::splat(0.5),
+                reproj_error: Some(idx as f32 * 0.25),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+
+        for format in [PlyFormat::Ascii, PlyFormat::Binary] {
+            let path = std::env::temp_dir().join(format!(
+                "sfm_export_test_{}_{:?}_streamed.ply",
+                std::process::id(),
+                format
+            ));
+            let mut writer = PlyStreamWriter::create(&path, format)?;
+            writer.write_points(&points[..2])?;
+            writer.write_points(&[])?;
+            writer.write_points(&points[2..])?;
+            let point_count = writer.finish()?;
+            let streamed = read_ply(&path);
+            std::fs::remove_file(&path)?;
+
+            assert_eq!(point_count, points.len(), "{:?}", format);
+            let (properties, vertices) = streamed?;
+            let (written_properties, written_vertices) = round_trip(&points, format)?;
+            assert_eq!(
+                properties
+                    .into_iter()
+                    .map(|(_, name)| name)
+                    .collect::<Vec<_>>(),
+                written_properties,
+                "{:?}",
+                format
+            );
+            assert_eq!(vertices, written_vertices, "{:?}", format);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_back_colmap_points() -> Result<()> {
+        let points = [
+            Point {
+                position: Vec3::new(1.0, -2.5, 3.25),
+                color: Vec3::new(1.0, 0.0, 0.2),
+                reproj_error: Some(0.5),
+                ..Default::default()
+            },
+            Point {
+                position: Vec3::new(-0.5, 0.0, 10.0),
+                color: Vec3::ONE,
+                ..Default::default()
+            },
+        ];
+
+        let dir =
+            std::env::temp_dir().join(format!("sfm_export_test_{}_colmap", std::process::id()));
+        write_colmap(&dir, &points, &[], &[])?;
+        let result = read_colmap_points(&dir);
+        std::fs::remove_dir_all(&dir)?;
+
+        let read_points = result?;
+        assert_eq!(read_points.len(), points.len());
+        for (read_point, point) in read_points.iter().zip(&points) {
+            assert_eq!(read_point.position, point.position);
+            assert!(read_point
+                .color
+                .abs_diff_eq(point.color, 0.5 / u8::MAX as f32));
+            assert_eq!(read_point.reproj_error, point.reproj_error);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_declares_written_properties() -> Result<()> {
+        let points = [Point {
+            position: Vec3::ONE,
+            color: Vec3::ONE,
+            ..Default::default()
+        }];
+
+        for format in [PlyFormat::Ascii, PlyFormat::Binary] {
+            let (properties, vertices) = round_trip(&points, format)?;
+            assert_eq!(
+                properties,
+                ["x", "y", "z", "red", "green", "blue"],
+                "{:?}",
+                format
+            );
+            assert_eq!(
+                vertices,
+                [[1.0, 1.0, 1.0, 255.0, 255.0, 255.0]],
+                "{:?}",
+                format
+            );
+        }
+
+        Ok(())
+    }
+}
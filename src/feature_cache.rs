@@ -0,0 +1,251 @@
+use std::{
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{bail, Context, Result};
+use cv::core::{KeyPoint, Point2f, Vector};
+use cv::prelude::*;
+use opencv as cv;
+
+use crate::Image;
+
+const MAGIC: &[u8; 8] = b"SFMFEAT2";
+
+/// Stores the keypoints and descriptors of each image in a directory, so that they don't have to be
+/// extracted again on the next runs. Entries are keyed by the image's path, modification time and
+/// size, and by the detector, so changing any of them invalidates the cached features. The key is
+/// written in the entry and checked on load, as the file names are only a hash of it.
+pub struct FeatureCache {
+    dir: PathBuf,
+    detector_name: String,
+}
+
+impl FeatureCache {
+    pub fn new(dir: &Path, detector_name: &str) -> Result<Self> {
+        std::fs::create_dir_all(dir).with_context(|| {
+            format!(
+                "Failed to create feature cache directory {}",
+                dir.to_string_lossy()
+            )
+        })?;
+
+        Ok(Self {
+            dir: dir.to_owned(),
+            detector_name: detector_name.to_owned(),
+        })
+    }
+
+    fn entry_key(&self, image_path: &Path, image: &Image) -> Result<Vec<u8>> {
+        let modified = std::fs::metadata(image_path)?
+            .modified()?
+            .duration_since(UNIX_EPOCH)?
+            .as_nanos();
+        let size = image.size()?;
+        let path = image_path.canonicalize()?;
+
+        let mut key = vec![];
+        for field in [
+            path.to_string_lossy().as_bytes(),
+            self.detector_name.as_bytes(),
+        ] {
+            key.extend((field.len() as u64).to_le_bytes());
+            key.extend(field);
+        }
+        key.extend(modified.to_le_bytes());
+        key.extend(size.width.to_le_bytes());
+        key.extend(size.height.to_le_bytes());
+
+        Ok(key)
+    }
+
+    fn entry_path(&self, key: &[u8]) -> PathBuf {
+        self.dir.join(format!("{:016x}.features", fnv1a(key)))
+    }
+
+    /// The cached features of the image, or `None` if there are none or they can't be read.
+    pub fn load(&self, image_path: &Path, image: &Image) -> Option<(Vector<KeyPoint>, Mat)> {
+        let key = self.entry_key(image_path, image).ok()?;
+        let entry_path = self.entry_path(&key);
+        let bytes = std::fs::read(&entry_path).ok()?;
+        match decode(&bytes, &key) {
+            Ok(features) => Some(features),
+            Err(error) => {
+                log::warn!(
+                    "Ignoring invalid feature cache entry {} ({})",
+                    entry_path.to_string_lossy(),
+                    error
+                );
+                None
+            }
+        }
+    }
+
+    pub fn store(
+        &self,
+        image_path: &Path,
+        image: &Image,
+        keypoints: &Vector<KeyPoint>,
+        descriptors: &Mat,
+    ) -> Result<()> {
+        let key = self.entry_key(image_path, image)?;
+        let entry_path = self.entry_path(&key);
+        std::fs::write(&entry_path, encode(&key, keypoints, descriptors)?).with_context(|| {
+            format!(
+                "Failed to write feature cache entry {}",
+                entry_path.to_string_lossy()
+            )
+        })
+    }
+}
+
+/// 64 bit FNV-1a, which unlike `DefaultHasher` hashes the same way with every Rust release, so that
+/// updating the toolchain doesn't invalidate the cache.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+fn encode(key: &[u8], keypoints: &Vector<KeyPoint>, descriptors: &Mat) -> Result<Vec<u8>> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend((key.len() as u64).to_le_bytes());
+    bytes.extend(key);
+
+    bytes.extend((keypoints.len() as u64).to_le_bytes());
+    for keypoint in keypoints {
+        for value in [
+            keypoint.pt().x,
+            keypoint.pt().y,
+            keypoint.size(),
+            keypoint.angle(),
+            keypoint.response(),
+        ] {
+            bytes.extend(value.to_le_bytes());
+        }
+        bytes.extend(keypoint.octave().to_le_bytes());
+        bytes.extend(keypoint.class_id().to_le_bytes());
+    }
+
+    let descriptors = descriptors.try_clone()?; // makes sure the data is continuous
+    for value in [descriptors.rows(), descriptors.cols(), descriptors.typ()] {
+        bytes.extend(value.to_le_bytes());
+    }
+    let data: &[u8] = match descriptors.empty() {
+        true => &[],
+        false => descriptors.data_bytes()?,
+    };
+    bytes.extend((data.len() as u64).to_le_bytes());
+    bytes.extend(data);
+
+    Ok(bytes)
+}
+
+/// Reads the little-endian values of an encoded cache entry in order.
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < count {
+            bail!("unexpected end of file");
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+
+        Ok(taken)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+}
+
+fn decode(bytes: &[u8], key: &[u8]) -> Result<(Vector<KeyPoint>, Mat)> {
+    let mut decoder = Decoder { bytes };
+    if decoder.take(MAGIC.len())? != MAGIC {
+        bail!("not a feature cache file");
+    }
+    let key_len = decoder.u64()? as usize;
+    if decoder.take(key_len)? != key {
+        bail!("the entry belongs to another image or detector");
+    }
+
+    let keypoint_count = decoder.u64()? as usize;
+    let mut keypoints = Vector::<KeyPoint>::with_capacity(keypoint_count);
+    for _ in 0..keypoint_count {
+        let pt = Point2f::new(decoder.f32()?, decoder.f32()?);
+        let (size, angle, response) = (decoder.f32()?, decoder.f32()?, decoder.f32()?);
+        let (octave, class_id) = (decoder.i32()?, decoder.i32()?);
+        keypoints.push(KeyPoint::new_point(
+            pt, size, angle, response, octave, class_id,
+        )?);
+    }
+
+    let (rows, cols, typ) = (decoder.i32()?, decoder.i32()?, decoder.i32()?);
+    let data_len = decoder.u64()? as usize;
+    let data = decoder.take(data_len)?;
+    if rows == 0 || cols == 0 {
+        return Ok((keypoints, Mat::default()));
+    }
+    let mut descriptors =
+        Mat::new_rows_cols_with_default(rows, cols, typ, cv::core::Scalar::all(0.0))?;
+    let descriptor_data = descriptors.data_bytes_mut()?;
+    if descriptor_data.len() != data.len() {
+        bail!("descriptor size mismatch");
+    }
+    descriptor_data.copy_from_slice(data);
+
+    Ok((keypoints, descriptors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keypoints() -> Result<Vector<KeyPoint>> {
+        let keypoint = KeyPoint::new_point(Point2f::new(1.5, 2.5), 3.0, 45.0, 0.5, 1, -1)?;
+
+        Ok(Vector::from_iter([keypoint]))
+    }
+
+    #[test]
+    fn decodes_what_was_encoded() -> Result<()> {
+        let descriptors = Mat::from_slice_rows_cols(&[1.0f32, 2.0, 3.0, 4.0], 1, 4)?;
+        let bytes = encode(b"key", &keypoints()?, &descriptors)?;
+
+        let (decoded_keypoints, decoded_descriptors) = decode(&bytes, b"key")?;
+        assert_eq!(decoded_keypoints.len(), 1);
+        assert_eq!(decoded_keypoints.get(0)?.pt(), Point2f::new(1.5, 2.5));
+        assert_eq!(
+            decoded_descriptors.data_typed::<f32>()?,
+            [1.0, 2.0, 3.0, 4.0]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_the_entry_of_another_key() -> Result<()> {
+        let bytes = encode(b"key", &keypoints()?, &Mat::default())?;
+        assert!(decode(&bytes, b"other key").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn hashes_keys_with_fnv1a() {
+        // Reference values of the 64 bit FNV-1a hash
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+}
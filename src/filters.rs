@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use cv::prelude::*;
+use kiddo::{KdTree, SquaredEuclidean};
+use morrigu::math_types::Vec3;
+use opencv as cv;
+
+use crate::render_state::Point;
+
+fn build_kd_tree(points: &[Point]) -> KdTree<f32, 3> {
+    let mut tree = KdTree::with_capacity(points.len());
+    for (idx, point) in points.iter().enumerate() {
+        tree.add(&point.position.to_array(), idx as u64);
+    }
+
+    tree
+}
+
+/// Statistical outlier removal: discards the points whose mean distance to their `neighbor_count`
+/// nearest neighbors is more than `std_ratio` standard deviations above the average of that mean
+/// distance over the whole cloud.
+pub fn remove_statistical_outliers(
+    points: Vec<Point>,
+    neighbor_count: usize,
+    std_ratio: f32,
+) -> Vec<Point> {
+    if points.len() <= neighbor_count {
+        log::warn!(
+            "Not enough points to remove outliers ({} points, {} neighbors)",
+            points.len(),
+            neighbor_count
+        );
+        return points;
+    }
+
+    let tree = build_kd_tree(&points);
+    let mean_distances = points
+        .iter()
+        .map(|point| {
+            // The closest neighbor is the point itself
+            let neighbors =
+                tree.nearest_n::<SquaredEuclidean>(&point.position.to_array(), neighbor_count + 1);
+            neighbors
+                .iter()
+                .skip(1)
+                .map(|neighbor| neighbor.distance.sqrt())
+                .sum::<f32>()
+                / neighbor_count as f32
+        })
+        .collect::<Vec<_>>();
+
+    let mean = mean_distances.iter().sum::<f32>() / mean_distances.len() as f32;
+    let variance = mean_distances
+        .iter()
+        .map(|distance| (distance - mean) * (distance - mean))
+        .sum::<f32>()
+        / mean_distances.len() as f32;
+    let threshold = mean + std_ratio * variance.sqrt();
+
+    let point_count = points.len();
+    let filtered_points = points
+        .into_iter()
+        .zip(mean_distances)
+        .filter(|(_, distance)| *distance <= threshold)
+        .map(|(point, _)| point)
+        .collect::<Vec<_>>();
+    log::info!(
+        "Removed {} of {} points as statistical outliers (mean neighbor distance above {:.4})",
+        point_count - filtered_points.len(),
+        point_count,
+        threshold
+    );
+
+    filtered_points
+}
+
+/// Sums of the values of the points that fell in a voxel.
+#[derive(Default)]
+struct Voxel {
+    count: u32,
+    position: Vec3,
+    color: Vec3,
+    reproj_error: (f32, u32),
+    num_views: Option<u32>,
+    source_pair: Option<(usize, usize)>,
+}
+
+/// Merges the points that fall in the same cell of a grid of `voxel_size`, keeping their average
+/// position, color and reprojection error. The merged points are given the largest view count of
+/// the points they replace, and the source pair of the first one.
+pub fn voxel_downsample(points: Vec<Point>, voxel_size: f32) -> Vec<Point> {
+    let point_count = points.len();
+    let mut voxel_indices = HashMap::new();
+    let mut voxels: Vec<Voxel> = vec![];
+    for point in &points {
+        let cell = (point.position / voxel_size).floor();
+        let idx = *voxel_indices
+            .entry((cell.x as i64, cell.y as i64, cell.z as i64))
+            .or_insert_with(|| {
+                voxels.push(Voxel::default());
+                voxels.len() - 1
+            });
+
+        let voxel = &mut voxels[idx];
+        voxel.count += 1;
+        voxel.position += point.position;
+        voxel.color += point.color;
+        if let Some(error) = point.reproj_error {
+            voxel.reproj_error.0 += error;
+            voxel.reproj_error.1 += 1;
+        }
+        voxel.num_views = voxel.num_views.max(point.num_views);
+        voxel.source_pair = voxel.source_pair.or(point.source_pair);
+    }
+
+    let merged_points = voxels
+        .into_iter()
+        .map(|voxel| Point {
+            position: voxel.position / voxel.count as f32,
+            color: voxel.color / voxel.count as f32,
+            reproj_error: (voxel.reproj_error.1 > 0)
+                .then(|| voxel.reproj_error.0 / voxel.reproj_error.1 as f32),
+            num_views: voxel.num_views,
+            source_pair: voxel.source_pair,
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+    log::info!(
+        "Merged {} points into {} voxels of size {}",
+        point_count,
+        merged_points.len(),
+        voxel_size
+    );
+
+    merged_points
+}
+
+/// Discards the points farther than `max_distance` from the nearest camera center.
+pub fn remove_distant_points(
+    points: Vec<Point>,
+    camera_centers: &[Vec3],
+    max_distance: f32,
+) -> Vec<Point> {
+    if camera_centers.is_empty() {
+        return points;
+    }
+
+    let mut tree: KdTree<f32, 3> = KdTree::with_capacity(camera_centers.len());
+    for (idx, center) in camera_centers.iter().enumerate() {
+        tree.add(&center.to_array(), idx as u64);
+    }
+
+    let point_count = points.len();
+    let max_squared_distance = max_distance * max_distance;
+    let filtered_points = points
+        .into_iter()
+        .filter(|point| {
+            tree.nearest_one::<SquaredEuclidean>(&point.position.to_array())
+                .distance
+                <= max_squared_distance
+        })
+        .collect::<Vec<_>>();
+    log::info!(
+        "Removed {} of {} points farther than {} from the cameras",
+        point_count - filtered_points.len(),
+        point_count,
+        max_distance
+    );
+
+    filtered_points
+}
+
+/// Spatially subsamples the cloud down to at most `max_points` points, by keeping the first point of
+/// each cell of a voxel grid. The grid starts with the cell size that would spread `max_points`
+/// points evenly over the cloud's bounding box, and is made coarser until few enough cells are
+/// occupied. Unlike `voxel_downsample`, the points are kept as is rather than averaged.
+pub fn subsample(points: &[Point], max_points: usize) -> Vec<Point> {
+    if points.len() <= max_points {
+        return points.to_vec();
+    }
+    if max_points == 0 {
+        return vec![];
+    }
+
+    let (min, max) = points.iter().fold(
+        (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+        |(min, max), point| (min.min(point.position), max.max(point.position)),
+    );
+    // Flat clouds would have a zero volume, their thinnest side is given a small thickness
+    let extent = (max - min).max(Vec3::splat((max - min).max_element() * 1e-3));
+    let mut cell_size = (extent.x * extent.y * extent.z / max_points as f32).cbrt();
+    if !cell_size.is_normal() {
+        // All the points are at the same position
+        return points[..max_points].to_vec();
+    }
+
+    loop {
+        let mut occupied_cells = HashMap::new();
+        for (idx, point) in points.iter().enumerate() {
+            let cell = ((point.position - min) / cell_size).floor();
+            occupied_cells
+                .entry((cell.x as i64, cell.y as i64, cell.z as i64))
+                .or_insert(idx);
+            if occupied_cells.len() > max_points {
+                break;
+            }
+        }
+
+        if occupied_cells.len() <= max_points {
+            let mut kept_indices = occupied_cells.into_values().collect::<Vec<_>>();
+            kept_indices.sort_unstable();
+            return kept_indices
+                .into_iter()
+                .map(|idx| points[idx].clone())
+                .collect();
+        }
+        cell_size *= 1.25;
+    }
+}
+
+/// Estimates the normal of each point as the direction of least variance of its neighborhood (the
+/// point and its `neighbor_count` nearest neighbors), oriented towards the nearest camera.
+pub fn estimate_normals(
+    points: &mut [Point],
+    neighbor_count: usize,
+    camera_centers: &[Vec3],
+) -> Result<()> {
+    if points.len() <= neighbor_count {
+        log::warn!(
+            "Not enough points to estimate normals ({} points, {} neighbors)",
+            points.len(),
+            neighbor_count
+        );
+        return Ok(());
+    }
+
+    let tree = build_kd_tree(points);
+    let normals = points
+        .iter()
+        .map(|point| -> Result<Vec3> {
+            let neighbors =
+                tree.nearest_n::<SquaredEuclidean>(&point.position.to_array(), neighbor_count + 1);
+            let positions = neighbors
+                .iter()
+                .map(|neighbor| points[neighbor.item as usize].position)
+                .collect::<Vec<_>>();
+            let centroid = positions.iter().sum::<Vec3>() / positions.len() as f32;
+
+            let mut covariance = [0.0; 9];
+            for position in &positions {
+                let offset = (*position - centroid).as_dvec3();
+                for row in 0..3 {
+                    for col in 0..3 {
+                        covariance[row * 3 + col] += offset[row] * offset[col];
+                    }
+                }
+            }
+            let covariance = cv::core::Mat::from_slice_rows_cols(&covariance, 3, 3)?;
+            let mut eigenvalues = cv::core::Mat::default();
+            let mut eigenvectors = cv::core::Mat::default();
+            cv::core::eigen(&covariance, &mut eigenvalues, &mut eigenvectors)?;
+
+            // The eigenvectors are sorted by decreasing eigenvalue
+            let mut normal = Vec3::new(
+                *eigenvectors.at_2d::<f64>(2, 0)? as f32,
+                *eigenvectors.at_2d::<f64>(2, 1)? as f32,
+                *eigenvectors.at_2d::<f64>(2, 2)? as f32,
+            )
+            .normalize_or_zero();
+            let nearest_camera = camera_centers.iter().min_by(|a, b| {
+                a.distance_squared(point.position)
+                    .total_cmp(&b.distance_squared(point.position))
+            });
+            if let Some(camera) = nearest_camera {
+                if normal.dot(*camera - point.position) < 0.0 {
+                    normal = -normal;
+                }
+            }
+
+            Ok(normal)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    for (point, normal) in points.iter_mut().zip(normals) {
+        point.normal = Some(normal);
+    }
+    log::info!(
+        "Estimated the normals of {} points from {} neighbors",
+        points.len(),
+        neighbor_count
+    );
+
+    Ok(())
+}
@@ -1,40 +1,258 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+};
 
-use anyhow::{Context, Result};
-use clap::Parser;
-use morrigu::application::ApplicationBuilder;
+use anyhow::{anyhow, bail, Result};
+use clap::{Args, Parser, Subcommand};
+use cv::prelude::{MatTraitConst, VideoCaptureTrait, VideoCaptureTraitConst};
+use morrigu::{
+    application::ApplicationBuilder,
+    math_types::{Vec2, Vec3},
+};
 use opencv as cv;
 
 use crate::{
-    pose::extract_pose,
-    render_state::{PointCloudData, RenderState},
-    sfm::generate_point_cloud,
+    controls::{load_controls, Controls},
+    events::{LogFormat, Stage},
+    export::{read_colmap_points, write_colmap, write_ply, PlyFormat, PlyStreamWriter},
+    pose::{
+        camera_view, exif_intrinsics, extract_bundler_pose, extract_colmap_pose, extract_nvm_pose,
+        extract_pose, mat_values, match_poses_to_images, read_colmap_extrinsics, read_intrinsics,
+        replace_intrinsics, rotate_intrinsics, scale_intrinsics, CameraModel, CameraView,
+        ImageRotation, Intrinsics, PoseFormat, PoseHeader, EXIF_INTRINSICS,
+    },
+    render_state::{Point, PointCloudData, RenderState},
+    sfm::{
+        compute_features, generate_point_cloud, recover_poses, register_incremental,
+        triangulate_two_views, PairMode, ReconstructionMode, SfmOptions,
+    },
+    transform::{apply_transform, parse_transform},
 };
 
+mod bookmarks;
+mod bundle_adjustment;
 mod camera;
+mod controls;
+mod events;
+mod export;
+mod feature_cache;
+mod filters;
+mod matcher;
 mod pose;
+mod progress;
 mod render_state;
 mod sfm;
+mod stats;
+mod timings;
+mod tracks;
+mod transform;
+mod version;
 
 pub type Image = cv::core::Mat;
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 pub struct CLI {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Print the versions of the program's dependencies and of the linked OpenCV library, with the
+    /// highlights of OpenCV's build configuration (CUDA, IPP, modules...), then exit.
+    #[arg(long, exclusive = true)]
+    pub version_detailed: bool,
+
     /// The path to the folder containing the images you would like to use.
-    #[arg(short, long, value_name = "FOLDER")]
-    pub data_path: PathBuf,
-    /// The name (NOT PATH) of the file containing pose data in the folder specified. Needs to follow the
-    /// templering dataset pose format. Defaults to "pose.txt"
+    #[arg(
+        short,
+        long,
+        value_name = "FOLDER",
+        required_unless_present = "video",
+        conflicts_with = "video"
+    )]
+    pub data_path: Option<PathBuf>,
+    /// Use the frames of this video instead of a folder of images. The frames are named after their
+    /// index (e.g. "frame_000042"), and the pose file is looked for next to the video.
+    #[arg(long, value_name = "FILE")]
+    pub video: Option<PathBuf>,
+    /// Only keep every Nth frame of the video.
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    pub frame_step: usize,
+    /// Also look for images (and the pose file) in the subfolders of the data folder.
+    #[arg(long)]
+    pub recursive: bool,
+    /// The name (NOT PATH) of the file containing pose data in the folder specified, in the format
+    /// given by `--pose-format`. Defaults to "pose.txt", "bundle.out" or "model.nvm".
     #[arg(short, long)]
     pub pose_file: Option<String>,
+    /// The format of the pose data. `colmap` reads the `cameras.txt` and `images.txt` files of a
+    /// COLMAP text export from the data folder, ignoring `--pose-file`.
+    #[arg(long, value_enum, default_value_t = PoseFormat::Templering)]
+    pub pose_format: PoseFormat,
+    /// Lines of the pose file starting with this prefix (ignoring leading whitespace) are treated as
+    /// comments and skipped, as are blank lines. An empty prefix disables comments.
+    #[arg(long, value_name = "PREFIX", default_value = "#")]
+    pub pose_comment_prefix: String,
+    /// Whether the first line of the pose file (after comments) is a header, such as the image count.
+    /// With `auto`, it is only skipped if it doesn't read as a pose line.
+    #[arg(long, value_enum, default_value_t = PoseHeader::Auto)]
+    pub pose_header: PoseHeader,
+    /// A file containing the intrinsics shared by all cameras, either as the 9 row-major values of K or
+    /// as `fx fy cx cy`. They are used for the lines of the pose file that only contain R and t, and
+    /// when no pose file is given or found, to recover the camera poses from the images. A
+    /// `distortion k1 k2 p1 p2 k3` line (`distortion k1 k2 k3 k4` for fisheye cameras) can be added to
+    /// undistort the images before processing them.
+    /// Pass `exif` instead of a file to recover the poses with intrinsics estimated from each image's
+    /// EXIF focal length.
+    #[arg(long, value_name = "FILE")]
+    pub intrinsics: Option<PathBuf>,
+    /// The lens model of the cameras. Fisheye images are undistorted to pinhole images before feature
+    /// extraction, using the K and distortion of the --intrinsics file.
+    #[arg(long, value_enum, default_value_t = CameraModel::Pinhole)]
+    pub camera_model: CameraModel,
+    /// Load the images in grayscale, which uses less memory and speeds up feature extraction. The
+    /// points are then colored with the images' intensity.
+    #[arg(long)]
+    pub no_color: bool,
+    /// Load the images at their full bit depth (e.g. 16 bit PNGs or floating-point EXRs) instead of
+    /// converting them to 8 bit, to color the points with the full precision. Features are still
+    /// extracted from an 8 bit copy. Reading EXR files requires OpenCV's OPENCV_IO_ENABLE_OPENEXR
+    /// environment variable. Video frames are always 8 bit.
+    #[arg(long)]
+    pub raw_depth: bool,
+    /// Downscale images whose largest side is bigger than this many pixels before extracting
+    /// features. The intrinsics of the matching poses are scaled accordingly.
+    #[arg(long, value_name = "PX")]
+    pub max_dimension: Option<i32>,
+    /// Only use the first N images (in file name order), and their poses.
+    #[arg(long, value_name = "N")]
+    pub limit: Option<usize>,
+    /// If specified, the generated point cloud will be written to this file in the PLY format.
+    #[arg(long, value_name = "FILE")]
+    pub export_ply: Option<PathBuf>,
+    /// The format of the exported PLY files, including the one saved from the viewer. Binary files
+    /// are smaller and faster to load.
+    #[arg(long, value_enum, default_value_t = PlyFormat::Ascii)]
+    pub ply_format: PlyFormat,
+    /// If specified, the reconstruction (cameras and points) will be written to this directory as a
+    /// COLMAP text model.
+    #[arg(long, value_name = "DIR")]
+    pub export_colmap: Option<PathBuf>,
+    /// Extend a reconstruction exported with --export-colmap to DIR: the images of the model keep
+    /// their poses and points, and the other images are registered against them by PnP (as with
+    /// --mode incremental). Only the pairs with a new image are triangulated, and their points are
+    /// added to the model's. Needs --intrinsics, the pose file being ignored.
+    #[arg(long, value_name = "DIR")]
+    pub resume: Option<PathBuf>,
+    /// A rigid transform applied to the reconstruction (points and cameras) before it is displayed or
+    /// exported, e.g. to align it to an external frame. Either a file containing a row-major 4x4
+    /// matrix or the 16 values themselves, separated by spaces or commas.
+    #[arg(long, value_name = "VALUES|FILE", allow_hyphen_values = true)]
+    pub transform: Option<String>,
+    /// Accept transforms with a uniform scale factor (similarities) in `--transform`.
+    #[arg(long)]
+    pub allow_similarity: bool,
+    /// The format of the progress output on stdout. With `json`, human readable logs are only
+    /// written to the log file.
+    #[arg(long, value_enum, default_value_t = LogFormat::Human)]
+    pub log_format: LogFormat,
+
+    /// Only run the reconstruction (and export it), without opening the viewer. Progress bars are
+    /// replaced by log lines.
+    #[arg(long)]
+    pub headless: bool,
+    /// Write the points to the --export-ply file as each batch of pairs is triangulated, rather than
+    /// keeping the whole cloud in memory. Needs --headless, and can't be combined with the options
+    /// that need the whole cloud (--pair-mode tracks, --denoise, --voxel-size, --normals and
+    /// --export-colmap).
+    #[arg(long)]
+    pub stream_export: bool,
+    /// Only show up to N points in the viewer, spatially subsampled from the cloud, to keep it
+    /// responsive with dense clouds. The exports still contain every point, but exporting the edited
+    /// cloud from the viewer only saves the points shown.
+    #[arg(long, value_name = "N")]
+    pub max_points: Option<usize>,
+    /// The horizontal field of view of the viewer's camera, in degrees. It can also be changed from
+    /// the viewer.
+    #[arg(long, value_name = "DEG", default_value_t = 64.5)]
+    pub fov: f32,
+    /// The near clipping plane of the viewer's camera. Raise it if distant surfaces flicker
+    /// (z-fighting) in large scenes.
+    #[arg(long, value_name = "DIST", default_value_t = 0.001)]
+    pub near: f32,
+    /// The far clipping plane of the viewer's camera. Raise it if distant points are clipped.
+    #[arg(long, value_name = "DIST", default_value_t = 1000.0)]
+    pub far: f32,
+    /// A JSON file the viewer's camera bookmarks and background color are loaded from and saved to.
+    #[arg(long, value_name = "FILE")]
+    pub bookmarks: Option<PathBuf>,
+    /// A JSON file remapping the viewer's camera keys, e.g. `{"forward": "Up", "toggle_mode": "C"}`.
+    /// The actions are forward, backward, left, right, down, up and toggle_mode. Keys are named by
+    /// their position on a US QWERTY keyboard (letters, digits, Up/Down/Left/Right, Space, Home,
+    /// End, PageUp, PageDown, Insert, Delete and Numpad0 to Numpad9).
+    #[arg(long, value_name = "FILE")]
+    pub controls: Option<PathBuf>,
+    /// Invert the horizontal mouse movements of the viewer's camera (rotation and panning).
+    #[arg(long)]
+    pub invert_x: bool,
+    /// Invert the vertical mouse movements of the viewer's camera (rotation, panning and zoom).
+    #[arg(long)]
+    pub invert_y: bool,
+    /// Print the time spent in each stage of the pipeline at the end of the reconstruction.
+    #[arg(long)]
+    pub timings: bool,
+    /// Write statistics of the reconstruction to FILE as JSON: the keypoints of each image, the
+    /// matches of each pair after each filtering step, the size, mean reprojection error and bounding
+    /// box of the cloud, and the time spent in each stage.
+    #[arg(long, value_name = "FILE")]
+    pub stats_json: Option<PathBuf>,
+    /// Check the inputs without reconstructing anything: list the images, read the poses and match
+    /// them to the images, print a summary and exit. Exits with code 1 if the inputs are inconsistent.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    #[command(flatten)]
+    pub sfm_options: SfmOptions,
 }
 
-fn init_logging() {
+#[derive(Subcommand)]
+pub enum Command {
+    /// Match and triangulate a single pair of images, and report the number of matches and the
+    /// reprojection error. The pipeline options (detector, matching...) go before the subcommand.
+    TwoView(TwoViewArgs),
+}
+
+#[derive(Args)]
+pub struct TwoViewArgs {
+    /// The first image of the pair.
+    #[arg(long, value_name = "IMAGE")]
+    pub left: PathBuf,
+    /// The second image of the pair.
+    #[arg(long, value_name = "IMAGE")]
+    pub right: PathBuf,
+    /// A pose file in the templeRing format containing the poses of both images.
+    #[arg(long, value_name = "FILE")]
+    pub poses: PathBuf,
+    /// Write the visualization of the matches to this image.
+    #[arg(long, value_name = "FILE")]
+    pub matches_image: Option<PathBuf>,
+}
+
+fn init_logging(log_format: LogFormat, progress_bars: bool) {
     #[cfg(debug_assertions)]
-    let log_level = ("trace", flexi_logger::Duplicate::Debug);
+    let mut log_level = ("trace", flexi_logger::Duplicate::Debug);
     #[cfg(not(debug_assertions))]
-    let log_level = ("info", flexi_logger::Duplicate::Info);
+    let mut log_level = ("info", flexi_logger::Duplicate::Info);
+
+    events::set_log_format(log_format);
+    if log_format == LogFormat::Json {
+        // stdout is reserved for the JSON events
+        log_level.1 = flexi_logger::Duplicate::None;
+    } else if progress_bars {
+        // The progress bars replace the progress log lines, which would otherwise break them up
+        log_level.1 = flexi_logger::Duplicate::Warn;
+    }
 
     let file_spec = flexi_logger::FileSpec::default().suppress_timestamp();
 
@@ -48,64 +266,779 @@ fn init_logging() {
         .expect("Failed to build logger");
 }
 
-fn main() {
-    init_logging();
+fn is_image(path: &Path) -> bool {
+    matches!(
+        path.extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .as_deref(),
+        Some("png" | "jpg" | "jpeg")
+    )
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Resizes the image so that its largest side is at most `max_dimension`, and returns the scale
+/// factor that was applied to it.
+fn downscale_image(image: &mut Image, max_dimension: i32) -> Result<f32> {
+    let size = image.size()?;
+    let largest_side = size.width.max(size.height);
+    if largest_side <= max_dimension {
+        return Ok(1.0);
+    }
+
+    let scale = max_dimension as f64 / largest_side as f64;
+    let mut resized = cv::core::Mat::default();
+    cv::imgproc::resize(
+        image,
+        &mut resized,
+        cv::core::Size::default(),
+        scale,
+        scale,
+        cv::imgproc::INTER_AREA,
+    )?;
+
+    let new_size = resized.size()?;
+    log::info!(
+        "\tdownscaled image from {}x{} to {}x{} (K scaled by {:.4})",
+        size.width,
+        size.height,
+        new_size.width,
+        new_size.height,
+        scale
+    );
+    *image = resized;
+
+    Ok(scale as f32)
+}
+
+/// Removes the lens distortion from the image, and returns the intrinsic matrix of the undistorted
+/// (pinhole) image, chosen so that it only contains valid pixels.
+fn undistort_image(
+    image: &mut Image,
+    k: &cv::core::Mat,
+    distortion: &cv::core::Mat,
+    model: CameraModel,
+) -> Result<cv::core::Mat> {
+    let size = image.size()?;
+    let mut undistorted = cv::core::Mat::default();
+    let new_k = match model {
+        CameraModel::Pinhole => {
+            let new_k = cv::calib3d::get_optimal_new_camera_matrix_def(k, distortion, size, 0.0)?;
+            cv::calib3d::undistort(image, &mut undistorted, k, distortion, &new_k)?;
+            new_k
+        }
+        CameraModel::Fisheye => {
+            let mut new_k = cv::core::Mat::default();
+            cv::calib3d::fisheye_estimate_new_camera_matrix_for_undistort_rectify(
+                k,
+                distortion,
+                size,
+                &cv::core::no_array(),
+                &mut new_k,
+                0.0,
+                size,
+                1.0,
+            )?;
+            cv::calib3d::fisheye_undistort_image(
+                image,
+                &mut undistorted,
+                k,
+                distortion,
+                &new_k,
+                size,
+            )?;
+            new_k
+        }
+        CameraModel::Equirect => bail!("Equirectangular images can't be undistorted"),
+    };
+    *image = undistorted;
+
+    let new_k_vals = mat_values(&new_k)?;
+    log::debug!("\tundistorted image, new K: {:?}", new_k_vals);
+
+    Ok(cv::core::Mat::from_slice_rows_cols(&new_k_vals, 3, 3)?)
+}
+
+/// The `imread` flags matching the color and bit depth options. The full bit depth is kept with
+/// `IMREAD_ANYDEPTH` rather than `IMREAD_UNCHANGED`, so that the images still have 1 or 3 channels.
+fn read_flags(cli: &CLI) -> i32 {
+    let color_flags = match cli.no_color {
+        true => cv::imgcodecs::IMREAD_GRAYSCALE,
+        false => cv::imgcodecs::IMREAD_COLOR,
+    };
+    match cli.raw_depth {
+        true => color_flags | cv::imgcodecs::IMREAD_ANYDEPTH,
+        false => color_flags,
+    }
+}
+
+fn read_image(path: &Path, read_flags: i32) -> Result<Image> {
+    let image = cv::imgcodecs::imread(&path.to_string_lossy(), read_flags)?;
+    if image.empty() {
+        bail!("Failed to read image {}", path.to_string_lossy());
+    }
+
+    Ok(image)
+}
+
+/// Runs the `two-view` subcommand. The images are used as is, without undistortion or downscaling,
+/// but rotated with `--rotate`.
+fn run_two_view(cli: &CLI, args: &TwoViewArgs) -> Result<()> {
+    let read_flags = read_flags(cli);
+    let image_paths = [args.left.clone(), args.right.clone()];
+    let mut images = [
+        read_image(&args.left, read_flags)?,
+        read_image(&args.right, read_flags)?,
+    ];
+    let image_names = image_paths
+        .iter()
+        .map(|path| file_name(path))
+        .collect::<Vec<_>>();
+
+    if cli.camera_model != CameraModel::Pinhole {
+        log::warn!(
+            "The two-view images are used as is, the {:?} camera model is ignored",
+            cli.camera_model
+        );
+    }
+    let intrinsics = cli
+        .intrinsics
+        .as_deref()
+        .map(|path| read_intrinsics(path, CameraModel::Pinhole))
+        .transpose()?;
+    let poses_by_name = extract_pose(
+        args.poses.clone(),
+        &cli.pose_comment_prefix,
+        cli.pose_header,
+        intrinsics.as_ref().map(|intrinsics| &intrinsics.k),
+    )?;
+    let (mut poses, _) = match_poses_to_images(poses_by_name, &image_names)?;
+    for (image, pose) in images.iter_mut().zip(&mut poses) {
+        let size = image.size()?;
+        cli.sfm_options.rotate.apply(image)?;
+        rotate_intrinsics(pose, cli.sfm_options.rotate, size)?;
+    }
+
+    let features = compute_features(&images, &image_paths, &cli.sfm_options)?;
+    let points = triangulate_two_views(
+        &images,
+        features,
+        &[poses[0].clone(), poses[1].clone()],
+        &cli.sfm_options,
+        args.matches_image.as_deref(),
+    )?;
+
+    if let Some(export_path) = &cli.export_ply {
+        write_ply(export_path, &points, cli.ply_format)?;
+    }
+
+    Ok(())
+}
+
+/// Reads every `frame_step`-th frame of the video, up to `limit` frames, along with their names.
+fn read_video_frames(
+    path: &Path,
+    frame_step: usize,
+    grayscale: bool,
+    limit: Option<usize>,
+) -> Result<(Vec<String>, Vec<Image>)> {
+    if frame_step == 0 {
+        bail!("The frame step must be at least 1");
+    }
+    let mut capture = cv::videoio::VideoCapture::from_file_def(&path.to_string_lossy())?;
+    if !capture.is_opened()? {
+        bail!("Failed to open video {}", path.to_string_lossy());
+    }
+
+    let mut frame_indices = vec![];
+    let mut frames = vec![];
+    let mut frame = Image::default();
+    let mut frame_idx = 0;
+    while capture.read(&mut frame)? {
+        if frame_idx % frame_step == 0 {
+            let image = match grayscale {
+                true => {
+                    let mut gray = Image::default();
+                    cv::imgproc::cvt_color_def(&frame, &mut gray, cv::imgproc::COLOR_BGR2GRAY)?;
+                    gray
+                }
+                false => frame.try_clone()?,
+            };
+            frame_indices.push(frame_idx);
+            frames.push(image);
+            if limit.is_some_and(|limit| frames.len() >= limit) {
+                break;
+            }
+        }
+        frame_idx += 1;
+    }
+    log::info!(
+        "Extracted {} frames from {} (every {} frames): {:?}",
+        frames.len(),
+        path.to_string_lossy(),
+        frame_step,
+        frame_indices
+    );
+
+    let names = frame_indices
+        .iter()
+        .map(|idx| format!("frame_{:06}", idx))
+        .collect();
+    Ok((names, frames))
+}
+
+/// Logs a summary of the inputs for `--dry-run`, failing if the poses couldn't be read or matched to
+/// the images.
+fn dry_run_summary(
+    cli: &CLI,
+    image_names: &[String],
+    file_poses: &Result<Option<(Vec<cv::core::Mat>, Vec<Vec3>)>>,
+    intrinsics: Option<&Intrinsics>,
+    use_exif_intrinsics: bool,
+) -> Result<()> {
+    if image_names.is_empty() {
+        bail!("No images found");
+    }
+    log::info!("{} images: {}", image_names.len(), image_names.join(", "));
+
+    match file_poses {
+        Ok(Some((poses, _))) => log::info!(
+            "{} poses read in the {:?} format, one for each image",
+            poses.len(),
+            cli.pose_format
+        ),
+        Ok(None) => log::info!("No pose file, the camera poses would be recovered from the images"),
+        Err(error) => bail!("Failed to read pose information: {:#}", error),
+    }
+
+    match (intrinsics, use_exif_intrinsics) {
+        (Some(intrinsics), _) => log::info!(
+            "Intrinsics: {:?} model, K = {:?}, {}",
+            intrinsics.model,
+            mat_values(&intrinsics.k)?,
+            match &intrinsics.distortion {
+                Some(distortion) => format!("distortion = {:?}", mat_values(distortion)?),
+                None => "no distortion".to_owned(),
+            }
+        ),
+        (None, true) => log::info!("Intrinsics: estimated from each image's EXIF data"),
+        (None, false) => log::info!("Intrinsics: read from the pose file"),
+    }
+    log::info!("Dry run: the inputs are consistent");
+
+    Ok(())
+}
+
+/// Checks that the viewer's camera options make a valid perspective projection.
+fn check_viewer_options(cli: &CLI) -> Result<()> {
+    if !(cli.fov > 0.0 && cli.fov < 180.0) {
+        bail!(
+            "The field of view must be between 0 and 180 degrees, got {}",
+            cli.fov
+        );
+    }
+    if !(cli.near > 0.0 && cli.far > cli.near) {
+        bail!(
+            "The clipping planes must satisfy 0 < near < far, got near {} and far {}",
+            cli.near,
+            cli.far
+        );
+    }
 
+    Ok(())
+}
+
+/// Only pinhole cameras are handled by the pipeline, fisheye images are undistorted to pinhole ones
+/// first, which needs their intrinsics.
+fn check_camera_model(cli: &CLI) -> Result<()> {
+    match cli.camera_model {
+        CameraModel::Pinhole => Ok(()),
+        CameraModel::Fisheye => match &cli.intrinsics {
+            Some(path) if path != Path::new(EXIF_INTRINSICS) => Ok(()),
+            _ => bail!(
+                "The fisheye camera model needs an --intrinsics file with K and the distortion \
+                coefficients, to undistort the images"
+            ),
+        },
+        CameraModel::Equirect => bail!(
+            "The equirectangular camera model is not supported yet, the images need to be split \
+            into pinhole views first"
+        ),
+    }
+}
+
+fn check_stream_options(cli: &CLI) -> Result<()> {
+    if !cli.stream_export {
+        return Ok(());
+    }
+    if !cli.headless {
+        bail!("--stream-export only works with --headless, the viewer needs the whole cloud");
+    }
+    if cli.export_ply.is_none() {
+        bail!("--stream-export needs an --export-ply file to write the points to");
+    }
+    if cli.export_colmap.is_some() {
+        bail!(
+            "--stream-export can't be combined with --export-colmap, which needs the whole cloud"
+        );
+    }
+
+    Ok(())
+}
+
+fn check_resume_options(cli: &CLI) -> Result<()> {
+    if cli.resume.is_none() {
+        return Ok(());
+    }
+    if cli.transform.is_some() {
+        bail!(
+            "--resume can't be combined with --transform, the resumed model is already transformed"
+        );
+    }
+    if cli.stream_export {
+        bail!("--resume can't be combined with --stream-export, the model's points must be merged");
+    }
+    if cli.sfm_options.pair_mode == PairMode::Tracks {
+        bail!("--resume can't be combined with --pair-mode tracks, which needs every pair");
+    }
+    if cli.pose_file.is_some() || cli.pose_format != PoseFormat::Templering {
+        bail!("--resume can't be combined with a pose file, the new images are registered by PnP");
+    }
+    if cli.intrinsics.is_none() {
+        bail!("--resume needs --intrinsics to register the new images");
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let start = std::time::Instant::now();
     let cli = CLI::parse();
+    if cli.version_detailed {
+        print!(
+            "{}",
+            version::detailed_version().expect("Failed to read the OpenCV version")
+        );
+        return;
+    }
+    check_viewer_options(&cli).expect("Invalid viewer options");
+    check_stream_options(&cli).expect("Invalid streaming options");
+    check_resume_options(&cli).expect("Invalid resume options");
+    check_camera_model(&cli).expect("Invalid camera model");
+    let controls = match &cli.controls {
+        Some(path) => load_controls(path).expect("Failed to load controls"),
+        None => Controls::default(),
+    };
+    // The statistics include the timings
+    timings::set_enabled(cli.timings || cli.stats_json.is_some());
+    stats::set_enabled(cli.stats_json.is_some());
+
+    let progress_bars =
+        !cli.headless && cli.log_format == LogFormat::Human && std::io::stdout().is_terminal();
+    progress::set_enabled(progress_bars);
+    init_logging(cli.log_format, progress_bars);
+
+    if let Some(seed) = cli.sfm_options.seed {
+        cv::core::set_rng_seed(seed as i32).expect("Failed to seed the random number generator");
+        log::info!("Seeded the random number generator with {}", seed);
+    }
 
-    let mut file_paths: Vec<_> = std::fs::read_dir(&cli.data_path)
-        .expect("Failed to read files in the specified folder")
-        .flatten()
+    if let Some(Command::TwoView(args)) = &cli.command {
+        run_two_view(&cli, args).expect("Failed to reconstruct the pair of images");
+        if cli.timings {
+            timings::print_summary(start.elapsed());
+        }
+        return;
+    }
+    let data_path = match (&cli.data_path, &cli.video) {
+        (Some(data_path), _) => data_path.as_path(),
+        (None, Some(video)) => video
+            .parent()
+            .filter(|folder| !folder.as_os_str().is_empty())
+            .unwrap_or(Path::new(".")),
+        (None, None) => unreachable!("clap requires the data path without a subcommand or --video"),
+    };
+
+    let mut file_paths: Vec<PathBuf> = match cli.recursive {
+        true => walkdir::WalkDir::new(data_path)
+            .into_iter()
+            .flatten()
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.into_path())
+            .collect(),
+        false => std::fs::read_dir(data_path)
+            .expect("Failed to read files in the specified folder")
+            .flatten()
+            .map(|entry| entry.path())
+            .collect(),
+    };
+    // Image indices are used to pair the images, so the order must not depend on the file system
+    file_paths.sort();
+
+    let mut image_paths: Vec<PathBuf> = file_paths
+        .iter()
+        .filter(|path| cli.video.is_none() && is_image(path))
+        .cloned()
         .collect();
-    file_paths.sort_by_key(|a| a.path());
+    if let Some(limit) = cli.limit {
+        if image_paths.len() > limit {
+            log::info!(
+                "Only using the first {} of {} images",
+                limit,
+                image_paths.len()
+            );
+            image_paths.truncate(limit);
+        }
+    }
+
+    let mut image_names = image_paths
+        .iter()
+        .map(|path| file_name(path))
+        .collect::<Vec<_>>();
+
+    let transform = cli
+        .transform
+        .as_ref()
+        .map(|arg| parse_transform(arg, cli.allow_similarity))
+        .transpose()
+        .expect("Invalid output transform");
 
-    let pose_file = file_paths
+    let use_exif_intrinsics = cli.intrinsics.as_deref() == Some(Path::new(EXIF_INTRINSICS));
+    let intrinsics = cli
+        .intrinsics
+        .as_deref()
+        .filter(|_| !use_exif_intrinsics)
+        .map(|path| read_intrinsics(path, cli.camera_model))
+        .transpose()
+        .expect("Failed to read intrinsics");
+
+    let read_flags = read_flags(&cli);
+    let mut images: Vec<Image> = match &cli.video {
+        Some(video) => {
+            let (frame_names, frames) =
+                read_video_frames(video, cli.frame_step, cli.no_color, cli.limit)
+                    .expect("Failed to read the frames of the video");
+            image_names = frame_names;
+            frames
+        }
+        None => {
+            log::info!("loading images from: {}", data_path.to_string_lossy());
+            image_paths
+                .iter()
+                .map(|path| -> Result<Image> {
+                    let img = read_image(path, read_flags)?;
+                    log::debug!("\tloaded {}", path.to_string_lossy());
+                    Ok(img)
+                })
+                .collect::<Result<_>>()
+                .expect("Failed to load the images")
+        }
+    };
+    log::info!("loaded {} images", images.len());
+
+    // Bundler and VisualSFM put the principal point at the center of the images
+    let image_sizes: HashMap<String, Vec2> = image_names
         .iter()
-        .find(|entry| {
-            &entry.file_name().to_string_lossy().to_string()
-                == cli.pose_file.as_ref().unwrap_or(&"pose.txt".to_owned())
+        .cloned()
+        .zip(&images)
+        .map(|(name, image)| -> Result<(String, Vec2)> {
+            let size = image.size()?;
+            Ok((name, Vec2::new(size.width as f32, size.height as f32)))
         })
-        .expect("Failed to find pose file")
-        .path();
-    log::info!("Found pose file {}", pose_file.to_string_lossy());
-
-    let (poses, camera_positions) =
-        extract_pose(pose_file).expect("Failed to read pose information");
-
-    log::info!("loading images from: {}", cli.data_path.to_string_lossy());
-    let images: Vec<Image> = file_paths
-        .into_iter()
-        .flat_map(|entry| -> Result<Image> {
-            match &*entry
-                .path()
-                .extension()
-                .context("Failed to parse file extension")?
-                .to_string_lossy()
+        .collect::<Result<_>>()
+        .expect("Failed to read image sizes");
+
+    let file_poses = match cli.pose_format {
+        PoseFormat::Templering if cli.resume.is_some() => {
+            log::info!("Resuming a reconstruction, ignoring the pose file");
+            None
+        }
+        PoseFormat::Templering => {
+            let pose_file_name = cli.pose_file.as_deref().unwrap_or("pose.txt");
+            match file_paths
+                .iter()
+                .find(|path| file_name(path) == pose_file_name)
             {
-                "png" | "jpg" | "jpeg" => {
-                    let img = cv::imgcodecs::imread(
-                        &entry.path().to_string_lossy(),
-                        cv::imgcodecs::IMREAD_COLOR,
-                    )?;
-                    // let mut rotated_img = cv::core::Mat::default();
-                    // cv::core::rotate(
-                    //     &img,
-                    //     &mut rotated_img,
-                    //     cv::core::RotateFlags::ROTATE_90_COUNTERCLOCKWISE.into(),
-                    // )
-                    // .expect("Failed to rotate image");
-                    log::debug!("\tloaded {}", entry.path().to_string_lossy());
-                    // Ok(rotated_img)
-                    Ok(img)
+                Some(pose_file) => {
+                    let pose_file = pose_file.clone();
+                    log::info!("Found pose file {}", pose_file.to_string_lossy());
+                    Some(
+                        extract_pose(
+                            pose_file,
+                            &cli.pose_comment_prefix,
+                            cli.pose_header,
+                            intrinsics.as_ref().map(|intrinsics| &intrinsics.k),
+                        )
+                        .and_then(|poses| match_poses_to_images(poses, &image_names)),
+                    )
                 }
+                None if cli.pose_file.is_none()
+                    && (intrinsics.is_some() || use_exif_intrinsics) =>
+                {
+                    log::info!(
+                        "No pose file found, camera poses will be recovered from the images"
+                    );
+                    None
+                }
+                None => Some(Err(anyhow!("Failed to find pose file {}", pose_file_name))),
+            }
+        }
+        PoseFormat::Colmap => Some(extract_colmap_pose(data_path, &image_names)),
+        PoseFormat::Bundler => {
+            let bundle_path = data_path.join(cli.pose_file.as_deref().unwrap_or("bundle.out"));
+            Some(
+                extract_bundler_pose(&bundle_path, &image_sizes)
+                    .and_then(|poses| match_poses_to_images(poses, &image_names)),
+            )
+        }
+        PoseFormat::Nvm => {
+            let nvm_path = data_path.join(cli.pose_file.as_deref().unwrap_or("model.nvm"));
+            Some(
+                extract_nvm_pose(&nvm_path, &image_sizes)
+                    .and_then(|poses| match_poses_to_images(poses, &image_names)),
+            )
+        }
+    }
+    .transpose();
 
-                _ => None.context("Invalid file extention")?,
+    if cli.dry_run {
+        let exit_code = match dry_run_summary(
+            &cli,
+            &image_names,
+            &file_poses,
+            intrinsics.as_ref(),
+            use_exif_intrinsics,
+        ) {
+            Ok(()) => 0,
+            Err(error) => {
+                log::error!("{:#}", error);
+                1
             }
+        };
+        std::process::exit(exit_code);
+    }
+    let mut file_poses = file_poses.expect("Failed to read pose information");
+
+    // Each image's intrinsics are updated separately when they are undistorted or downscaled
+    let mut image_intrinsics = match (&file_poses, &intrinsics) {
+        (None, Some(intrinsics)) => vec![intrinsics.k.clone(); images.len()],
+        (None, None) if use_exif_intrinsics => {
+            let sizes = image_names
+                .iter()
+                .map(|name| image_sizes[name])
+                .collect::<Vec<_>>();
+            exif_intrinsics(&image_paths, &sizes).expect("Failed to estimate intrinsics from EXIF")
+        }
+        _ => vec![],
+    };
+
+    if let Some(Intrinsics {
+        k,
+        distortion: Some(distortion),
+        model,
+    }) = &intrinsics
+    {
+        log::info!("Undistorting {:?} images", model);
+        for (idx, image) in images.iter_mut().enumerate() {
+            let new_k =
+                undistort_image(image, k, distortion, *model).expect("Failed to undistort image");
+            match &mut file_poses {
+                Some((poses, _)) => {
+                    if let Some(pose) = poses.get_mut(idx) {
+                        *pose = replace_intrinsics(pose, k, &new_k)
+                            .expect("Failed to update the pose's intrinsics");
+                    }
+                }
+                None => image_intrinsics[idx] = new_k,
+            }
+        }
+    }
+
+    let rotation = cli.sfm_options.rotate;
+    if rotation != ImageRotation::None {
+        log::info!("Rotating the images by {} degrees", rotation.degrees());
+        for (idx, image) in images.iter_mut().enumerate() {
+            let size = image.size().expect("Failed to read image size");
+            rotation.apply(image).expect("Failed to rotate image");
+            let calibration = match &mut file_poses {
+                Some((poses, _)) => poses.get_mut(idx),
+                None => image_intrinsics.get_mut(idx),
+            };
+            if let Some(calibration) = calibration {
+                rotate_intrinsics(calibration, rotation, size)
+                    .expect("Failed to rotate intrinsics");
+            }
+        }
+    }
+
+    if let Some(max_dimension) = cli.max_dimension {
+        for (idx, image) in images.iter_mut().enumerate() {
+            let scale = downscale_image(image, max_dimension).expect("Failed to downscale image");
+            let calibration = match &mut file_poses {
+                Some((poses, _)) => poses.get_mut(idx),
+                None => image_intrinsics.get_mut(idx),
+            };
+            if let Some(calibration) = calibration {
+                scale_intrinsics(calibration, scale).expect("Failed to scale intrinsics");
+            }
+        }
+    }
+
+    let features = compute_features(&images, &image_paths, &cli.sfm_options)
+        .expect("Failed to extract image features");
+    let resumed = cli
+        .resume
+        .as_ref()
+        .map(|dir| -> Result<_> {
+            let known_extrinsics = read_colmap_extrinsics(dir, &image_names)?;
+            log::info!(
+                "Resuming the reconstruction of {} of the {} images from {}",
+                known_extrinsics.iter().flatten().count(),
+                image_names.len(),
+                dir.to_string_lossy()
+            );
+            Ok((known_extrinsics, read_colmap_points(dir)?))
         })
-        .collect();
-    log::info!("loaded {} images", images.len());
+        .transpose()
+        .expect("Failed to read the resumed reconstruction");
+    let resumed_images = match &resumed {
+        Some((known_extrinsics, _)) => known_extrinsics.iter().map(Option::is_some).collect(),
+        None => vec![],
+    };
+    // The viewer places the cameras from their decomposed poses rather than from these positions
+    let (mut poses, _) = match file_poses {
+        Some(file_poses) => {
+            if cli.sfm_options.mode == ReconstructionMode::Incremental {
+                log::warn!("The camera poses are read from a file, ignoring --mode incremental");
+            }
+            file_poses
+        }
+        None => match (&resumed, cli.sfm_options.mode) {
+            (Some((known_extrinsics, _)), _) => register_incremental(
+                &images,
+                &features,
+                &image_intrinsics,
+                known_extrinsics,
+                &cli.sfm_options,
+            ),
+            (None, ReconstructionMode::Pairwise) => {
+                recover_poses(&features, &image_intrinsics, &cli.sfm_options)
+            }
+            (None, ReconstructionMode::Incremental) => {
+                register_incremental(&images, &features, &image_intrinsics, &[], &cli.sfm_options)
+            }
+        }
+        .expect("Failed to recover camera poses"),
+    };
+
+    let camera_image_sizes = images
+        .iter()
+        .map(|image| image.size())
+        .collect::<cv::Result<Vec<_>>>()
+        .expect("Failed to read image sizes");
+
+    let mut ply_stream = cli
+        .export_ply
+        .as_ref()
+        .filter(|_| cli.stream_export)
+        .map(|export_path| PlyStreamWriter::create(export_path, cli.ply_format))
+        .transpose()
+        .expect("Failed to export point cloud");
+    let mut points = match &mut ply_stream {
+        Some(ply_stream) => {
+            let mut sink = |mut points: Vec<Point>| {
+                if let Some(transform) = &transform {
+                    apply_transform(transform, &mut points, &mut []);
+                }
+                ply_stream.write_points(&points)
+            };
+            generate_point_cloud(
+                images,
+                features,
+                &mut poses,
+                &cli.sfm_options,
+                &resumed_images,
+                Some(&mut sink),
+            )
+        }
+        None => generate_point_cloud(
+            images,
+            features,
+            &mut poses,
+            &cli.sfm_options,
+            &resumed_images,
+            None,
+        ),
+    }
+    .expect("Failed to generate cloud point");
+    if let Some((_, resumed_points)) = resumed {
+        log::info!(
+            "Adding {} new points to the {} points of the resumed model",
+            points.len(),
+            resumed_points.len()
+        );
+        points.extend(resumed_points);
+    }
+
+    let mut cameras = poses
+        .iter()
+        .zip(camera_image_sizes)
+        .map(|(pose, image_size)| camera_view(pose, image_size))
+        .collect::<Result<Vec<_>>>()
+        .expect("Failed to decompose camera poses");
+    if let Some(transform) = &transform {
+        log::info!("Applying output transform {}", transform);
+        apply_transform(transform, &mut points, &mut cameras);
+    }
+    if ply_stream.is_none() {
+        stats::record_cloud(&points);
+    }
+
+    if let Some(export_path) = &cli.export_ply {
+        let stage = Stage::start("export");
+        match ply_stream {
+            Some(ply_stream) => ply_stream.finish().map(stats::record_point_count),
+            None => write_ply(export_path, &points, cli.ply_format),
+        }
+        .expect("Failed to export point cloud");
+        stage.finish();
+    }
+    if let Some(export_dir) = &cli.export_colmap {
+        let stage = Stage::start("export_colmap");
+        write_colmap(export_dir, &points, &cameras, &image_names)
+            .expect("Failed to export COLMAP model");
+        stage.finish();
+    }
+
+    if cli.timings {
+        timings::print_summary(start.elapsed());
+    }
+    if let Some(stats_path) = &cli.stats_json {
+        stats::write(stats_path, &image_names, start.elapsed())
+            .expect("Failed to write the statistics");
+    }
+
+    if cli.headless {
+        return;
+    }
 
-    let points = generate_point_cloud(images, poses).expect("Failed to generate cloud point");
+    if let Some(max_points) = cli
+        .max_points
+        .filter(|max_points| points.len() > *max_points)
+    {
+        let point_count = points.len();
+        points = filters::subsample(&points, max_points);
+        log::info!(
+            "Showing {} of the {} points in the viewer (--max-points {})",
+            points.len(),
+            point_count,
+            max_points
+        );
+    }
 
     ApplicationBuilder::new()
         .with_window_name("Point cloud viewer")
@@ -114,6 +1047,18 @@ fn main() {
         .with_application_version(0, 1, 0)
         .build_and_run_inplace::<RenderState, PointCloudData>(PointCloudData {
             points,
-            camera_positions,
+            cameras,
+            export_path: cli
+                .export_ply
+                .clone()
+                .unwrap_or_else(|| PathBuf::from("edited_cloud.ply")),
+            export_format: cli.ply_format,
+            bookmarks_path: cli.bookmarks.clone(),
+            horizontal_fov: cli.fov,
+            near_plane: cli.near,
+            far_plane: cli.far,
+            controls,
+            invert_x: cli.invert_x,
+            invert_y: cli.invert_y,
         });
 }
@@ -0,0 +1,142 @@
+use anyhow::Result;
+use cv::core::{DMatch, Mat, Vector};
+use opencv as cv;
+use opencv::prelude::*;
+
+/// Ratio used by Lowe's ratio test to discard ambiguous matches.
+pub const DEFAULT_RATIO: f32 = 0.7;
+
+pub trait Matcher {
+    /// Name of the matching backend, for reporting purposes.
+    fn name(&self) -> &'static str;
+
+    /// Finds the 2 nearest neighbors of each descriptor of `descriptors1` (the query set) in
+    /// `descriptors2` (the train set), to be filtered with `ratio_test`.
+    fn knn_match(&self, descriptors1: &Mat, descriptors2: &Mat) -> Result<Vector<Vector<DMatch>>>;
+}
+
+/// Only keeps the best candidate of each 2-nearest-neighbors match if it is clearly better than the
+/// second best one. A ratio of 1 or more disables the test, keeping every nearest neighbor.
+pub fn ratio_test(knn_matches: &Vector<Vector<DMatch>>, ratio: f32) -> Vector<DMatch> {
+    knn_matches
+        .iter()
+        .flat_map(|img_match| {
+            let best = img_match.get(0).ok()?;
+            match img_match.get(1) {
+                Ok(second) if ratio < 1.0 && best.distance >= ratio * second.distance => None,
+                _ => Some(best),
+            }
+        })
+        .collect()
+}
+
+/// Approximate nearest neighbors matching using FLANN's KD-tree, for floating point descriptors.
+pub struct FlannMatcher {
+    matcher: cv::features2d::FlannBasedMatcher,
+}
+
+impl FlannMatcher {
+    pub fn new() -> Result<Self> {
+        let index_params = cv::flann::KDTreeIndexParams::new(5)?;
+        let search_params = cv::flann::SearchParams::new_def()?;
+        let matcher = cv::features2d::FlannBasedMatcher::new(
+            &cv::core::Ptr::new(index_params.into()),
+            &cv::core::Ptr::new(search_params),
+        )?;
+
+        Ok(Self { matcher })
+    }
+}
+
+impl Matcher for FlannMatcher {
+    fn name(&self) -> &'static str {
+        "FLANN (CPU)"
+    }
+
+    fn knn_match(&self, descriptors1: &Mat, descriptors2: &Mat) -> Result<Vector<Vector<DMatch>>> {
+        let mut matches = Vector::<Vector<DMatch>>::new();
+        self.matcher
+            .knn_train_match_def(descriptors1, descriptors2, &mut matches, 2)?;
+
+        Ok(matches)
+    }
+}
+
+/// Exact nearest neighbors matching. Use `NORM_HAMMING` for binary descriptors (ORB), and `NORM_L2`
+/// for floating point ones.
+pub struct BruteForceMatcher {
+    matcher: cv::features2d::BFMatcher,
+}
+
+impl BruteForceMatcher {
+    pub fn new(norm_type: i32) -> Result<Self> {
+        Ok(Self {
+            matcher: cv::features2d::BFMatcher::new(norm_type, false)?,
+        })
+    }
+}
+
+impl Matcher for BruteForceMatcher {
+    fn name(&self) -> &'static str {
+        "brute-force (CPU)"
+    }
+
+    fn knn_match(&self, descriptors1: &Mat, descriptors2: &Mat) -> Result<Vector<Vector<DMatch>>> {
+        let mut matches = Vector::<Vector<DMatch>>::new();
+        self.matcher
+            .knn_train_match_def(descriptors1, descriptors2, &mut matches, 2)?;
+
+        Ok(matches)
+    }
+}
+
+/// Exact nearest neighbors matching on the GPU, through OpenCV's CUDA module.
+#[cfg(feature = "cuda")]
+pub struct CudaMatcher {
+    matcher: std::sync::Mutex<cv::core::Ptr<cv::cudafeatures2d::DescriptorMatcher>>,
+}
+
+#[cfg(feature = "cuda")]
+impl CudaMatcher {
+    /// Returns `None` if no CUDA capable device is available.
+    pub fn new(norm_type: i32) -> Result<Option<Self>> {
+        if cv::core::get_cuda_enabled_device_count()? == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            matcher: std::sync::Mutex::new(
+                cv::cudafeatures2d::DescriptorMatcher::create_bf_matcher(norm_type)?,
+            ),
+        }))
+    }
+}
+
+#[cfg(feature = "cuda")]
+impl Matcher for CudaMatcher {
+    fn name(&self) -> &'static str {
+        "brute-force (CUDA)"
+    }
+
+    fn knn_match(&self, descriptors1: &Mat, descriptors2: &Mat) -> Result<Vector<Vector<DMatch>>> {
+        let mut gpu_descriptors1 = cv::core::GpuMat::default()?;
+        gpu_descriptors1.upload(descriptors1)?;
+        let mut gpu_descriptors2 = cv::core::GpuMat::default()?;
+        gpu_descriptors2.upload(descriptors2)?;
+
+        let mut matches = Vector::<Vector<DMatch>>::new();
+        self.matcher
+            .lock()
+            .map_err(|_| anyhow::anyhow!("CUDA matcher lock was poisoned"))?
+            .knn_match(
+                &gpu_descriptors1,
+                &gpu_descriptors2,
+                &mut matches,
+                2,
+                &cv::core::no_array(),
+                false,
+            )?;
+
+        Ok(matches)
+    }
+}
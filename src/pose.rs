@@ -1,58 +1,231 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
 
-use anyhow::Result;
-use cv::prelude::MatExprTraitConst;
-use morrigu::math_types::Vec3;
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use cv::prelude::*;
+use morrigu::math_types::{Mat3, Mat4, Quat, Vec2, Vec3};
 use opencv as cv;
 
-pub fn extract_pose(pose_file_path: PathBuf) -> Result<(Vec<cv::core::Mat>, Vec<Vec3>)> {
-    let file_contents = std::fs::read_to_string(pose_file_path)?;
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PoseFormat {
+    /// A single file with one `name K R t` line per image, as in the templeRing dataset.
+    Templering,
+    /// COLMAP's `cameras.txt` and `images.txt` text export.
+    Colmap,
+    /// Bundler's `bundle.out`, with the images listed in the same order in a `list.txt` file next to
+    /// it.
+    Bundler,
+    /// VisualSFM's `.nvm` format. Only the first model of the file is read.
+    Nvm,
+}
+
+/// The projection of the cameras' lenses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CameraModel {
+    /// A pinhole camera, with an optional radial-tangential distortion (k1 k2 p1 p2 k3).
+    Pinhole,
+    /// OpenCV's equidistant fisheye model, with 4 distortion coefficients (k1 k2 k3 k4). The images
+    /// are undistorted to a pinhole camera before being processed.
+    Fisheye,
+    /// A 360° equirectangular projection. Not supported yet.
+    Equirect,
+}
+
+impl CameraModel {
+    /// The number of distortion coefficients of the model, and their names.
+    fn distortion_coefficients(self) -> (usize, &'static str) {
+        match self {
+            CameraModel::Pinhole | CameraModel::Equirect => (5, "k1 k2 p1 p2 k3"),
+            CameraModel::Fisheye => (4, "k1 k2 k3 k4"),
+        }
+    }
+}
+
+/// A counterclockwise rotation of the input images, e.g. for portrait photos stored in landscape.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ImageRotation {
+    #[default]
+    #[value(name = "0")]
+    None,
+    #[value(name = "90")]
+    Quarter,
+    #[value(name = "180")]
+    Half,
+    #[value(name = "270")]
+    ThreeQuarters,
+}
+
+impl ImageRotation {
+    pub fn degrees(self) -> u32 {
+        match self {
+            ImageRotation::None => 0,
+            ImageRotation::Quarter => 90,
+            ImageRotation::Half => 180,
+            ImageRotation::ThreeQuarters => 270,
+        }
+    }
 
-    let mut lines = file_contents.lines();
-    lines.next(); // ignore first line
+    /// Rotates the image in place.
+    pub fn apply(self, image: &mut cv::core::Mat) -> Result<()> {
+        let rotate_flag = match self {
+            ImageRotation::None => return Ok(()),
+            ImageRotation::Quarter => cv::core::RotateFlags::ROTATE_90_COUNTERCLOCKWISE,
+            ImageRotation::Half => cv::core::RotateFlags::ROTATE_180,
+            ImageRotation::ThreeQuarters => cv::core::RotateFlags::ROTATE_90_CLOCKWISE,
+        };
+        let mut rotated = cv::core::Mat::default();
+        cv::core::rotate(image, &mut rotated, rotate_flag.into())?;
+        *image = rotated;
+
+        Ok(())
+    }
+
+    /// The transform from the pixel coordinates of an image of `size` to those of the rotated image.
+    fn pixel_transform(self, size: cv::core::Size) -> Mat3 {
+        let (width, height) = ((size.width - 1) as f32, (size.height - 1) as f32);
+        match self {
+            ImageRotation::None => Mat3::IDENTITY,
+            ImageRotation::Quarter => Mat3::from_cols(
+                Vec3::new(0.0, -1.0, 0.0),
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(0.0, width, 1.0),
+            ),
+            ImageRotation::Half => Mat3::from_cols(
+                Vec3::new(-1.0, 0.0, 0.0),
+                Vec3::new(0.0, -1.0, 0.0),
+                Vec3::new(width, height, 1.0),
+            ),
+            ImageRotation::ThreeQuarters => Mat3::from_cols(
+                Vec3::new(0.0, 1.0, 0.0),
+                Vec3::new(-1.0, 0.0, 0.0),
+                Vec3::new(height, 0.0, 1.0),
+            ),
+        }
+    }
+}
+
+/// Whether the first data line of a templeRing pose file is a header (e.g. the image count).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PoseHeader {
+    /// The first line is a header unless it reads as a pose.
+    Auto,
+    /// The first line is always skipped.
+    Yes,
+    /// Every line is a pose.
+    No,
+}
+
+/// Filename, followed by the values of K (3x3), R (3x3) and t (3).
+const POSE_LINE_TOKENS: usize = 1 + 9 + 9 + 3;
+/// Filename, followed by the values of R (3x3) and t (3), K being given separately.
+const EXTRINSICS_LINE_TOKENS: usize = 1 + 9 + 3;
+
+/// The projection matrix and camera position of each image, by filename.
+pub type PosesByName = HashMap<String, (cv::core::Mat, Vec3)>;
+
+/// Whether the tokens of a line are a filename followed by the values of a pose, with or without K.
+fn is_pose_line(params: &[&str]) -> bool {
+    matches!(params.len(), POSE_LINE_TOKENS | EXTRINSICS_LINE_TOKENS)
+        && params[1..].iter().all(|value| value.parse::<f32>().is_ok())
+}
+
+/// Reads a pose file in the templeRing format. Lines can leave out K, in which case `intrinsics` is
+/// used instead. `header` tells whether the first line is skipped.
+pub fn extract_pose(
+    pose_file_path: PathBuf,
+    comment_prefix: &str,
+    header: PoseHeader,
+    intrinsics: Option<&cv::core::Mat>,
+) -> Result<PosesByName> {
+    let file_contents = std::fs::read_to_string(&pose_file_path).with_context(|| {
+        format!(
+            "Failed to read pose file {}",
+            pose_file_path.to_string_lossy()
+        )
+    })?;
+
+    // Blank lines and comments can appear anywhere in the file, only data rows are kept. An empty
+    // prefix disables comments rather than matching every line.
+    let mut lines = file_contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| {
+            let line = line.trim_start();
+            !line.is_empty() && (comment_prefix.is_empty() || !line.starts_with(comment_prefix))
+        })
+        .peekable();
+    if let Some((line_idx, line)) = lines.peek() {
+        let params = line.split_whitespace().collect::<Vec<_>>();
+        let skip_first_line = match header {
+            PoseHeader::Auto => !is_pose_line(&params),
+            PoseHeader::Yes => true,
+            PoseHeader::No => false,
+        };
+        match skip_first_line {
+            true => log::info!(
+                "Skipping line {} of the pose file as a header",
+                line_idx + 1
+            ),
+            false => log::info!(
+                "The pose file has no header, line {} is a pose",
+                line_idx + 1
+            ),
+        }
+        if skip_first_line {
+            lines.next();
+        }
+    }
 
     log::info!("Extracting pose data for images");
-    let mut poses = vec![];
-    let mut camera_positions = vec![];
-    for line in lines {
-        let mut params = line.split(' ');
-        let filename = params.next().unwrap(); // ignore filename
-
-        let k_vals = params
-            .clone()
-            .take(9)
-            .map(|s| {
-                s.parse::<f32>()
-                    .expect("Failed to parse float value for the K matrix")
-            })
-            .collect::<Vec<_>>();
-        let k = cv::core::Mat::from_slice_rows_cols(&k_vals, 3, 3)?;
-        let params = params.skip(9);
-
-        let mut rt_vals = params
-            .clone()
-            .take(9)
-            .map(|s| {
-                s.parse::<f32>()
-                    .expect("Failed to parse float value for the R matrix")
-            })
-            .collect::<Vec<_>>();
-        let mut params = params.skip(9);
+    let mut poses = HashMap::new();
+    for (line_idx, line) in lines {
+        let line_number = line_idx + 1;
+        let params = line.split_whitespace().collect::<Vec<_>>();
+        let (k, extrinsics) = match params.len() {
+            POSE_LINE_TOKENS => {
+                let k_vals = parse_values(&params[1..10], "K", line_number)?;
+                (
+                    cv::core::Mat::from_slice_rows_cols(&k_vals, 3, 3)?,
+                    &params[10..],
+                )
+            }
+            EXTRINSICS_LINE_TOKENS => match intrinsics {
+                Some(k) => (k.clone(), &params[1..]),
+                None => bail!(
+                    "Line {} has no intrinsics (K), and none were given with --intrinsics",
+                    line_number
+                ),
+            },
+            token_count => bail!(
+                "Expected {} values (filename, K, R and t) or {} values (filename, R and t) on line {}, found {}",
+                POSE_LINE_TOKENS,
+                EXTRINSICS_LINE_TOKENS,
+                line_number,
+                token_count
+            ),
+        };
+        let filename = params[0];
 
-        let position = Vec3::new(
-            params.next().unwrap().parse::<f32>().unwrap(),
-            params.next().unwrap().parse::<f32>().unwrap(),
-            params.next().unwrap().parse::<f32>().unwrap(),
-        );
+        let mut rt_vals = parse_values(&extrinsics[0..9], "R", line_number)?;
+
+        let t_vals = parse_values(&extrinsics[9..12], "t", line_number)?;
+        let position = Vec3::new(t_vals[0], t_vals[1], t_vals[2]);
 
-        camera_positions.push(position);
         rt_vals.insert(3, position.x);
         rt_vals.insert(7, position.y);
         rt_vals.insert(11, position.z);
 
         let rt = cv::core::Mat::from_slice_rows_cols(&rt_vals, 3, 4)?;
 
+        let k_vals = mat_values(&k)?;
         let pose = (k * rt).into_result()?.to_mat()?;
+        validate_pose(&k_vals, &rt_vals, &pose)
+            .with_context(|| format!("Invalid pose for {} on line {}", filename, line_number))?;
 
         log::debug!("\tExtracted values for {}:", filename);
         log::debug!("\t\tk: {:?}", k_vals);
@@ -62,9 +235,948 @@ pub fn extract_pose(pose_file_path: PathBuf) -> Result<(Vec<cv::core::Mat>, Vec<
             pose.iter::<f32>()?.map(|(_, val)| val).collect::<Vec<_>>()
         );
 
-        poses.push(pose);
+        if poses
+            .insert(filename.to_owned(), (pose, position))
+            .is_some()
+        {
+            bail!(
+                "Line {} contains another pose for {}",
+                line_number,
+                filename
+            );
+        }
     }
     log::info!("Extracted pose data for images");
 
+    Ok(poses)
+}
+
+/// Looks up the pose of each image by its filename, so that the images don't have to be listed in
+/// the same order as in the pose file.
+pub fn match_poses_to_images(
+    mut poses_by_name: PosesByName,
+    image_names: &[String],
+) -> Result<(Vec<cv::core::Mat>, Vec<Vec3>)> {
+    let mut poses = Vec::with_capacity(image_names.len());
+    let mut camera_positions = Vec::with_capacity(image_names.len());
+    for name in image_names {
+        let (pose, position) = poses_by_name
+            .remove(name)
+            .with_context(|| format!("No pose found for image {}", name))?;
+        poses.push(pose);
+        camera_positions.push(position);
+    }
+    if !poses_by_name.is_empty() {
+        log::debug!(
+            "\tignored the poses of {} images that were not loaded",
+            poses_by_name.len()
+        );
+    }
+
+    Ok((poses, camera_positions))
+}
+
+/// Checks that a projection matrix can be used for triangulation: an invalid pose line would
+/// otherwise silently produce NaN or infinite points.
+fn validate_pose(k_vals: &[f32], rt_vals: &[f32], pose: &cv::core::Mat) -> Result<()> {
+    let pose_vals = mat_values(pose)?;
+    if !k_vals
+        .iter()
+        .chain(rt_vals)
+        .chain(&pose_vals)
+        .all(|value| value.is_finite())
+    {
+        bail!("the pose contains non-finite values");
+    }
+
+    let rotation = Mat3::from_cols_array(&[
+        rt_vals[0],
+        rt_vals[4],
+        rt_vals[8],
+        rt_vals[1],
+        rt_vals[5],
+        rt_vals[9],
+        rt_vals[2],
+        rt_vals[6],
+        rt_vals[10],
+    ]);
+    if rotation.determinant().abs() < 1e-6 {
+        bail!("the rotation is singular");
+    }
+    let k = Mat3::from_cols_slice(k_vals).transpose();
+    if k.determinant().abs() < 1e-6 {
+        bail!("the intrinsic matrix is singular");
+    }
+
+    Ok(())
+}
+
+fn parse_values(tokens: &[&str], label: &str, line_number: usize) -> Result<Vec<f32>> {
+    tokens
+        .iter()
+        .enumerate()
+        .map(|(idx, token)| {
+            token.parse::<f32>().with_context(|| {
+                format!(
+                    "Failed to parse {}[{}] (\"{}\") on line {}",
+                    label, idx, token, line_number
+                )
+            })
+        })
+        .collect()
+}
+
+/// Reads the intrinsic matrices of a COLMAP `cameras.txt` file, by camera id.
+fn parse_colmap_cameras(path: &Path) -> Result<HashMap<u32, [f32; 9]>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.to_string_lossy()))?;
+
+    let mut cameras = HashMap::new();
+    for (line_idx, line) in contents.lines().enumerate() {
+        let line_number = line_idx + 1;
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        if tokens.is_empty() || tokens[0].starts_with('#') {
+            continue;
+        }
+        if tokens.len() < 4 {
+            bail!("Incomplete camera on line {} of cameras.txt", line_number);
+        }
+
+        let camera_id = tokens[0]
+            .parse::<u32>()
+            .with_context(|| format!("Invalid camera id on line {}", line_number))?;
+        let params = parse_values(&tokens[4..], "PARAMS", line_number)?;
+        let (fx, fy, cx, cy) = match (tokens[1], params.as_slice()) {
+            ("SIMPLE_PINHOLE" | "SIMPLE_RADIAL" | "RADIAL", [f, cx, cy, ..]) => (*f, *f, *cx, *cy),
+            ("PINHOLE" | "OPENCV", [fx, fy, cx, cy, ..]) => (*fx, *fy, *cx, *cy),
+            (model, _) => bail!(
+                "Unsupported camera model {} (or missing parameters) on line {}",
+                model,
+                line_number
+            ),
+        };
+        if tokens[1] != "SIMPLE_PINHOLE" && tokens[1] != "PINHOLE" {
+            log::warn!(
+                "Camera {} uses the {} model, its distortion parameters are ignored",
+                camera_id,
+                tokens[1]
+            );
+        }
+
+        cameras.insert(camera_id, [fx, 0.0, cx, 0.0, fy, cy, 0.0, 0.0, 1.0]);
+    }
+
+    Ok(cameras)
+}
+
+/// An image of a COLMAP `images.txt` file.
+struct ColmapImage {
+    name: String,
+    camera_id: u32,
+    rotation: Mat3,
+    translation: Vec3,
+    line_number: usize,
+}
+
+/// Reads the images of a COLMAP `images.txt` file, the images being identified by file name.
+fn parse_colmap_images(path: &Path) -> Result<Vec<ColmapImage>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.to_string_lossy()))?;
+
+    // Each image takes two lines, the second one (its 2D points) being empty if the image has no
+    // observations, so only comments are skipped and the lines are read in pairs
+    let mut images = vec![];
+    let mut lines = contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.starts_with('#'));
+    while let Some((line_idx, line)) = lines.next() {
+        let line_number = line_idx + 1;
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        if tokens.is_empty() {
+            // A blank line in place of an image, such as at the end of the file
+            continue;
+        }
+        lines.next(); // ignore 2D points
+
+        if tokens.len() < 10 {
+            bail!("Incomplete image on line {} of images.txt", line_number);
+        }
+
+        let values = parse_values(&tokens[1..8], "QT", line_number)?;
+        let camera_id = tokens[8]
+            .parse::<u32>()
+            .with_context(|| format!("Invalid camera id on line {}", line_number))?;
+        images.push(ColmapImage {
+            name: image_file_name(&tokens[9..].join(" ")),
+            camera_id,
+            rotation: Mat3::from_quat(
+                Quat::from_xyzw(values[1], values[2], values[3], values[0]).normalize(),
+            ),
+            translation: Vec3::new(values[4], values[5], values[6]),
+            line_number,
+        });
+    }
+
+    Ok(images)
+}
+
+/// Reads the poses of a COLMAP text export (`cameras.txt` and `images.txt` in `folder`), ordered like
+/// `image_names`.
+pub fn extract_colmap_pose(
+    folder: &Path,
+    image_names: &[String],
+) -> Result<(Vec<cv::core::Mat>, Vec<Vec3>)> {
+    log::info!("Extracting COLMAP pose data for images");
+    let cameras = parse_colmap_cameras(&folder.join("cameras.txt"))?;
+
+    let mut poses_by_name = HashMap::new();
+    for image in parse_colmap_images(&folder.join("images.txt"))? {
+        // Images can be left out, e.g. with --limit
+        if !image_names.contains(&image.name) {
+            log::debug!(
+                "\tignoring image {} on line {} of images.txt, which was not loaded",
+                image.name,
+                image.line_number
+            );
+            continue;
+        }
+        let k_vals = cameras.get(&image.camera_id).with_context(|| {
+            format!(
+                "Unknown camera {} on line {} of images.txt",
+                image.camera_id, image.line_number
+            )
+        })?;
+
+        let k = cv::core::Mat::from_slice_rows_cols(k_vals, 3, 3)?;
+        let pose = projection_matrix(&k, &image.rotation, &image.translation)?;
+        let center = -(image.rotation.transpose() * image.translation);
+
+        log::debug!("\tExtracted values for {}:", image.name);
+        log::debug!("\t\tk: {:?}", k_vals);
+        log::debug!("\t\tr: {:?}, t: {:?}", image.rotation, image.translation);
+        poses_by_name.insert(image.name, (pose, center));
+    }
+
+    let mut poses = Vec::with_capacity(image_names.len());
+    let mut camera_positions = Vec::with_capacity(image_names.len());
+    for name in image_names {
+        let (pose, center) = poses_by_name
+            .remove(name)
+            .with_context(|| format!("No pose found in images.txt for {}", name))?;
+        poses.push(pose);
+        camera_positions.push(center);
+    }
+    log::info!("Extracted COLMAP pose data for images");
+
     Ok((poses, camera_positions))
 }
+
+/// Reads the rotation and translation of the images of a COLMAP text export that are in
+/// `image_names`, in the same order. The images that are not in the export are `None`.
+pub fn read_colmap_extrinsics(
+    folder: &Path,
+    image_names: &[String],
+) -> Result<Vec<Option<(Mat3, Vec3)>>> {
+    let mut extrinsics = vec![None; image_names.len()];
+    for image in parse_colmap_images(&folder.join("images.txt"))? {
+        if let Some(idx) = image_names.iter().position(|name| *name == image.name) {
+            extrinsics[idx] = Some((image.rotation, image.translation));
+        }
+    }
+
+    Ok(extrinsics)
+}
+
+/// Pose files can reference the images by path, the images are matched by their file name only.
+fn image_file_name(name: &str) -> String {
+    Path::new(name)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| name.to_owned())
+}
+
+/// Intrinsic matrix of a camera whose principal point is at the center of the image, as assumed by
+/// Bundler and VisualSFM.
+fn centered_intrinsics(focal: f32, image_size: &Vec2) -> Result<cv::core::Mat> {
+    let k_vals = [
+        focal,
+        0.0,
+        image_size.x / 2.0,
+        0.0,
+        focal,
+        image_size.y / 2.0,
+        0.0,
+        0.0,
+        1.0,
+    ];
+
+    Ok(cv::core::Mat::from_slice_rows_cols(&k_vals, 3, 3)?)
+}
+
+/// Reads the poses of a Bundler `bundle.out` file. The images' sizes are needed for their principal
+/// point, which Bundler puts at the center of the image.
+pub fn extract_bundler_pose(
+    bundle_path: &Path,
+    image_sizes: &HashMap<String, Vec2>,
+) -> Result<PosesByName> {
+    log::info!("Extracting Bundler pose data for images");
+    let list_path = bundle_path.with_file_name("list.txt");
+    let list = std::fs::read_to_string(&list_path)
+        .with_context(|| format!("Failed to read {}", list_path.to_string_lossy()))?;
+    let names = list
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .map(image_file_name)
+        .collect::<Vec<_>>();
+
+    let contents = std::fs::read_to_string(bundle_path)
+        .with_context(|| format!("Failed to read {}", bundle_path.to_string_lossy()))?;
+    let mut lines = contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'));
+    let mut next_values = |label: &str, count: usize| -> Result<Vec<f32>> {
+        let (line_idx, line) = lines.next().context("Unexpected end of the bundle file")?;
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        if tokens.len() != count {
+            bail!(
+                "Expected {} values ({}) on line {} of the bundle file, found {}",
+                count,
+                label,
+                line_idx + 1,
+                tokens.len()
+            );
+        }
+        parse_values(&tokens, label, line_idx + 1)
+    };
+
+    let camera_count = next_values("camera and point counts", 2)?[0] as usize;
+    if camera_count != names.len() {
+        bail!(
+            "The bundle file has {} cameras, but {} lists {} images",
+            camera_count,
+            list_path.to_string_lossy(),
+            names.len()
+        );
+    }
+
+    // Bundler's cameras look down -Z with +Y up, OpenCV's look down +Z with +Y down
+    let flip = Mat3::from_diagonal(Vec3::new(1.0, -1.0, -1.0));
+    let mut poses = HashMap::new();
+    let mut distorted_cameras = 0;
+    for name in names {
+        let camera = next_values("f k1 k2", 3)?;
+        let mut rotation_vals = vec![];
+        for _ in 0..3 {
+            rotation_vals.extend(next_values("R", 3)?);
+        }
+        let t_vals = next_values("t", 3)?;
+
+        if camera[0] == 0.0 {
+            log::debug!("\tignoring image {}, which Bundler did not register", name);
+            continue;
+        }
+        let Some(image_size) = image_sizes.get(&name) else {
+            log::debug!("\tignoring image {}, which was not loaded", name);
+            continue;
+        };
+        if camera[1] != 0.0 || camera[2] != 0.0 {
+            distorted_cameras += 1;
+        }
+
+        let rotation = flip * Mat3::from_cols_slice(&rotation_vals).transpose();
+        let translation = flip * Vec3::new(t_vals[0], t_vals[1], t_vals[2]);
+        let k = centered_intrinsics(camera[0], image_size)?;
+        let pose = projection_matrix(&k, &rotation, &translation)?;
+        let center = -(rotation.transpose() * translation);
+
+        log::debug!("\tExtracted values for {}:", name);
+        log::debug!("\t\tf: {}", camera[0]);
+        log::debug!("\t\tr: {:?}, t: {:?}", rotation, translation);
+        poses.insert(name, (pose, center));
+    }
+    if distorted_cameras > 0 {
+        log::warn!(
+            "{} cameras have radial distortion coefficients, they are ignored",
+            distorted_cameras
+        );
+    }
+    log::info!("Extracted Bundler pose data for images");
+
+    Ok(poses)
+}
+
+/// Reads the poses of the first model of a VisualSFM `.nvm` file. The principal point is the center
+/// of the image unless the file's header gives one (`NVM_V3 FixedK fx cx fy cy`).
+pub fn extract_nvm_pose(
+    nvm_path: &Path,
+    image_sizes: &HashMap<String, Vec2>,
+) -> Result<PosesByName> {
+    log::info!("Extracting NVM pose data for images");
+    let contents = std::fs::read_to_string(nvm_path)
+        .with_context(|| format!("Failed to read {}", nvm_path.to_string_lossy()))?;
+    let mut lines = contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'));
+
+    let header = lines
+        .next()
+        .map(|(_, line)| line.split_whitespace().collect::<Vec<_>>())
+        .unwrap_or_default();
+    if header.first() != Some(&"NVM_V3") {
+        bail!("{} is not an NVM_V3 file", nvm_path.to_string_lossy());
+    }
+    let fixed_k = match header.get(1) {
+        Some(&"FixedK") if header.len() >= 6 => {
+            let values = parse_values(&header[2..6], "FixedK", 1)?;
+            Some([
+                values[0], 0.0, values[1], 0.0, values[2], values[3], 0.0, 0.0, 1.0,
+            ])
+        }
+        _ => None,
+    };
+
+    let (line_idx, count_line) = lines.next().context("Missing camera count in NVM file")?;
+    let camera_count = count_line.trim().parse::<usize>().with_context(|| {
+        format!(
+            "Invalid camera count on line {} of the NVM file",
+            line_idx + 1
+        )
+    })?;
+
+    let mut poses = HashMap::new();
+    let mut distorted_cameras = 0;
+    for _ in 0..camera_count {
+        let (line_idx, line) = lines.next().context("Unexpected end of the NVM file")?;
+        let line_number = line_idx + 1;
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        if tokens.len() < 10 {
+            bail!("Incomplete camera on line {} of the NVM file", line_number);
+        }
+        let name = image_file_name(tokens[0]);
+        let values = parse_values(&tokens[1..10], "camera", line_number)?;
+
+        let Some(image_size) = image_sizes.get(&name) else {
+            log::debug!("\tignoring image {}, which was not loaded", name);
+            continue;
+        };
+        if values[8] != 0.0 {
+            distorted_cameras += 1;
+        }
+
+        // The file gives the camera's center rather than its translation
+        let rotation = Mat3::from_quat(
+            Quat::from_xyzw(values[2], values[3], values[4], values[1]).normalize(),
+        );
+        let center = Vec3::new(values[5], values[6], values[7]);
+        let translation = -(rotation * center);
+        let k = match &fixed_k {
+            Some(k_vals) => cv::core::Mat::from_slice_rows_cols(k_vals, 3, 3)?,
+            None => centered_intrinsics(values[0], image_size)?,
+        };
+        let pose = projection_matrix(&k, &rotation, &translation)?;
+
+        log::debug!("\tExtracted values for {}:", name);
+        log::debug!("\t\tf: {}", values[0]);
+        log::debug!("\t\tr: {:?}, t: {:?}", rotation, translation);
+        poses.insert(name, (pose, center));
+    }
+    if distorted_cameras > 0 {
+        log::warn!(
+            "{} cameras have a radial distortion coefficient, it is ignored",
+            distorted_cameras
+        );
+    }
+    log::info!("Extracted NVM pose data for images");
+
+    Ok(poses)
+}
+
+/// Computes the 3x4 projection matrix K * [R|t].
+pub fn projection_matrix(
+    k: &cv::core::Mat,
+    rotation: &Mat3,
+    translation: &Vec3,
+) -> Result<cv::core::Mat> {
+    let rt_vals = (0..3)
+        .flat_map(|row| rotation.row(row).extend(translation[row]).to_array())
+        .collect::<Vec<_>>();
+    let rt = cv::core::Mat::from_slice_rows_cols(&rt_vals, 3, 4)?;
+
+    Ok((k * rt).into_result()?.to_mat()?)
+}
+
+/// Splits a 3x4 projection matrix into its intrinsics K (normalized so that K[2][2] is 1), its
+/// rotation R and its translation t, such that P = K * [R|t] up to scale.
+pub fn decompose_projection(projection: &cv::core::Mat) -> Result<(Mat3, Mat3, Vec3)> {
+    let mut k = cv::core::Mat::default();
+    let mut r = cv::core::Mat::default();
+    let mut center = cv::core::Mat::default();
+    cv::calib3d::decompose_projection_matrix_def(projection, &mut k, &mut r, &mut center)?;
+
+    let k = Mat3::from_cols_slice(&mat_values(&k)?).transpose();
+    let r = Mat3::from_cols_slice(&mat_values(&r)?).transpose();
+    let center = mat_values(&center)?;
+    let center = Vec3::new(center[0], center[1], center[2]) / center[3];
+
+    Ok((k * (1.0 / k.z_axis.z), r, -(r * center)))
+}
+
+/// Intrinsics shared by all the cameras.
+pub struct Intrinsics {
+    pub k: cv::core::Mat,
+    /// The lens distortion coefficients of the model, if any. Fisheye cameras always have them.
+    pub distortion: Option<cv::core::Mat>,
+    pub model: CameraModel,
+}
+
+fn parse_intrinsics_values<'a>(tokens: impl Iterator<Item = &'a str>) -> Result<Vec<f32>> {
+    tokens
+        .map(|token| {
+            token
+                .parse::<f32>()
+                .with_context(|| format!("Invalid intrinsics value \"{}\"", token))
+        })
+        .collect()
+}
+
+/// Value of `--intrinsics` that estimates the intrinsics of each image from its EXIF data.
+pub const EXIF_INTRINSICS: &str = "exif";
+/// Horizontal field of view in degrees assumed for the images without an EXIF focal length.
+const DEFAULT_HORIZONTAL_FOV: f32 = 60.0;
+/// Diagonal of a 35mm film frame (36x24mm), in millimeters.
+const FILM_35MM_DIAGONAL: f32 = 43.27;
+
+/// Focal length of the image in pixels, from the 35mm equivalent focal length in its EXIF data. The
+/// equivalent focal length is relative to the frame's diagonal, so this doesn't depend on the aspect
+/// ratio or the orientation of the image.
+fn exif_focal_length(path: &Path, image_size: &Vec2) -> Option<f32> {
+    let file = File::open(path).ok()?;
+    let exif = exif::Reader::new()
+        .read_from_container(&mut BufReader::new(file))
+        .ok()?;
+    let field = exif.get_field(exif::Tag::FocalLengthIn35mmFilm, exif::In::PRIMARY)?;
+    let focal_35mm = field.value.get_uint(0).filter(|focal| *focal > 0)?;
+
+    Some(focal_35mm as f32 / FILM_35MM_DIAGONAL * image_size.length())
+}
+
+/// Estimates the intrinsics of each image from its EXIF data, with the principal point at the center
+/// of the image. The images without a focal length in their EXIF data are given a horizontal field
+/// of view of `DEFAULT_HORIZONTAL_FOV` degrees.
+pub fn exif_intrinsics(
+    image_paths: &[PathBuf],
+    image_sizes: &[Vec2],
+) -> Result<Vec<cv::core::Mat>> {
+    let mut intrinsics = Vec::with_capacity(image_sizes.len());
+    let mut missing_count = 0;
+    for (idx, image_size) in image_sizes.iter().enumerate() {
+        let focal = match image_paths
+            .get(idx)
+            .and_then(|path| exif_focal_length(path, image_size))
+        {
+            Some(focal) => {
+                log::debug!("\tEXIF focal length of image #{}: {:.1}px", idx + 1, focal);
+                focal
+            }
+            None => {
+                missing_count += 1;
+                image_size.x / 2.0 / (DEFAULT_HORIZONTAL_FOV.to_radians() / 2.0).tan()
+            }
+        };
+        intrinsics.push(centered_intrinsics(focal, image_size)?);
+    }
+
+    if missing_count > 0 {
+        log::warn!(
+            "{} of {} images have no EXIF focal length, assuming a horizontal field of view of {} degrees",
+            missing_count,
+            image_sizes.len(),
+            DEFAULT_HORIZONTAL_FOV
+        );
+    }
+    log::info!(
+        "Estimated the intrinsics of {} images from their EXIF data",
+        image_sizes.len() - missing_count
+    );
+
+    Ok(intrinsics)
+}
+
+/// Reads the intrinsics of the camera from a file containing either the 9 row-major values of K, or
+/// only `fx fy cx cy`. The distortion coefficients can be given on a separate line, as
+/// `distortion k1 k2 p1 p2 k3`.
+pub fn read_intrinsics(path: &Path, model: CameraModel) -> Result<Intrinsics> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read intrinsics file {}", path.to_string_lossy()))?;
+
+    let mut values = vec![];
+    let mut distortion = None;
+    for line in contents.lines() {
+        let mut tokens = line.split_whitespace().peekable();
+        if tokens.peek() == Some(&"distortion") {
+            tokens.next();
+            let coefficients = parse_intrinsics_values(tokens)?;
+            let (expected_count, names) = model.distortion_coefficients();
+            if coefficients.len() != expected_count {
+                bail!(
+                    "Expected {} distortion coefficients ({}) for the {:?} model in {}, found {}",
+                    expected_count,
+                    names,
+                    model,
+                    path.to_string_lossy(),
+                    coefficients.len()
+                );
+            }
+            distortion = Some(coefficients);
+        } else {
+            values.extend(parse_intrinsics_values(tokens)?);
+        }
+    }
+
+    let k_vals = match values.len() {
+        9 => values,
+        // fx fy cx cy
+        4 => vec![
+            values[0], 0.0, values[2], 0.0, values[1], values[3], 0.0, 0.0, 1.0,
+        ],
+        _ => bail!(
+            "Expected 9 values (K) or 4 values (fx fy cx cy) in {}, found {}",
+            path.to_string_lossy(),
+            values.len()
+        ),
+    };
+    if model == CameraModel::Fisheye && distortion.is_none() {
+        // Even without distortion, the fisheye projection differs from the pinhole one
+        distortion = Some(vec![0.0; 4]);
+    }
+    log::info!(
+        "Read {:?} intrinsics from {}: {:?} (distortion: {:?})",
+        model,
+        path.to_string_lossy(),
+        k_vals,
+        distortion
+    );
+
+    Ok(Intrinsics {
+        k: cv::core::Mat::from_slice_rows_cols(&k_vals, 3, 3)?,
+        distortion: distortion
+            .map(|coefficients| {
+                cv::core::Mat::from_slice_rows_cols(&coefficients, 1, coefficients.len())
+            })
+            .transpose()?,
+        model,
+    })
+}
+
+/// Swaps the intrinsic matrix of a projection matrix P = K * [R|t] for another one.
+pub fn replace_intrinsics(
+    pose: &cv::core::Mat,
+    k: &cv::core::Mat,
+    new_k: &cv::core::Mat,
+) -> Result<cv::core::Mat> {
+    let k = Mat3::from_cols_slice(&mat_values(k)?).transpose();
+    let new_k = Mat3::from_cols_slice(&mat_values(new_k)?).transpose();
+    let change = new_k * k.inverse();
+
+    let pose_vals = mat_values(pose)?;
+    let columns = (0..4)
+        .map(|col| change * Vec3::new(pose_vals[col], pose_vals[4 + col], pose_vals[8 + col]))
+        .collect::<Vec<_>>();
+    let new_pose_vals = (0..3)
+        .flat_map(|row| columns.iter().map(move |column| column[row]))
+        .collect::<Vec<_>>();
+
+    Ok(cv::core::Mat::from_slice_rows_cols(&new_pose_vals, 3, 4)?)
+}
+
+/// Updates a projection (or intrinsic) matrix for an image resized by `scale`, which is equivalent to
+/// scaling fx, fy, cx and cy in its intrinsic matrix.
+pub fn scale_intrinsics(pose: &mut cv::core::Mat, scale: f32) -> Result<()> {
+    for row in 0..2 {
+        for col in 0..pose.cols() {
+            *pose.at_2d_mut::<f32>(row, col)? *= scale;
+        }
+    }
+
+    Ok(())
+}
+
+/// Updates a projection (or intrinsic) matrix for an image of `size` rotated by `rotation`. The
+/// projection matrices are composed with the rotation of the pixel coordinates. The intrinsic
+/// matrices must stay upper triangular, so the camera is rotated along with the image instead: fx and
+/// fy are swapped for quarter turns, and the principal point is moved with the pixels.
+pub fn rotate_intrinsics(
+    calibration: &mut cv::core::Mat,
+    rotation: ImageRotation,
+    size: cv::core::Size,
+) -> Result<()> {
+    let transform = rotation.pixel_transform(size);
+    let values = mat_values(calibration)?;
+    let cols = calibration.cols() as usize;
+    let mut columns = (0..cols)
+        .map(|col| transform * Vec3::new(values[col], values[cols + col], values[2 * cols + col]))
+        .collect::<Vec<_>>();
+    if cols == 3 {
+        let camera_rotation = Mat3::from_cols(transform.x_axis, transform.y_axis, Vec3::Z);
+        let k = Mat3::from_cols(columns[0], columns[1], columns[2]) * camera_rotation.transpose();
+        columns = vec![k.x_axis, k.y_axis, k.z_axis];
+    }
+
+    let new_values = (0..3)
+        .flat_map(|row| columns.iter().map(move |column| column[row]))
+        .collect::<Vec<_>>();
+    *calibration = cv::core::Mat::from_slice_rows_cols(&new_values, 3, cols)?;
+
+    Ok(())
+}
+
+/// Orientation, intrinsics and projection of one of the dataset's cameras, used by the viewer.
+#[derive(Clone, Copy)]
+pub struct CameraView {
+    pub center: Vec3,
+    pub forward: Vec3,
+    pub up: Vec3,
+    pub horizontal_fov: f32,
+    pub image_size: Vec2,
+    /// The 3x4 projection matrix, padded with a (0, 0, 0, 1) row.
+    pub projection: Mat4,
+}
+
+impl CameraView {
+    pub fn is_in_frustum(&self, point: &Vec3) -> bool {
+        let projected = self.projection * point.extend(1.0);
+        if projected.z <= 0.0 {
+            return false;
+        }
+
+        let pixel = projected.truncate().truncate() / projected.z;
+        (0.0..self.image_size.x).contains(&pixel.x) && (0.0..self.image_size.y).contains(&pixel.y)
+    }
+}
+
+pub fn mat_values(mat: &cv::core::Mat) -> Result<Vec<f32>> {
+    let mut converted = cv::core::Mat::default();
+    mat.convert_to(&mut converted, cv::core::CV_32F, 1.0, 0.0)?;
+
+    Ok(converted.iter::<f32>()?.map(|(_, val)| val).collect())
+}
+
+pub fn camera_view(pose: &cv::core::Mat, image_size: cv::core::Size) -> Result<CameraView> {
+    let mut k = cv::core::Mat::default();
+    let mut r = cv::core::Mat::default();
+    let mut center = cv::core::Mat::default();
+    cv::calib3d::decompose_projection_matrix_def(pose, &mut k, &mut r, &mut center)?;
+
+    let k = mat_values(&k)?;
+    let r = mat_values(&r)?;
+    let center = mat_values(&center)?;
+
+    let mut projection = mat_values(pose)?;
+    projection.extend([0.0, 0.0, 0.0, 1.0]);
+
+    // OpenCV cameras look down their +Z axis, with +Y pointing down in the image
+    Ok(CameraView {
+        center: Vec3::new(center[0], center[1], center[2]) / center[3],
+        forward: Vec3::new(r[6], r[7], r[8]),
+        up: -Vec3::new(r[3], r[4], r[5]),
+        horizontal_fov: 2.0 * (image_size.width as f32 / (2.0 * k[0].abs())).atan(),
+        image_size: Vec2::new(image_size.width as f32, image_size.height as f32),
+        projection: Mat4::from_cols_slice(&projection).transpose(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOLERANCE: f32 = 1e-5;
+
+    /// Writes `contents` to a file of the temporary folder, named after the test.
+    fn write_temp_file(name: &str, contents: &str) -> Result<PathBuf> {
+        let path =
+            std::env::temp_dir().join(format!("sfm_pose_test_{}_{}", std::process::id(), name));
+        std::fs::write(&path, contents)?;
+
+        Ok(path)
+    }
+
+    fn read_pose_file(name: &str, contents: &str, comment_prefix: &str) -> Result<PosesByName> {
+        let path = write_temp_file(name, contents)?;
+        let poses = extract_pose(path.clone(), comment_prefix, PoseHeader::Auto, None);
+        std::fs::remove_file(&path)?;
+
+        poses
+    }
+
+    const K: &str = "500 0 320 0 500 240 0 0 1";
+    const R: &str = "1 0 0 0 1 0 0 0 1";
+
+    #[test]
+    fn skips_comments_and_blank_lines() -> Result<()> {
+        let contents = format!(
+            "# exported poses\n\n2\n  # indented comment\na.png {K} {R} 1 2 3\n\n\t\nb.png {K} {R} 4 5 6\n"
+        );
+        let poses = read_pose_file("comments.txt", &contents, "#")?;
+
+        assert_eq!(poses.len(), 2);
+        assert!(poses["a.png"].1.distance(Vec3::new(1.0, 2.0, 3.0)) < TOLERANCE);
+        assert!(poses["b.png"].1.distance(Vec3::new(4.0, 5.0, 6.0)) < TOLERANCE);
+        Ok(())
+    }
+
+    #[test]
+    fn ignores_trailing_whitespace() -> Result<()> {
+        let contents = format!("a.png {K} {R} 1 2 3   \r\nb.png {K} {R} 4 5 6\t\n");
+        let poses = read_pose_file("whitespace.txt", &contents, "#")?;
+
+        assert_eq!(poses.len(), 2);
+        assert!(poses["b.png"].1.distance(Vec3::new(4.0, 5.0, 6.0)) < TOLERANCE);
+
+        // The projection matrix is K [R | t]
+        let expected = [
+            500.0, 0.0, 320.0, 1460.0, 0.0, 500.0, 240.0, 1720.0, 0.0, 0.0, 1.0, 3.0,
+        ];
+        let pose = mat_values(&poses["a.png"].0)?;
+        for (value, expected) in pose.iter().zip(expected) {
+            assert!((value - expected).abs() < TOLERANCE, "{:?}", pose);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn empty_comment_prefix_keeps_every_line() -> Result<()> {
+        let contents = format!("a.png {K} {R} 1 2 3\nb.png {K} {R} 4 5 6\n");
+        let poses = read_pose_file("no_comments.txt", &contents, "")?;
+
+        assert_eq!(poses.len(), 2);
+        Ok(())
+    }
+
+    /// Checks that `pose` is the projection matrix K [R | t].
+    fn assert_projection(pose: &cv::core::Mat, k: Mat3, rotation: Mat3, translation: Vec3) {
+        let kr = k * rotation;
+        let kt = k * translation;
+        let expected = (0..3).flat_map(|row| kr.row(row).extend(kt[row]).to_array());
+        let values = mat_values(pose).unwrap();
+        assert_eq!(values.len(), 12);
+        for (value, expected) in values.iter().zip(expected) {
+            assert!(
+                (value - expected).abs() < 1e-4 * expected.abs().max(1.0),
+                "{:?}",
+                values
+            );
+        }
+    }
+
+    fn image_sizes(names: &[&str]) -> HashMap<String, Vec2> {
+        names
+            .iter()
+            .map(|name| (name.to_string(), Vec2::new(640.0, 480.0)))
+            .collect()
+    }
+
+    #[test]
+    fn reads_bundler_cameras() -> Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("sfm_pose_test_{}_bundler", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(
+            dir.join("list.txt"),
+            "images/a.jpg 0 500\nimages/b.jpg\nimages/c.jpg 0 400\n",
+        )?;
+        let bundle_path = dir.join("bundle.out");
+        // The second camera was not registered, so its focal length is 0
+        std::fs::write(
+            &bundle_path,
+            "# Bundle file v0.3\n3 0\n\
+             500 0 0\n1 0 0\n0 1 0\n0 0 1\n0 0 -2\n\
+             0 0 0\n0 0 0\n0 0 0\n0 0 0\n0 0 0\n\
+             400 0.1 0\n0 -1 0\n1 0 0\n0 0 1\n1 2 3\n",
+        )?;
+        let poses = extract_bundler_pose(&bundle_path, &image_sizes(&["a.jpg", "b.jpg", "c.jpg"]));
+        std::fs::remove_dir_all(&dir)?;
+        let poses = poses?;
+
+        assert_eq!(poses.len(), 2);
+        assert!(!poses.contains_key("b.jpg"));
+
+        // Bundler's cameras look down -Z, the poses are converted to OpenCV's convention
+        let flip = Mat3::from_diagonal(Vec3::new(1.0, -1.0, -1.0));
+        let (pose, center) = &poses["a.jpg"];
+        let k = Mat3::from_cols_array(&[500.0, 0.0, 0.0, 0.0, 500.0, 0.0, 320.0, 240.0, 1.0]);
+        assert_projection(pose, k, flip, Vec3::new(0.0, 0.0, 2.0));
+        assert!(center.distance(Vec3::new(0.0, 0.0, 2.0)) < TOLERANCE);
+
+        let (pose, center) = &poses["c.jpg"];
+        let k = Mat3::from_cols_array(&[400.0, 0.0, 0.0, 0.0, 400.0, 0.0, 320.0, 240.0, 1.0]);
+        let rotation =
+            flip * Mat3::from_cols_array(&[0.0, 1.0, 0.0, -1.0, 0.0, 0.0, 0.0, 0.0, 1.0]);
+        let translation = flip * Vec3::new(1.0, 2.0, 3.0);
+        assert_projection(pose, k, rotation, translation);
+        assert!(center.distance(-(rotation.transpose() * translation)) < TOLERANCE);
+        Ok(())
+    }
+
+    #[test]
+    fn reads_nvm_cameras() -> Result<()> {
+        // The cameras are given as: name, focal length, rotation quaternion (wxyz), center, radial
+        // distortion and 0. The last camera's image is not loaded.
+        let contents = "NVM_V3\n\n3\n\
+                        a.jpg 500 1 0 0 0 0 0 5 0 0\n\
+                        images/b.jpg 400 0.70710678 0 0 0.70710678 1 2 3 0.01 0\n\
+                        c.jpg 500 1 0 0 0 0 0 0 0 0\n\n0\n";
+        let path = write_temp_file("cameras.nvm", contents)?;
+        let poses = extract_nvm_pose(&path, &image_sizes(&["a.jpg", "b.jpg"]));
+        std::fs::remove_file(&path)?;
+        let poses = poses?;
+
+        assert_eq!(poses.len(), 2);
+
+        let (pose, center) = &poses["a.jpg"];
+        let k = Mat3::from_cols_array(&[500.0, 0.0, 0.0, 0.0, 500.0, 0.0, 320.0, 240.0, 1.0]);
+        assert_projection(pose, k, Mat3::IDENTITY, Vec3::new(0.0, 0.0, -5.0));
+        assert!(center.distance(Vec3::new(0.0, 0.0, 5.0)) < TOLERANCE);
+
+        // A quarter turn around Z, the translation being derived from the center
+        let (pose, center) = &poses["b.jpg"];
+        let k = Mat3::from_cols_array(&[400.0, 0.0, 0.0, 0.0, 400.0, 0.0, 320.0, 240.0, 1.0]);
+        let rotation = Mat3::from_rotation_z(std::f32::consts::FRAC_PI_2);
+        let expected_center = Vec3::new(1.0, 2.0, 3.0);
+        assert_projection(pose, k, rotation, -(rotation * expected_center));
+        assert!(center.distance(expected_center) < TOLERANCE);
+        Ok(())
+    }
+
+    #[test]
+    fn reads_colmap_images_without_observations() -> Result<()> {
+        // The second image has no 2D points, so its second line is empty
+        let contents = "# Image list with two lines of data per image:\n\
+                        #   IMAGE_ID, QW, QX, QY, QZ, TX, TY, TZ, CAMERA_ID, NAME\n\
+                        #   POINTS2D[] as (X, Y, POINT3D_ID)\n\
+                        1 1 0 0 0 1 2 3 1 a.jpg\n\
+                        10.5 20.5 -1 30.5 40.5 7\n\
+                        2 1 0 0 0 4 5 6 1 b.jpg\n\
+                        \n\
+                        3 1 0 0 0 7 8 9 2 images/c d.jpg\n\
+                        1.0 2.0 -1\n";
+        let path = write_temp_file("images.txt", contents)?;
+        let images = parse_colmap_images(&path);
+        std::fs::remove_file(&path)?;
+        let images = images?;
+
+        let names = images
+            .iter()
+            .map(|image| image.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a.jpg", "b.jpg", "c d.jpg"]);
+        assert_eq!(images[2].camera_id, 2);
+        assert_eq!(images[2].line_number, 8);
+        assert!(images[1]
+            .translation
+            .abs_diff_eq(Vec3::new(4.0, 5.0, 6.0), TOLERANCE));
+        Ok(())
+    }
+}
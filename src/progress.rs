@@ -0,0 +1,30 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+static PROGRESS_BARS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_enabled(enabled: bool) {
+    PROGRESS_BARS.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    PROGRESS_BARS.load(Ordering::Relaxed)
+}
+
+/// A progress bar showing the elapsed time and ETA, or a hidden one when progress bars are disabled.
+pub fn bar(len: usize, message: &'static str) -> ProgressBar {
+    if !enabled() {
+        return ProgressBar::hidden();
+    }
+
+    let bar = ProgressBar::new(len as u64).with_message(message);
+    bar.set_style(
+        ProgressStyle::with_template(
+            "{msg:>20} [{elapsed_precise}] {wide_bar} {pos}/{len} (ETA {eta})",
+        )
+        .expect("Invalid progress bar template"),
+    );
+
+    bar
+}
@@ -1,6 +1,10 @@
-use std::path::Path;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
+    path::{Path, PathBuf},
+};
 
-use morrigu::bevy_ecs::prelude::Entity;
+use morrigu::bevy_ecs::prelude::{Entity, World};
 use morrigu::{
     allocated_types::AllocatedBuffer,
     application::{ApplicationState, BuildableApplicationState},
@@ -11,53 +15,361 @@ use morrigu::{
         transform::Transform,
     },
     descriptor_resources::DescriptorResources,
-    math_types::{Quat, Vec2, Vec3},
+    math_types::{Mat3, Quat, Vec2, Vec3, Vec4},
     shader::Shader,
     systems::mesh_renderer,
     utils::ThreadSafeRef,
     vertices::simple::SimpleVertex,
+    winit::keyboard::KeyCode,
 };
 use morrigu::{egui, winit};
 
-use crate::camera::ViewerCamera;
+use crate::{
+    bookmarks::{load_settings, save_settings, Bookmark, ViewerSettings},
+    camera::{Binding, CameraMode, CameraState, ViewerCamera, MAX_MOVE_SPEED, MIN_MOVE_SPEED},
+    controls::Controls,
+    export::{write_ply, PlyFormat},
+    pose::CameraView,
+};
 
 type Vertex = SimpleVertex;
 type Material = morrigu::material::Material<Vertex>;
 type Mesh = morrigu::mesh::Mesh<Vertex>;
 type MeshRendering = morrigu::components::mesh_rendering::MeshRendering<Vertex>;
 
+/// Bounds of the field of view slider, in degrees.
+const MIN_FOV: f32 = 10.0;
+const MAX_FOV: f32 = 150.0;
+/// Scale applied to the reconstruction's coordinates when displaying it.
+const SCENE_SCALE: f32 = 2.0;
+/// Radius of the points' spheres, in scene units.
+const DEFAULT_POINT_SIZE: f32 = 0.005;
+/// Depth of the camera gizmos' pyramids, in scene units.
+const CAMERA_GIZMO_SIZE: f32 = 0.1;
+const CAMERA_GIZMO_COLOR: Vec3 = Vec3::new(1.0, 0.6, 0.1);
+/// Width of the segments of the camera trajectory, in scene units.
+const TRAJECTORY_WIDTH: f32 = 0.005;
+const TRAJECTORY_COLOR: Vec3 = Vec3::new(1.0, 0.9, 0.1);
+/// Color of the segment between the two points picked to measure their distance.
+const MEASURE_COLOR: Vec3 = Vec3::new(0.1, 0.8, 1.0);
+/// Maximum distance between the cursor and a point's projection for the point to be picked, in pixels.
+const PICK_RADIUS: f32 = 10.0;
+const DARK_BACKGROUND: [f32; 3] = [0.05, 0.05, 0.05];
+const LIGHT_BACKGROUND: [f32; 3] = [0.95, 0.95, 0.95];
+const DEFAULT_GAMMA: f32 = 1.0;
+const DEFAULT_EXPOSURE: f32 = 1.0;
+/// Number of frames the frame time is averaged over.
+const FRAME_TIME_SAMPLES: usize = 60;
+/// The most transparent the points get with the error transparency, so that they stay visible.
+const MIN_POINT_ALPHA: f32 = 0.1;
+
+#[derive(Clone, Default)]
 pub struct Point {
     pub position: Vec3,
     pub color: Vec3,
+
+    // Optional diagnostic data, exported as PLY scalar fields when present
+    pub confidence: Option<f32>,
+    pub reproj_error: Option<f32>,
+    pub num_views: Option<u32>,
+    /// Unit normal, estimated from the point's neighborhood.
+    pub normal: Option<Vec3>,
+    /// The indices of the pair of images the point was triangulated from.
+    pub source_pair: Option<(usize, usize)>,
+}
+
+/// Extent of a point cloud.
+#[derive(Clone, Copy, Debug)]
+pub struct CloudStats {
+    pub point_count: usize,
+    /// Corners of the axis-aligned bounding box.
+    pub min: Vec3,
+    pub max: Vec3,
+    pub centroid: Vec3,
+}
+
+impl CloudStats {
+    /// The statistics of the points, or `None` if there are none.
+    pub fn of<'a>(points: impl IntoIterator<Item = &'a Point>) -> Option<Self> {
+        let mut points = points.into_iter();
+        let first = points.next()?.position;
+        let mut stats = Self {
+            point_count: 1,
+            min: first,
+            max: first,
+            centroid: first,
+        };
+        for point in points {
+            stats.point_count += 1;
+            stats.min = stats.min.min(point.position);
+            stats.max = stats.max.max(point.position);
+            stats.centroid += point.position;
+        }
+        stats.centroid /= stats.point_count as f32;
+
+        Some(stats)
+    }
+}
+
+impl std::fmt::Display for CloudStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let size = self.max - self.min;
+        write!(
+            f,
+            "{} points, bounding box ({:.4}, {:.4}, {:.4}) to ({:.4}, {:.4}, {:.4}) (size {:.4} x {:.4} x {:.4}), centroid ({:.4}, {:.4}, {:.4})",
+            self.point_count,
+            self.min.x,
+            self.min.y,
+            self.min.z,
+            self.max.x,
+            self.max.y,
+            self.max.z,
+            size.x,
+            size.y,
+            size.z,
+            self.centroid.x,
+            self.centroid.y,
+            self.centroid.z
+        )
+    }
 }
 
 pub struct PointCloudData {
     pub points: Vec<Point>,
-    // Maybe add reference images later ?
-    pub camera_positions: Vec<Vec3>,
+    pub cameras: Vec<CameraView>,
+    /// Where the edited point cloud is saved from the viewer.
+    pub export_path: PathBuf,
+    pub export_format: PlyFormat,
+    /// Where the camera bookmarks (and the background color) are loaded from and saved to, if
+    /// anywhere.
+    pub bookmarks_path: Option<PathBuf>,
+    /// The initial horizontal field of view of the viewer's camera, in degrees.
+    pub horizontal_fov: f32,
+    /// The clipping planes of the viewer's camera (and of the solo view), in scene units.
+    pub near_plane: f32,
+    pub far_plane: f32,
+    /// The keys of the camera's keyboard controls.
+    pub controls: Controls,
+    /// Initial mouse axis inversions of the camera, see `ViewerCamera::invert_x`.
+    pub invert_x: bool,
+    pub invert_y: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ColorMode {
+    /// The colors sampled from the images.
+    Rgb,
+    /// A color ramp on the distance to the center of the cloud.
+    Depth,
+    /// A distinct hue for each pair of images the points were triangulated from.
+    SourcePair,
+}
+
+impl ColorMode {
+    fn label(self) -> &'static str {
+        match self {
+            ColorMode::Rgb => "RGB",
+            ColorMode::Depth => "Depth",
+            ColorMode::SourcePair => "Source pair",
+        }
+    }
+}
+
+/// What the slice's planes are perpendicular to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ClipAxis {
+    X,
+    Y,
+    Z,
+    /// The viewing direction, the slice following the viewer's camera.
+    CameraDepth,
+}
+
+impl ClipAxis {
+    fn label(self) -> &'static str {
+        match self {
+            ClipAxis::X => "X",
+            ClipAxis::Y => "Y",
+            ClipAxis::Z => "Z",
+            ClipAxis::CameraDepth => "Camera depth",
+        }
+    }
+}
+
+/// Samples of the viridis color map, evenly spaced between 0 and 1.
+const VIRIDIS: [Vec3; 5] = [
+    Vec3::new(0.267, 0.005, 0.329),
+    Vec3::new(0.229, 0.322, 0.546),
+    Vec3::new(0.128, 0.567, 0.551),
+    Vec3::new(0.369, 0.789, 0.383),
+    Vec3::new(0.993, 0.906, 0.144),
+];
+
+fn viridis(t: f32) -> Vec3 {
+    let scaled = t.clamp(0.0, 1.0) * (VIRIDIS.len() - 1) as f32;
+    let idx = (scaled.floor() as usize).min(VIRIDIS.len() - 2);
+    VIRIDIS[idx].lerp(VIRIDIS[idx + 1], scaled - idx as f32)
+}
+
+/// Color of the points triangulated from a pair of images. The hues of successive pairs are spread by
+/// the golden ratio so that neighboring pairs are easy to tell apart. Points without a source pair
+/// are gray.
+fn source_pair_color(source_pair: Option<(usize, usize)>) -> Vec3 {
+    let Some((left, right)) = source_pair else {
+        return Vec3::splat(0.5);
+    };
+
+    let hue = ((left * 7919 + right) as f32 * 0.618_034).fract() * 6.0;
+    let x = 1.0 - (hue % 2.0 - 1.0).abs();
+    match hue as u32 {
+        0 => Vec3::new(1.0, x, 0.0),
+        1 => Vec3::new(x, 1.0, 0.0),
+        2 => Vec3::new(0.0, 1.0, x),
+        3 => Vec3::new(0.0, x, 1.0),
+        4 => Vec3::new(x, 0.0, 1.0),
+        _ => Vec3::new(1.0, 0.0, x),
+    }
+}
+
+/// The controls handled by the viewer itself, listed in the help after the camera's.
+const VIEWER_BINDINGS: [Binding; 5] = [
+    Binding {
+        input: Cow::Borrowed("[ / ] or Page Up / Page Down"),
+        action: "Snap to the previous / next camera of the dataset",
+    },
+    Binding {
+        input: Cow::Borrowed("Left click"),
+        action: "Pick a point, then a second one to measure their distance (with \"Pick points\")",
+    },
+    Binding {
+        input: Cow::Borrowed("Left drag"),
+        action: "Delete points (with the deletion brush)",
+    },
+    Binding {
+        input: Cow::Borrowed("Ctrl + Z"),
+        action: "Undo the last brush stroke",
+    },
+    Binding {
+        input: Cow::Borrowed("H"),
+        action: "Show or hide this help",
+    },
+];
+
+/// Camera changes requested from the UI, applied on the next update.
+enum CameraAction {
+    Reset,
+    FitToCloud,
+    Restore(CameraState),
+}
+
+/// State saved when looking through one of the dataset's cameras, to restore the orbit camera after.
+struct SoloView {
+    camera_idx: usize,
+    hide_outside_frustum: bool,
+    orbit_camera: Camera,
+    orbit_distance: f32,
 }
 
 pub struct RenderState {
     camera: ViewerCamera,
-    camera_positions: Vec<Vec3>,
+    cameras: Vec<CameraView>,
     selected_camera: usize,
+    /// Horizontal field of view of the orbit and free cameras, in degrees.
+    fov: f32,
+    applied_fov: f32,
+    near_plane: f32,
+    far_plane: f32,
+
+    solo_view_enabled: bool,
+    hide_outside_frustum: bool,
+    solo_view: Option<SoloView>,
+    pending_camera_action: Option<CameraAction>,
+
+    show_cameras: bool,
+    camera_gizmos: Vec<Entity>,
+    hidden_camera_gizmos: Vec<(Entity, ThreadSafeRef<MeshRendering>)>,
+    show_trajectory: bool,
+    /// The segments of the polyline through the camera positions, in capture order.
+    trajectory_segments: Vec<Entity>,
+    hidden_trajectory_segments: Vec<(Entity, ThreadSafeRef<MeshRendering>)>,
 
+    cloud: Vec<Point>,
+    /// The statistics of the points that were not deleted, and how many were deleted when they were
+    /// computed.
+    cloud_stats: Option<CloudStats>,
+    cloud_stats_deleted_count: usize,
     points: Vec<Entity>,
+    /// The color buffer of each point, in the same order as `points`.
+    color_buffers: Vec<ThreadSafeRef<AllocatedBuffer>>,
+    color_mode: ColorMode,
+    applied_color_mode: ColorMode,
+    /// How quickly the points fade out with their reprojection error, 0 keeps them all opaque.
+    error_transparency: f32,
+    applied_error_transparency: f32,
+    point_size: f32,
+    /// The size the points' transforms currently use, updated to `point_size` in `on_update`.
+    applied_point_size: f32,
+    /// Display adjustments of the colors, applied by the fragment shader.
+    gamma: f32,
+    exposure: f32,
+    /// The gamma and exposure of the material's display buffer, updated in `on_update`.
+    applied_display_settings: Vec2,
+    display_buffer: ThreadSafeRef<AllocatedBuffer>,
+    /// Only draw what lies between two planes, `clip_min` and `clip_max` along `clip_axis` in the
+    /// reconstruction's units. The fragment shader discards the rest.
+    clip_enabled: bool,
+    clip_axis: ClipAxis,
+    clip_min: f32,
+    clip_max: f32,
+    /// The origin, direction and range of the material's clip buffer, updated in `on_update`.
+    applied_clip_data: [Vec4; 3],
+    clip_buffer: ThreadSafeRef<AllocatedBuffer>,
+    hidden_points: HashMap<Entity, ThreadSafeRef<MeshRendering>>,
+
+    brush_enabled: bool,
+    brush_radius: f32,
+    /// Each brush stroke's deleted points, by index in `points`, to be able to undo them.
+    deleted_strokes: Vec<Vec<(usize, ThreadSafeRef<MeshRendering>)>>,
+    pending_undo: bool,
+    picking_enabled: bool,
+    /// Indices in `points` of the points picked by the user, two at most. Picking a third point
+    /// starts a new measurement.
+    picked_points: Vec<usize>,
+    /// The segment between the two picked points, hidden unless two points are picked.
+    measure_segment: Entity,
+    hidden_measure_segment: Vec<(Entity, ThreadSafeRef<MeshRendering>)>,
+    pointer_over_ui: bool,
+    keyboard_over_ui: bool,
+    /// The durations of the last `FRAME_TIME_SAMPLES` frames.
+    frame_times: VecDeque<std::time::Duration>,
+    show_help: bool,
+    export_path: PathBuf,
+    export_format: PlyFormat,
+
+    bookmarks: Vec<Bookmark>,
+    bookmarks_path: Option<PathBuf>,
+    bookmark_name: String,
+    background_color: [f32; 3],
     material_ref: ThreadSafeRef<Material>,
     mesh_ref: ThreadSafeRef<Mesh>,
+    camera_mesh_ref: ThreadSafeRef<Mesh>,
+    segment_mesh_ref: ThreadSafeRef<Mesh>,
 }
 
 impl BuildableApplicationState<PointCloudData> for RenderState {
     fn build(context: &mut morrigu::application::StateContext, data: PointCloudData) -> Self {
+        let horizontal_fov = f32::to_radians(data.horizontal_fov);
         let camera = Camera::builder().build(
             morrigu::components::camera::Projection::Perspective(PerspectiveData {
-                horizontal_fov: f32::to_radians(64.5),
-                near_plane: 0.001,
-                far_plane: 1000.0,
+                horizontal_fov,
+                near_plane: data.near_plane,
+                far_plane: data.far_plane,
             }),
             &Vec2::new(1280.0, 720.0),
         );
-        let mut camera = ViewerCamera::new(camera);
+        let mut camera = ViewerCamera::new(camera, horizontal_fov, data.controls);
+        camera.invert_x = data.invert_x;
+        camera.invert_y = data.invert_y;
         camera.set_focal_point(&Vec3::new(0.0, 0.0, 0.0));
 
         let shader_ref = Shader::from_path(
@@ -66,10 +378,25 @@ impl BuildableApplicationState<PointCloudData> for RenderState {
             &context.renderer.device,
         )
         .expect("Failed to create shader");
+        let display_settings = Vec2::new(DEFAULT_GAMMA, DEFAULT_EXPOSURE);
+        let display_buffer = ThreadSafeRef::new(
+            AllocatedBuffer::builder(std::mem::size_of::<Vec2>() as u64)
+                .build_with_data(display_settings, context.renderer)
+                .expect("Failed to build display settings buffer"),
+        );
+        let clip_data = [Vec4::ZERO; 3];
+        let clip_buffer = ThreadSafeRef::new(
+            AllocatedBuffer::builder(std::mem::size_of::<[Vec4; 3]>() as u64)
+                .build_with_data(clip_data, context.renderer)
+                .expect("Failed to build clip buffer"),
+        );
         let material_ref = Material::builder()
             .build::<Vertex>(
                 &shader_ref,
-                DescriptorResources::default(),
+                DescriptorResources {
+                    uniform_buffers: [(0, display_buffer.clone()), (1, clip_buffer.clone())].into(),
+                    ..Default::default()
+                },
                 context.renderer,
             )
             .expect("Failed to build material");
@@ -79,13 +406,15 @@ impl BuildableApplicationState<PointCloudData> for RenderState {
                 .expect("Failed to load sphere model");
 
         let mut points = vec![];
+        let mut color_buffers = vec![];
 
-        for point in data.points {
+        for point in &data.points {
             let color_buffer = ThreadSafeRef::new(
-                AllocatedBuffer::builder(std::mem::size_of::<Vec3>() as u64)
-                    .build_with_data(point.color, context.renderer)
+                AllocatedBuffer::builder(std::mem::size_of::<Vec4>() as u64)
+                    .build_with_data(point.color.extend(1.0), context.renderer)
                     .expect("Failed to build color buffer"),
             );
+            color_buffers.push(color_buffer.clone());
             let sphere_rendering_ref = MeshRendering::new(
                 &mesh_ref,
                 &material_ref,
@@ -102,9 +431,12 @@ impl BuildableApplicationState<PointCloudData> for RenderState {
             .expect("Failed to create mesh rendering");
 
             // let position = point.position * 10.0;
-            let position = point.position * 2.0;
-            let transform =
-                Transform::from_trs(&position, &Quat::default(), &Vec3::new(0.005, 0.005, 0.005));
+            let position = point.position * SCENE_SCALE;
+            let transform = Transform::from_trs(
+                &position,
+                &Quat::default(),
+                &Vec3::splat(DEFAULT_POINT_SIZE),
+            );
 
             let id = context
                 .ecs_manager
@@ -115,15 +447,767 @@ impl BuildableApplicationState<PointCloudData> for RenderState {
             points.push(id);
         }
 
+        let camera_mesh_ref =
+            Vertex::load_model_from_path_obj(Path::new("assets/camera.obj"), context.renderer)
+                .expect("Failed to load camera model");
+        let mut camera_gizmos = vec![];
+        for view in &data.cameras {
+            let color_buffer = ThreadSafeRef::new(
+                AllocatedBuffer::builder(std::mem::size_of::<Vec4>() as u64)
+                    .build_with_data(CAMERA_GIZMO_COLOR.extend(1.0), context.renderer)
+                    .expect("Failed to build color buffer"),
+            );
+            let gizmo_rendering_ref = MeshRendering::new(
+                &camera_mesh_ref,
+                &material_ref,
+                DescriptorResources {
+                    uniform_buffers: [
+                        default_ubo_bindings(context.renderer).unwrap(),
+                        (1, color_buffer),
+                    ]
+                    .into(),
+                    ..Default::default()
+                },
+                context.renderer,
+            )
+            .expect("Failed to create mesh rendering");
+
+            // The pyramid's base spans the camera's field of view, with +Y pointing down like in
+            // OpenCV's camera frame
+            let half_width = (view.horizontal_fov / 2.0).tan();
+            let half_height = half_width * view.image_size.y / view.image_size.x;
+            let rotation = Quat::from_mat3(&Mat3::from_cols(
+                (-view.up).cross(view.forward),
+                -view.up,
+                view.forward,
+            ));
+            let transform = Transform::from_trs(
+                &(view.center * SCENE_SCALE),
+                &rotation,
+                &(Vec3::new(half_width, half_height, 1.0) * CAMERA_GIZMO_SIZE),
+            );
+
+            let id = context
+                .ecs_manager
+                .world
+                .spawn((transform, gizmo_rendering_ref))
+                .id();
+
+            camera_gizmos.push(id);
+        }
+
+        // The trajectory is drawn as thin prisms stretched between consecutive camera positions,
+        // as the material only renders triangles
+        let segment_mesh_ref =
+            Vertex::load_model_from_path_obj(Path::new("assets/segment.obj"), context.renderer)
+                .expect("Failed to load segment model");
+        let mut segment_rendering = |color: Vec3| {
+            let color_buffer = ThreadSafeRef::new(
+                AllocatedBuffer::builder(std::mem::size_of::<Vec4>() as u64)
+                    .build_with_data(color.extend(1.0), context.renderer)
+                    .expect("Failed to build color buffer"),
+            );
+            MeshRendering::new(
+                &segment_mesh_ref,
+                &material_ref,
+                DescriptorResources {
+                    uniform_buffers: [
+                        default_ubo_bindings(context.renderer).unwrap(),
+                        (1, color_buffer),
+                    ]
+                    .into(),
+                    ..Default::default()
+                },
+                context.renderer,
+            )
+            .expect("Failed to create mesh rendering")
+        };
+        let mut trajectory_segments = vec![];
+        for (start, end) in data.cameras.iter().zip(data.cameras.iter().skip(1)) {
+            let Some(transform) =
+                segment_transform(start.center * SCENE_SCALE, end.center * SCENE_SCALE)
+            else {
+                continue;
+            };
+
+            let id = context
+                .ecs_manager
+                .world
+                .spawn((transform, segment_rendering(TRAJECTORY_COLOR)))
+                .id();
+
+            trajectory_segments.push(id);
+        }
+
+        // Its transform is set when two points are picked
+        let measure_segment = context
+            .ecs_manager
+            .world
+            .spawn(Transform::from_trs(
+                &Vec3::ZERO,
+                &Quat::default(),
+                &Vec3::ONE,
+            ))
+            .id();
+        let hidden_measure_segment = vec![(measure_segment, segment_rendering(MEASURE_COLOR))];
+
+        let settings = match &data.bookmarks_path {
+            Some(path) => load_settings(path).unwrap_or_else(|error| {
+                log::error!("Failed to load camera bookmarks: {:#}", error);
+                ViewerSettings::default()
+            }),
+            None => ViewerSettings::default(),
+        };
+
         Self {
             camera,
-            camera_positions: data.camera_positions,
+            cameras: data.cameras,
             selected_camera: 0,
+            fov: data.horizontal_fov,
+            applied_fov: data.horizontal_fov,
+            near_plane: data.near_plane,
+            far_plane: data.far_plane,
+            solo_view_enabled: false,
+            hide_outside_frustum: false,
+            solo_view: None,
+            pending_camera_action: None,
+            show_cameras: true,
+            camera_gizmos,
+            hidden_camera_gizmos: vec![],
+            show_trajectory: false,
+            trajectory_segments,
+            hidden_trajectory_segments: vec![],
+            cloud_stats: CloudStats::of(&data.points),
+            cloud_stats_deleted_count: 0,
+            cloud: data.points,
             points,
+            color_buffers,
+            color_mode: ColorMode::Rgb,
+            applied_color_mode: ColorMode::Rgb,
+            error_transparency: 0.0,
+            applied_error_transparency: 0.0,
+            point_size: DEFAULT_POINT_SIZE,
+            applied_point_size: DEFAULT_POINT_SIZE,
+            gamma: DEFAULT_GAMMA,
+            exposure: DEFAULT_EXPOSURE,
+            applied_display_settings: display_settings,
+            display_buffer,
+            clip_enabled: false,
+            clip_axis: ClipAxis::Z,
+            clip_min: 0.0,
+            clip_max: 0.0,
+            applied_clip_data: clip_data,
+            clip_buffer,
+            hidden_points: HashMap::new(),
+            brush_enabled: false,
+            brush_radius: 20.0,
+            deleted_strokes: vec![],
+            pending_undo: false,
+            picking_enabled: false,
+            picked_points: vec![],
+            measure_segment,
+            hidden_measure_segment,
+            pointer_over_ui: false,
+            keyboard_over_ui: false,
+            frame_times: VecDeque::with_capacity(FRAME_TIME_SAMPLES),
+            show_help: false,
+            export_path: data.export_path,
+            export_format: data.export_format,
+            bookmarks: settings.bookmarks,
+            bookmarks_path: data.bookmarks_path,
+            bookmark_name: String::new(),
+            background_color: settings.background_color.unwrap_or(DARK_BACKGROUND),
             material_ref,
             mesh_ref,
+            camera_mesh_ref,
+            segment_mesh_ref,
+        }
+    }
+}
+
+/// The transform stretching the segment model, a unit prism along +Z, from `start` to `end`. Returns
+/// `None` if they are the same.
+fn segment_transform(start: Vec3, end: Vec3) -> Option<Transform> {
+    let direction = (end - start).try_normalize()?;
+
+    Some(Transform::from_trs(
+        &start,
+        &Quat::from_rotation_arc(Vec3::Z, direction),
+        &Vec3::new(TRAJECTORY_WIDTH, TRAJECTORY_WIDTH, start.distance(end)),
+    ))
+}
+
+/// Inserts back the hidden renderings of the entities, or takes them out to hide the entities.
+fn set_visible(
+    world: &mut World,
+    entities: &[Entity],
+    hidden: &mut Vec<(Entity, ThreadSafeRef<MeshRendering>)>,
+    visible: bool,
+) {
+    if visible {
+        for (entity, rendering) in hidden.drain(..) {
+            world.entity_mut(entity).insert(rendering);
+        }
+    } else if hidden.is_empty() {
+        for entity in entities {
+            if let Some(rendering) = world
+                .entity_mut(*entity)
+                .take::<ThreadSafeRef<MeshRendering>>()
+            {
+                hidden.push((*entity, rendering));
+            }
+        }
+    }
+}
+
+impl RenderState {
+    fn hide_points_outside(&mut self, world: &mut World, view: &CameraView) {
+        for (entity, point) in self.points.iter().zip(&self.cloud) {
+            if view.is_in_frustum(&point.position) {
+                continue;
+            }
+            if let Some(rendering) = world
+                .entity_mut(*entity)
+                .take::<ThreadSafeRef<MeshRendering>>()
+            {
+                self.hidden_points.insert(*entity, rendering);
+            }
+        }
+    }
+
+    fn show_all_points(&mut self, world: &mut World) {
+        for (entity, rendering) in self.hidden_points.drain() {
+            world.entity_mut(entity).insert(rendering);
+        }
+    }
+
+    /// Opacity of the point, decreasing with its reprojection error. Points without an error are
+    /// opaque.
+    fn point_alpha(&self, point: &Point) -> f32 {
+        match point.reproj_error {
+            Some(error) => (1.0 / (1.0 + self.error_transparency * error)).max(MIN_POINT_ALPHA),
+            None => 1.0,
+        }
+    }
+
+    fn update_point_colors(&mut self) {
+        if self.color_mode == self.applied_color_mode
+            && self.error_transparency == self.applied_error_transparency
+        {
+            return;
+        }
+
+        let colors = match self.color_mode {
+            ColorMode::Rgb => self.cloud.iter().map(|point| point.color).collect(),
+            ColorMode::Depth => {
+                let centroid = self
+                    .cloud
+                    .iter()
+                    .fold(Vec3::ZERO, |sum, point| sum + point.position)
+                    / self.cloud.len().max(1) as f32;
+                let distances = self
+                    .cloud
+                    .iter()
+                    .map(|point| point.position.distance(centroid))
+                    .collect::<Vec<_>>();
+                let max_distance = distances.iter().copied().fold(f32::EPSILON, f32::max);
+                distances
+                    .into_iter()
+                    .map(|distance| viridis(distance / max_distance))
+                    .collect::<Vec<_>>()
+            }
+            ColorMode::SourcePair => self
+                .cloud
+                .iter()
+                .map(|point| source_pair_color(point.source_pair))
+                .collect(),
+        };
+
+        for ((buffer, color), point) in self.color_buffers.iter().zip(colors).zip(&self.cloud) {
+            if let Err(error) = buffer
+                .lock()
+                .upload_data(color.extend(self.point_alpha(point)))
+            {
+                log::error!("Failed to update point color: {:?}", error);
+                break;
+            }
+        }
+        self.applied_color_mode = self.color_mode;
+        self.applied_error_transparency = self.error_transparency;
+    }
+
+    /// Only the material's buffer has to be updated, unlike for the color modes.
+    fn update_display_settings(&mut self) {
+        let display_settings = Vec2::new(self.gamma, self.exposure);
+        if display_settings == self.applied_display_settings {
+            return;
+        }
+
+        if let Err(error) = self.display_buffer.lock().upload_data(display_settings) {
+            log::error!("Failed to update display settings: {:?}", error);
+        }
+        self.applied_display_settings = display_settings;
+    }
+
+    /// The range the slice's planes can be moved in: the extent of the cloud along the axis, or up
+    /// to its farthest corner from the viewer's camera.
+    fn clip_extent(&self) -> (f32, f32) {
+        let Some(stats) = self.cloud_stats else {
+            return (0.0, 1.0);
+        };
+
+        match self.clip_axis {
+            ClipAxis::X => (stats.min.x, stats.max.x),
+            ClipAxis::Y => (stats.min.y, stats.max.y),
+            ClipAxis::Z => (stats.min.z, stats.max.z),
+            ClipAxis::CameraDepth => {
+                let position = *self.camera.mrg_camera.position() / SCENE_SCALE;
+                let farthest_corner = (position - stats.min)
+                    .abs()
+                    .max((position - stats.max).abs());
+                (0.0, farthest_corner.length())
+            }
         }
     }
+
+    /// Uploads the slice to the material's clip buffer when it changed. The camera depth slice moves
+    /// with the camera, so it has to be checked every frame.
+    fn update_clip_planes(&mut self) {
+        let (origin, direction) = match self.clip_axis {
+            ClipAxis::X => (Vec3::ZERO, Vec3::X),
+            ClipAxis::Y => (Vec3::ZERO, Vec3::Y),
+            ClipAxis::Z => (Vec3::ZERO, Vec3::Z),
+            ClipAxis::CameraDepth => (
+                *self.camera.mrg_camera.position(),
+                self.camera.mrg_camera.forward_vector(),
+            ),
+        };
+        let clip_data = [
+            origin.extend(1.0),
+            direction.extend(0.0),
+            Vec4::new(
+                self.clip_min * SCENE_SCALE,
+                self.clip_max * SCENE_SCALE,
+                if self.clip_enabled { 1.0 } else { 0.0 },
+                0.0,
+            ),
+        ];
+        if clip_data == self.applied_clip_data {
+            return;
+        }
+
+        if let Err(error) = self.clip_buffer.lock().upload_data(clip_data) {
+            log::error!("Failed to update clip planes: {:?}", error);
+        }
+        self.applied_clip_data = clip_data;
+    }
+
+    /// Applies the field of view chosen in the UI. The solo view keeps the field of view of the
+    /// selected camera, the change is applied when leaving it.
+    fn update_fov(&mut self) {
+        if self.fov == self.applied_fov || self.solo_view.is_some() {
+            return;
+        }
+
+        self.camera
+            .set_perspective(self.fov.to_radians(), self.near_plane, self.far_plane);
+        self.applied_fov = self.fov;
+    }
+
+    fn update_point_size(&mut self, world: &mut World) {
+        if self.point_size == self.applied_point_size {
+            return;
+        }
+
+        for (entity, point) in self.points.iter().zip(&self.cloud) {
+            if let Some(mut transform) = world.get_mut::<Transform>(*entity) {
+                *transform = Transform::from_trs(
+                    &(point.position * SCENE_SCALE),
+                    &Quat::default(),
+                    &Vec3::splat(self.point_size),
+                );
+            }
+        }
+        self.applied_point_size = self.point_size;
+    }
+
+    /// Shows or hides the camera gizmos and the trajectory to match the UI. The gizmos are always
+    /// hidden in the solo view, to avoid looking through the inside of the selected camera's own
+    /// gizmo.
+    fn update_camera_gizmos(&mut self, world: &mut World) {
+        let visible = self.show_cameras && self.solo_view.is_none();
+        set_visible(
+            world,
+            &self.camera_gizmos,
+            &mut self.hidden_camera_gizmos,
+            visible,
+        );
+        set_visible(
+            world,
+            &self.trajectory_segments,
+            &mut self.hidden_trajectory_segments,
+            self.show_trajectory,
+        );
+    }
+
+    fn snap_to_selected_camera(&mut self) {
+        let Some(view) = self.cameras.get(self.selected_camera) else {
+            return;
+        };
+        let desired_pos = view.center * SCENE_SCALE;
+        self.camera.look_at(&desired_pos, &Vec3::ZERO, &Vec3::Y);
+        log::debug!(
+            "camera is now at {}, (stored is {})",
+            self.camera.mrg_camera.position(),
+            desired_pos
+        );
+    }
+
+    /// The dataset camera closest to the viewer's camera, as displayed in the scene.
+    fn nearest_camera(&self) -> Option<usize> {
+        let position = *self.camera.mrg_camera.position();
+        self.cameras
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| {
+                (a.center * SCENE_SCALE)
+                    .distance_squared(position)
+                    .total_cmp(&(b.center * SCENE_SCALE).distance_squared(position))
+            })
+            .map(|(idx, _)| idx)
+    }
+
+    /// Steps to the previous or next camera with the bracket keys (or Page Up / Page Down), wrapping
+    /// around at the ends, and snaps to it.
+    fn update_camera_cycling(&mut self, context: &morrigu::application::StateContext) {
+        let input = context.window_input_state;
+        let camera_count = self.cameras.len();
+        if self.keyboard_over_ui || camera_count == 0 {
+            return;
+        }
+
+        let previous =
+            input.key_pressed(KeyCode::BracketLeft) || input.key_pressed(KeyCode::PageUp);
+        let next = input.key_pressed(KeyCode::BracketRight) || input.key_pressed(KeyCode::PageDown);
+        self.selected_camera = match (previous, next) {
+            (true, false) => (self.selected_camera + camera_count - 1) % camera_count,
+            (false, true) => (self.selected_camera + 1) % camera_count,
+            _ => return,
+        };
+        self.snap_to_selected_camera();
+    }
+
+    /// Deletes the points under the cursor while the left mouse button is held in brush mode.
+    fn update_brush(&mut self, context: &mut morrigu::application::StateContext) {
+        let input = context.window_input_state;
+        if self.pending_undo || (input.key_pressed(KeyCode::KeyZ) && input.held_control()) {
+            self.pending_undo = false;
+            self.undo_deletion(&mut context.ecs_manager.world);
+        }
+
+        if !self.brush_enabled || self.pointer_over_ui || self.solo_view.is_some() {
+            return;
+        }
+        let last_stroke_is_empty = self
+            .deleted_strokes
+            .last()
+            .is_some_and(|stroke| stroke.is_empty());
+        if input.mouse_pressed(0) && !last_stroke_is_empty {
+            self.deleted_strokes.push(vec![]);
+        }
+        let (Some(cursor), Some(stroke)) = (input.cursor(), self.deleted_strokes.last_mut()) else {
+            return;
+        };
+        if !input.mouse_held(0) {
+            return;
+        }
+
+        let cursor = Vec2::new(cursor.0, cursor.1);
+        for (idx, (entity, point)) in self.points.iter().zip(&self.cloud).enumerate() {
+            let Some(screen_position) = self
+                .camera
+                .project_to_screen(&(point.position * SCENE_SCALE))
+            else {
+                continue;
+            };
+            if screen_position.distance(cursor) > self.brush_radius {
+                continue;
+            }
+
+            if let Some(rendering) = context
+                .ecs_manager
+                .world
+                .entity_mut(*entity)
+                .take::<ThreadSafeRef<MeshRendering>>()
+            {
+                stroke.push((idx, rendering));
+                // A deleted point can't be measured
+                self.picked_points.retain(|picked| *picked != idx);
+            }
+        }
+    }
+
+    /// Picks the visible point whose projection is the closest to the cursor when clicking.
+    fn update_picking(&mut self, context: &mut morrigu::application::StateContext) {
+        let input = context.window_input_state;
+        if !self.picking_enabled
+            || self.brush_enabled
+            || self.pointer_over_ui
+            || !input.mouse_pressed(0)
+        {
+            return;
+        }
+        let Some(cursor) = input.cursor() else {
+            return;
+        };
+
+        let cursor = Vec2::new(cursor.0, cursor.1);
+        let deleted = self
+            .deleted_strokes
+            .iter()
+            .flatten()
+            .map(|(idx, _)| *idx)
+            .collect::<HashSet<_>>();
+        let picked_point = self
+            .points
+            .iter()
+            .zip(&self.cloud)
+            .enumerate()
+            .filter(|(idx, (entity, _))| {
+                !deleted.contains(idx) && !self.hidden_points.contains_key(entity)
+            })
+            .filter_map(|(idx, (_, point))| {
+                let screen_position = self
+                    .camera
+                    .project_to_screen(&(point.position * SCENE_SCALE))?;
+                let distance = screen_position.distance(cursor);
+                (distance <= PICK_RADIUS).then_some((idx, distance))
+            })
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(idx, _)| idx);
+
+        match picked_point {
+            Some(idx) => {
+                if self.picked_points.len() == 2 {
+                    self.picked_points.clear();
+                }
+                self.picked_points.push(idx);
+            }
+            None => self.picked_points.clear(),
+        }
+    }
+
+    /// The distance between the two picked points, in the reconstruction's units.
+    fn measured_distance(&self) -> Option<f32> {
+        match self.picked_points[..] {
+            [first, second] => Some(
+                self.cloud[first]
+                    .position
+                    .distance(self.cloud[second].position),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Stretches the measure segment between the two picked points, or hides it.
+    fn update_measure_segment(&mut self, world: &mut World) {
+        let transform = match self.picked_points[..] {
+            [first, second] => segment_transform(
+                self.cloud[first].position * SCENE_SCALE,
+                self.cloud[second].position * SCENE_SCALE,
+            ),
+            _ => None,
+        };
+        let visible = transform.is_some();
+        if let Some(transform) = transform {
+            if let Some(mut current) = world.get_mut::<Transform>(self.measure_segment) {
+                *current = transform;
+            }
+        }
+        set_visible(
+            world,
+            &[self.measure_segment],
+            &mut self.hidden_measure_segment,
+            visible,
+        );
+    }
+
+    fn undo_deletion(&mut self, world: &mut World) {
+        let Some(stroke) = self.deleted_strokes.pop() else {
+            return;
+        };
+
+        for (idx, rendering) in stroke {
+            world.entity_mut(self.points[idx]).insert(rendering);
+        }
+    }
+
+    fn deleted_point_count(&self) -> usize {
+        self.deleted_strokes.iter().map(Vec::len).sum()
+    }
+
+    /// The points that were not deleted with the brush.
+    fn remaining_points(&self) -> impl Iterator<Item = &Point> {
+        let deleted = self
+            .deleted_strokes
+            .iter()
+            .flatten()
+            .map(|(idx, _)| *idx)
+            .collect::<HashSet<_>>();
+        self.cloud
+            .iter()
+            .enumerate()
+            .filter(move |(idx, _)| !deleted.contains(idx))
+            .map(|(_, point)| point)
+    }
+
+    fn export_edited_cloud(&self) {
+        let remaining = self.remaining_points().cloned().collect::<Vec<_>>();
+
+        if let Err(error) = write_ply(&self.export_path, &remaining, self.export_format) {
+            log::error!("Failed to export edited point cloud: {}", error);
+        }
+    }
+
+    /// Recomputes the statistics of the cloud after points were deleted or restored.
+    fn update_cloud_stats(&mut self) {
+        let deleted_count = self.deleted_point_count();
+        if deleted_count == self.cloud_stats_deleted_count {
+            return;
+        }
+
+        self.cloud_stats = CloudStats::of(self.remaining_points());
+        self.cloud_stats_deleted_count = deleted_count;
+    }
+
+    /// The mean duration of the last frames, or `None` before the first one.
+    fn mean_frame_time(&self) -> Option<std::time::Duration> {
+        if self.frame_times.is_empty() {
+            return None;
+        }
+
+        Some(self.frame_times.iter().sum::<std::time::Duration>() / self.frame_times.len() as u32)
+    }
+
+    fn fit_camera_to_cloud(&mut self) {
+        self.update_cloud_stats();
+        match self.cloud_stats {
+            Some(stats) => self
+                .camera
+                .fit_to_bounds(&(stats.min * SCENE_SCALE), &(stats.max * SCENE_SCALE)),
+            None => log::warn!("No points to fit the camera to"),
+        }
+    }
+
+    fn save_settings(&self) {
+        let Some(path) = &self.bookmarks_path else {
+            return;
+        };
+        let settings = ViewerSettings {
+            bookmarks: self.bookmarks.clone(),
+            background_color: Some(self.background_color),
+        };
+        if let Err(error) = save_settings(path, &settings) {
+            log::error!("Failed to save camera bookmarks: {:#}", error);
+        }
+    }
+
+    fn bookmarks_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.text_edit_singleline(&mut self.bookmark_name);
+            if ui
+                .add_enabled(self.solo_view.is_none(), egui::Button::new("Save bookmark"))
+                .clicked()
+            {
+                let name = match self.bookmark_name.trim() {
+                    "" => format!("Bookmark #{}", self.bookmarks.len() + 1),
+                    name => name.to_owned(),
+                };
+                self.bookmarks.push(Bookmark {
+                    name,
+                    camera: self.camera.state(),
+                });
+                self.bookmark_name.clear();
+                self.save_settings();
+            }
+        });
+
+        let mut removed = None;
+        for (idx, bookmark) in self.bookmarks.iter().enumerate() {
+            ui.horizontal(|ui| {
+                if ui.button("Go").clicked() {
+                    self.solo_view_enabled = false;
+                    self.pending_camera_action = Some(CameraAction::Restore(bookmark.camera));
+                }
+                if ui.button("Delete").clicked() {
+                    removed = Some(idx);
+                }
+                ui.label(&bookmark.name);
+            });
+        }
+        if let Some(idx) = removed {
+            self.bookmarks.remove(idx);
+            self.save_settings();
+        }
+    }
+
+    /// Enters, leaves or updates the solo camera view to match what was requested from the UI.
+    fn update_solo_view(&mut self, world: &mut World) {
+        let requested = self
+            .solo_view_enabled
+            .then_some((self.selected_camera, self.hide_outside_frustum));
+        let current = self
+            .solo_view
+            .as_ref()
+            .map(|solo_view| (solo_view.camera_idx, solo_view.hide_outside_frustum));
+        if requested == current {
+            return;
+        }
+
+        if let Some(solo_view) = self.solo_view.take() {
+            self.camera.mrg_camera = solo_view.orbit_camera;
+            self.camera.distance = solo_view.orbit_distance;
+            self.show_all_points(world);
+        }
+
+        let Some((camera_idx, hide_outside_frustum)) = requested else {
+            return;
+        };
+        let Some(&view) = self.cameras.get(camera_idx) else {
+            log::warn!("No projection data for camera #{}", camera_idx);
+            self.solo_view_enabled = false;
+            return;
+        };
+
+        let orbit_camera = self.camera.mrg_camera;
+        let orbit_distance = self.camera.distance;
+        let size = orbit_camera.size();
+        self.camera.mrg_camera = Camera::builder().build(
+            morrigu::components::camera::Projection::Perspective(PerspectiveData {
+                horizontal_fov: view.horizontal_fov,
+                near_plane: self.near_plane,
+                far_plane: self.far_plane,
+            }),
+            &Vec2::new(size.x, size.y),
+        );
+        let eye = view.center * SCENE_SCALE;
+        self.camera.look_at(
+            &eye,
+            &((view.center + view.forward) * SCENE_SCALE),
+            &view.up,
+        );
+
+        if hide_outside_frustum {
+            self.hide_points_outside(world, &view);
+        }
+
+        self.solo_view = Some(SoloView {
+            camera_idx,
+            hide_outside_frustum,
+            orbit_camera,
+            orbit_distance,
+        });
+    }
 }
 
 impl ApplicationState for RenderState {
@@ -138,7 +1222,40 @@ impl ApplicationState for RenderState {
         dt: std::time::Duration,
         context: &mut morrigu::application::StateContext,
     ) {
-        self.camera.on_update(dt, context.window_input_state);
+        if self.frame_times.len() == FRAME_TIME_SAMPLES {
+            self.frame_times.pop_front();
+        }
+        self.frame_times.push_back(dt);
+        self.update_solo_view(&mut context.ecs_manager.world);
+        match self.pending_camera_action.take() {
+            Some(CameraAction::Reset) => self.camera.reset(),
+            Some(CameraAction::FitToCloud) => self.fit_camera_to_cloud(),
+            Some(CameraAction::Restore(state)) => self.camera.restore(&state),
+            None => (),
+        }
+        self.update_fov();
+        self.update_camera_gizmos(&mut context.ecs_manager.world);
+        self.update_point_size(&mut context.ecs_manager.world);
+        self.update_point_colors();
+        self.update_cloud_stats();
+        self.update_display_settings();
+        let [red, green, blue] = self.background_color;
+        context.renderer.clear_color = [red, green, blue, 1.0];
+        self.update_brush(context);
+        self.update_picking(context);
+        self.update_measure_segment(&mut context.ecs_manager.world);
+        self.update_camera_cycling(context);
+        if !self.keyboard_over_ui && context.window_input_state.key_pressed(KeyCode::KeyH) {
+            self.show_help = !self.show_help;
+        }
+
+        let brushing =
+            self.brush_enabled && !self.pointer_over_ui && context.window_input_state.mouse_held(0);
+        // Keys typed in the UI's text fields shouldn't move the camera
+        if self.solo_view.is_none() && !brushing && !self.keyboard_over_ui {
+            self.camera.on_update(dt, context.window_input_state);
+        }
+        self.update_clip_planes();
         context
             .ecs_manager
             .world
@@ -154,13 +1271,139 @@ impl ApplicationState for RenderState {
         _dt: std::time::Duration,
         context: &mut morrigu::application::EguiUpdateContext,
     ) {
+        self.pointer_over_ui = context.egui_context.is_pointer_over_area();
+        self.keyboard_over_ui = context.egui_context.wants_keyboard_input();
+
+        egui::Window::new("Controls")
+            .open(&mut self.show_help)
+            .show(context.egui_context, |ui| {
+                egui::Grid::new("bindings").striped(true).show(ui, |ui| {
+                    for binding in self.camera.bindings().chain(VIEWER_BINDINGS) {
+                        ui.strong(binding.input.as_ref());
+                        ui.label(binding.action);
+                        ui.end_row();
+                    }
+                });
+            });
+
         egui::Window::new("Settings and info").show(context.egui_context, |ui| {
-            ui.label(format!("Number of points: {}", self.points.len()));
+            ui.horizontal(|ui| {
+                ui.label(format!(
+                    "Number of points: {}",
+                    self.points.len() - self.deleted_point_count()
+                ));
+                if ui
+                    .button("?")
+                    .on_hover_text("Show the controls (H)")
+                    .clicked()
+                {
+                    self.show_help = !self.show_help;
+                }
+            });
+            match &self.cloud_stats {
+                Some(stats) => {
+                    let size = stats.max - stats.min;
+                    ui.label(format!(
+                        "Bounding box: {:.3} x {:.3} x {:.3}",
+                        size.x, size.y, size.z
+                    ))
+                    .on_hover_text(format!(
+                        "From ({:.4}, {:.4}, {:.4}) to ({:.4}, {:.4}, {:.4})",
+                        stats.min.x,
+                        stats.min.y,
+                        stats.min.z,
+                        stats.max.x,
+                        stats.max.y,
+                        stats.max.z
+                    ));
+                    ui.label(format!(
+                        "Centroid: ({:.3}, {:.3}, {:.3})",
+                        stats.centroid.x, stats.centroid.y, stats.centroid.z
+                    ));
+                }
+                None => {
+                    ui.label("The cloud is empty");
+                }
+            }
+            if let Some(frame_time) = self.mean_frame_time() {
+                ui.label(format!(
+                    "Frame time: {:.1} ms ({:.0} FPS)",
+                    frame_time.as_secs_f64() * 1000.0,
+                    1.0 / frame_time.as_secs_f64().max(f64::EPSILON)
+                ))
+                .on_hover_text(format!(
+                    "Averaged over the last {} frames",
+                    FRAME_TIME_SAMPLES
+                ));
+            }
+            ui.add(
+                egui::Slider::new(&mut self.point_size, 0.001..=0.1)
+                    .logarithmic(true)
+                    .text("Point size"),
+            );
+            egui::ComboBox::from_label("Color mode")
+                .selected_text(self.color_mode.label())
+                .show_ui(ui, |ui| {
+                    for mode in [ColorMode::Rgb, ColorMode::Depth, ColorMode::SourcePair] {
+                        ui.selectable_value(&mut self.color_mode, mode, mode.label());
+                    }
+                });
+            ui.horizontal(|ui| {
+                ui.color_edit_button_rgb(&mut self.background_color);
+                ui.label("Background");
+                if ui.button("Dark").clicked() {
+                    self.background_color = DARK_BACKGROUND;
+                }
+                if ui.button("Light").clicked() {
+                    self.background_color = LIGHT_BACKGROUND;
+                }
+            });
+            ui.add(
+                egui::Slider::new(&mut self.error_transparency, 0.0..=10.0)
+                    .text("Error transparency"),
+            )
+            .on_hover_text("Make the points with a large reprojection error more transparent");
+            ui.add(egui::Slider::new(&mut self.gamma, 0.2..=5.0).text("Gamma"));
+            ui.add(
+                egui::Slider::new(&mut self.exposure, 0.1..=10.0)
+                    .logarithmic(true)
+                    .text("Exposure"),
+            );
+
+            let clip_axis = self.clip_axis;
+            ui.horizontal(|ui| {
+                if ui
+                    .checkbox(&mut self.clip_enabled, "Slice")
+                    .on_hover_text(
+                        "Only draw what lies between two planes, to look inside the cloud",
+                    )
+                    .changed()
+                    && self.clip_enabled
+                {
+                    (self.clip_min, self.clip_max) = self.clip_extent();
+                }
+                egui::ComboBox::from_id_source("clip_axis")
+                    .selected_text(self.clip_axis.label())
+                    .show_ui(ui, |ui| {
+                        for axis in [ClipAxis::X, ClipAxis::Y, ClipAxis::Z, ClipAxis::CameraDepth] {
+                            ui.selectable_value(&mut self.clip_axis, axis, axis.label());
+                        }
+                    });
+            });
+            if self.clip_axis != clip_axis {
+                (self.clip_min, self.clip_max) = self.clip_extent();
+            }
+            if self.clip_enabled {
+                let (min, max) = self.clip_extent();
+                ui.add(egui::Slider::new(&mut self.clip_min, min..=max).text("Slice start"));
+                ui.add(egui::Slider::new(&mut self.clip_max, min..=max).text("Slice end"));
+                self.clip_max = self.clip_max.max(self.clip_min);
+            }
 
             egui::ComboBox::from_label("Select camera")
                 .selected_text(format!("Camera #{}", self.selected_camera))
                 .show_ui(ui, |ui| {
-                    for (idx, _) in self.camera_positions.iter().enumerate() {
+                    for idx in 0..self.cameras.len() {
                         ui.selectable_value(
                             &mut self.selected_camera,
                             idx,
@@ -168,18 +1411,120 @@ impl ApplicationState for RenderState {
                         );
                     }
                 });
-            if ui.button("Snap to camera location").clicked() {
-                let desired_pos = self.camera_positions.get(self.selected_camera).unwrap();
-                // self.camera.distance = desired_pos.length();
-                // self.camera.set_focal_point(&Vec3::new(0.0, 0.0, 0.0));
-                // self.camera.mrg_camera.set_position(desired_pos);
-                self.camera.mrg_camera.set_position(desired_pos);
-                self.camera.lookat_temp(&Vec3::new(0.0, 0.0, 0.0));
-                log::warn!(
-                    "camera is now at {}, (stored is {})",
-                    self.camera.mrg_camera.position(),
-                    desired_pos
+            ui.horizontal(|ui| {
+                if ui.button("Snap to camera location").clicked() {
+                    self.snap_to_selected_camera();
+                }
+                if ui
+                    .add_enabled(
+                        !self.cameras.is_empty(),
+                        egui::Button::new("Snap to nearest camera"),
+                    )
+                    .on_hover_text("Select the camera closest to the current view and snap to it")
+                    .clicked()
+                {
+                    if let Some(idx) = self.nearest_camera() {
+                        self.selected_camera = idx;
+                        self.snap_to_selected_camera();
+                    }
+                }
+            });
+            ui.label("[ / ] or Page Up / Page Down: previous / next camera");
+
+            ui.horizontal(|ui| {
+                if ui.button("Reset camera").clicked() {
+                    self.solo_view_enabled = false;
+                    self.pending_camera_action = Some(CameraAction::Reset);
+                }
+                if ui.button("Fit to cloud").clicked() {
+                    self.solo_view_enabled = false;
+                    self.pending_camera_action = Some(CameraAction::FitToCloud);
+                }
+            });
+            let mut camera_mode = self.camera.mode();
+            ui.horizontal(|ui| {
+                ui.radio_value(&mut camera_mode, CameraMode::Orbit, "Orbit camera");
+                ui.radio_value(&mut camera_mode, CameraMode::Free, "Free camera");
+            })
+            .response
+            .on_hover_text(format!(
+                "Press {} to switch",
+                self.camera.controls().toggle_mode
+            ));
+            self.camera.set_mode(camera_mode);
+            ui.add(
+                egui::Slider::new(&mut self.camera.move_speed, MIN_MOVE_SPEED..=MAX_MOVE_SPEED)
+                    .logarithmic(true)
+                    .text("Movement speed"),
+            )
+            .on_hover_text("Ctrl + scroll to adjust, hold Shift to move faster");
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut self.camera.invert_x, "Invert mouse X");
+                ui.checkbox(&mut self.camera.invert_y, "Invert mouse Y");
+            });
+            ui.add_enabled(
+                self.solo_view.is_none(),
+                egui::Slider::new(&mut self.fov, MIN_FOV..=MAX_FOV).text("Field of view (°)"),
+            );
+            ui.checkbox(&mut self.show_cameras, "Show cameras");
+            ui.checkbox(&mut self.show_trajectory, "Show trajectory")
+                .on_hover_text("Connect the camera positions in capture order");
+
+            ui.separator();
+            ui.label("Camera bookmarks");
+            self.bookmarks_ui(ui);
+
+            ui.separator();
+            ui.checkbox(&mut self.solo_view_enabled, "Solo camera view")
+                .on_hover_text(
+                    "Look through the selected camera, using its pose and field of view",
+                );
+            ui.add_enabled(
+                self.solo_view_enabled,
+                egui::Checkbox::new(
+                    &mut self.hide_outside_frustum,
+                    "Hide points outside of the camera's view",
+                ),
+            );
+
+            ui.separator();
+            ui.checkbox(&mut self.brush_enabled, "Deletion brush")
+                .on_hover_text("Left click and drag to delete points, Ctrl+Z to undo");
+            ui.add(
+                egui::Slider::new(&mut self.brush_radius, 2.0..=200.0).text("Brush radius (px)"),
+            );
+            ui.horizontal(|ui| {
+                if ui
+                    .add_enabled(!self.deleted_strokes.is_empty(), egui::Button::new("Undo"))
+                    .clicked()
+                {
+                    self.pending_undo = true;
+                }
+                if ui.button("Export edited cloud").clicked() {
+                    self.export_edited_cloud();
+                }
+            });
+
+            ui.separator();
+            ui.checkbox(&mut self.picking_enabled, "Pick points")
+                .on_hover_text(
+                    "Left click on a point to show its coordinates, and on a second one to \
+                    measure their distance",
                 );
+            for idx in &self.picked_points {
+                let point = &self.cloud[*idx];
+                ui.label(format!(
+                    "Point #{}: ({:.4}, {:.4}, {:.4})",
+                    idx, point.position.x, point.position.y, point.position.z
+                ));
+                ui.label(format!(
+                    "Color: ({:.3}, {:.3}, {:.3})",
+                    point.color.x, point.color.y, point.color.z
+                ));
+            }
+            if let Some(distance) = self.measured_distance() {
+                ui.label(format!("Distance: {:.4}", distance))
+                    .on_hover_text("In the units of the reconstruction, metric if the poses are");
             }
         });
     }
@@ -205,6 +1550,25 @@ impl ApplicationState for RenderState {
     }
 
     fn on_drop(&mut self, context: &mut morrigu::application::StateContext) {
+        // The background color isn't saved each time it changes while dragging the color picker
+        self.save_settings();
+        self.show_all_points(&mut context.ecs_manager.world);
+        for (entity, rendering) in self
+            .hidden_camera_gizmos
+            .drain(..)
+            .chain(self.hidden_trajectory_segments.drain(..))
+            .chain(self.hidden_measure_segment.drain(..))
+        {
+            context
+                .ecs_manager
+                .world
+                .entity_mut(entity)
+                .insert(rendering);
+        }
+        while !self.deleted_strokes.is_empty() {
+            self.undo_deletion(&mut context.ecs_manager.world);
+        }
+
         let mut query = context
             .ecs_manager
             .world
@@ -219,7 +1583,15 @@ impl ApplicationState for RenderState {
             mrc.lock().destroy(context.renderer)
         }
 
+        self.display_buffer
+            .lock()
+            .destroy(&context.renderer.device, &mut context.renderer.allocator());
+        self.clip_buffer
+            .lock()
+            .destroy(&context.renderer.device, &mut context.renderer.allocator());
         self.mesh_ref.lock().destroy(context.renderer);
+        self.camera_mesh_ref.lock().destroy(context.renderer);
+        self.segment_mesh_ref.lock().destroy(context.renderer);
 
         self.material_ref
             .lock()
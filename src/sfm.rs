@@ -1,34 +1,628 @@
-use crate::{render_state, Image};
-use anyhow::Result;
+use crate::{
+    bundle_adjustment,
+    events::{self, Event, Stage},
+    feature_cache::FeatureCache,
+    filters,
+    matcher::{ratio_test, BruteForceMatcher, FlannMatcher, Matcher, DEFAULT_RATIO},
+    pose::{self, ImageRotation},
+    progress, render_state,
+    stats::{self, PairStats},
+    timings,
+    tracks::{Observation, TrackBuilder},
+    Image,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Args, ValueEnum};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
 
-use cv::core::{DMatch, Point2f, Vec3b, Vector};
-#[allow(unused)]
+use cv::core::{DMatch, Point2f, Vector};
 use itertools::Itertools;
-use morrigu::math_types::Vec3;
+use morrigu::math_types::{Mat3, Vec3};
 use opencv as cv;
 use opencv::prelude::*;
+use rayon::prelude::*;
+
+/// Number of image pairs matched to estimate the detector's yield in auto-detector mode.
+const AUTO_DETECTOR_PROBE_PAIRS: usize = 2;
+/// Average number of matches per probed pair under which the fallback detector is tried.
+const AUTO_DETECTOR_MIN_MATCHES: f32 = 30.0;
+/// The ratios the ratio test is relaxed to, in order, when a pair has fewer than `--min-matches`.
+const RELAXED_RATIOS: [f32; 2] = [0.8, 0.9];
+/// Minimum number of matches (and of inliers) needed to recover the relative pose of two images.
+const MIN_POSE_MATCHES: usize = 8;
+/// Reprojection error in pixels above which the points triangulated while registering the images
+/// incrementally are discarded, when `--max-reproj-error` is not given.
+const INCREMENTAL_MAX_REPROJ_ERROR: f32 = 4.0;
+/// Pairs processed by each thread between two calls of the point sink, when streaming the points.
+const STREAMED_PAIRS_PER_THREAD: usize = 4;
+
+#[derive(Args)]
+pub struct SfmOptions {
+    /// The feature detector used to find keypoints and compute their descriptors.
+    #[arg(long, value_enum, default_value_t = Detector::Sift)]
+    pub detector: Detector,
+    /// Check the match yield on the first image pairs, and re-run feature extraction with an
+    /// alternative detector if too few matches are found. The detector with the best yield is kept.
+    #[arg(long)]
+    pub auto_detector: bool,
+    /// Convert SIFT descriptors to RootSIFT before matching. This changes the distribution of
+    /// descriptor distances, so the ratio test threshold might need to be tuned again.
+    #[arg(long)]
+    pub rootsift: bool,
+    /// The CPU matcher used to find the nearest neighbors of the descriptors.
+    #[arg(long, value_enum, default_value_t = MatcherKind::Flann)]
+    pub matcher: MatcherKind,
+    /// Match descriptors on the GPU when possible. Requires the `cuda` feature, and falls back to the
+    /// CPU matchers when no CUDA device is available.
+    #[arg(long)]
+    pub gpu_matching: bool,
+    /// Detect ORB features and match descriptors on the GPU (implies --gpu-matching). Requires the
+    /// `cuda` feature, and falls back to the CPU when no CUDA device is available. OpenCV has no
+    /// CUDA implementation of SIFT or AKAZE, those are still detected on the CPU.
+    #[arg(long)]
+    pub gpu: bool,
+    /// The threshold of Lowe's ratio test: a match is only kept if its distance is less than this
+    /// ratio times the distance of the second best candidate. Values of 1 or more disable the test.
+    #[arg(long, default_value_t = DEFAULT_RATIO)]
+    pub ratio_threshold: f32,
+    /// When fewer matches than this pass the ratio test (and the cross-check) for a pair, the ratio
+    /// is relaxed to 0.8, then 0.9, for that pair. Helps on low-texture or repetitive scenes.
+    #[arg(long, value_name = "N")]
+    pub min_matches: Option<usize>,
+    /// How the camera poses are recovered from the images, when no pose file is given.
+    #[arg(long, value_enum, default_value_t = ReconstructionMode::Pairwise)]
+    pub mode: ReconstructionMode,
+    /// After the geometric filtering of each pair, look for more matches for the unmatched keypoints,
+    /// among the keypoints near their epipolar line only. Recovers matches the ratio test rejected
+    /// because of similar keypoints elsewhere in the image.
+    #[arg(long)]
+    pub guided_matching: bool,
+    /// The maximum distance in pixels between a keypoint and the epipolar line for guided matching.
+    #[arg(long, value_name = "PX", default_value_t = 2.0)]
+    pub guided_matching_distance: f32,
+    /// Seed OpenCV's random number generator, so that the same inputs give the same point cloud.
+    /// This only affects the randomized stages (RANSAC when estimating the relative poses and
+    /// filtering matches). OpenCV only uses the lower 32 bits of the seed.
+    #[arg(long, value_name = "SEED")]
+    pub seed: Option<u64>,
+    /// Which image pairs are matched and triangulated.
+    #[arg(long, value_enum, default_value_t = PairMode::Sequential)]
+    pub pair_mode: PairMode,
+    /// How the colors of a point's views are combined into its color.
+    #[arg(long, value_enum, default_value_t = ColorCombination::Mean)]
+    pub color_mode: ColorCombination,
+    /// Discard triangulated points whose mean reprojection error in their source images is
+    /// larger than this many pixels.
+    #[arg(long, value_name = "PX")]
+    pub max_reproj_error: Option<f32>,
+    /// Discard the points farther than this from the nearest camera, such as the points triangulated
+    /// near infinity.
+    #[arg(long, value_name = "DIST")]
+    pub max_point_distance: Option<f32>,
+    /// Discard the points behind one of the cameras they were triangulated from.
+    #[arg(long)]
+    pub cheirality_check: bool,
+    /// Remove the points that are unusually far from their neighbors (statistical outlier removal).
+    #[arg(long)]
+    pub denoise: bool,
+    /// The number of neighbors considered for each point when denoising.
+    #[arg(long, value_name = "K", default_value_t = 8)]
+    pub denoise_neighbors: usize,
+    /// Points whose mean distance to their neighbors is more than this many standard deviations above
+    /// the average are removed when denoising.
+    #[arg(long, value_name = "N", default_value_t = 1.0)]
+    pub denoise_std_ratio: f32,
+    /// Only keep this many matches per image pair, the ones with the smallest descriptor distances
+    /// once the outliers are filtered out. 0 keeps every match.
+    #[arg(long, value_name = "N", default_value_t = 100)]
+    pub max_matches: usize,
+    /// Only keep the matches that are also the best match in the reverse direction (from the second
+    /// image to the first one).
+    #[arg(long)]
+    pub cross_check: bool,
+    /// Replace the points in each cell of a voxel grid of this size by their average, to merge the
+    /// duplicates triangulated from overlapping pairs.
+    #[arg(long, value_name = "SIZE")]
+    pub voxel_size: Option<f32>,
+    /// Refine the camera poses and the points together by minimizing their reprojection error over
+    /// all the tracks. Only applies to `--pair-mode tracks`.
+    #[arg(long)]
+    pub bundle_adjust: bool,
+    /// The number of iterations of the bundle adjustment.
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    pub bundle_adjust_iterations: usize,
+    /// Estimate a normal for each point from its nearest neighbors, and export it in the PLY file.
+    #[arg(long)]
+    pub normals: bool,
+    /// The number of neighbors used to estimate each point's normal.
+    #[arg(long, value_name = "K", default_value_t = 10)]
+    pub normal_neighbors: usize,
+    /// Write an image of the matches of each pair to this directory, for debugging. The matches go
+    /// from green to red as their ratio test margin shrinks, the rejected candidates are drawn
+    /// faintly in gray.
+    #[arg(long, value_name = "DIR")]
+    pub debug_matches: Option<PathBuf>,
+    /// Only write the match images of the pairs with fewer matches than this (and of the pairs above
+    /// `--debug-matches-max`, if given).
+    #[arg(long, value_name = "N", requires = "debug_matches")]
+    pub debug_matches_min: Option<usize>,
+    /// Only write the match images of the pairs with more matches than this (and of the pairs below
+    /// `--debug-matches-min`, if given).
+    #[arg(long, value_name = "N", requires = "debug_matches")]
+    pub debug_matches_max: Option<usize>,
+    /// Store the extracted features in this directory, and reuse them on the next runs as long as the
+    /// images and the detector don't change.
+    #[arg(long, value_name = "DIR")]
+    pub feature_cache: Option<PathBuf>,
+    /// A directory of PNG masks restricting where features are detected: black pixels are excluded.
+    /// The mask of `image.jpg` is `image.png` or `image.jpg.png`. Masks the size of the original
+    /// images are rotated along with `--rotate` and scaled along with `--max-dimension`.
+    #[arg(long, value_name = "DIR")]
+    pub mask_dir: Option<PathBuf>,
+    /// Rotate the images counterclockwise by this many degrees after loading them, e.g. for portrait
+    /// photos stored in landscape. The intrinsics and the poses read from a file are rotated too.
+    #[arg(long, value_enum, value_name = "DEG", default_value_t = ImageRotation::None)]
+    pub rotate: ImageRotation,
+
+    #[command(flatten)]
+    pub sift: SiftOptions,
+}
+
+/// Parameters of the SIFT detector, defaulting to OpenCV's.
+#[derive(Args, Debug)]
+pub struct SiftOptions {
+    /// The maximum number of SIFT keypoints kept per image, the ones with the best response. 0 keeps
+    /// every keypoint.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub sift_max_features: i32,
+    /// SIFT's contrast threshold: larger values find fewer keypoints in low contrast regions.
+    #[arg(long, value_name = "THRESHOLD", default_value_t = 0.04)]
+    pub sift_contrast_threshold: f64,
+    /// SIFT's edge threshold: smaller values discard more edge-like keypoints.
+    #[arg(long, value_name = "THRESHOLD", default_value_t = 10.0)]
+    pub sift_edge_threshold: f64,
+    /// The sigma of the Gaussian blur applied to the images at the first octave.
+    #[arg(long, value_name = "SIGMA", default_value_t = 1.6)]
+    pub sift_sigma: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum MatcherKind {
+    /// Approximate nearest neighbors, with FLANN's KD-tree. Binary descriptors always use
+    /// brute-force matching.
+    Flann,
+    /// Exact nearest neighbors. Slower, but useful on small datasets or as a baseline.
+    Bf,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PairMode {
+    /// Only consecutive images.
+    Sequential,
+    /// Every pair of images. This is quadratic in the number of images.
+    Exhaustive,
+    /// Every pair of images, with the matches linked across images into tracks. Each track is
+    /// triangulated once from all of its views, giving a single, more accurate point.
+    Tracks,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ColorCombination {
+    /// The mean of the views' colors.
+    Mean,
+    /// The color in the first view, e.g. the left image of a pair.
+    First,
+    /// The median of each channel over the views, which ignores overexposed or occluded views. It is
+    /// the same as the mean for points seen in two images, it needs the tracks of `--pair-mode
+    /// tracks` to make a difference.
+    Median,
+}
+
+impl ColorCombination {
+    /// Combines the colors of a point's views, in the order of the views. There must be at least one.
+    fn combine(self, mut colors: Vec<Vec3>) -> Vec3 {
+        match self {
+            ColorCombination::Mean => colors.iter().sum::<Vec3>() / colors.len() as f32,
+            ColorCombination::First => colors[0],
+            ColorCombination::Median => {
+                let mut median = Vec3::ZERO;
+                for channel in 0..3 {
+                    colors.sort_unstable_by(|a, b| a[channel].total_cmp(&b[channel]));
+                    let middle = colors.len() / 2;
+                    median[channel] = match colors.len() % 2 {
+                        0 => (colors[middle - 1][channel] + colors[middle][channel]) / 2.0,
+                        _ => colors[middle][channel],
+                    };
+                }
+                median
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ReconstructionMode {
+    /// Chain the relative poses of consecutive images. The errors accumulate along the sequence.
+    Pairwise,
+    /// Start from the pair of images with the most matches, then register the other images one at a
+    /// time by PnP against the points triangulated so far.
+    Incremental,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Detector {
+    Sift,
+    Orb,
+    Akaze,
+}
+
+impl Detector {
+    /// The detector to retry with when this one yields too few matches.
+    fn fallback(self) -> Detector {
+        match self {
+            Detector::Sift => Detector::Orb,
+            Detector::Orb | Detector::Akaze => Detector::Sift,
+        }
+    }
+
+    /// Whether the descriptors are bit strings, to be compared with the Hamming distance.
+    fn is_binary(self) -> bool {
+        match self {
+            Detector::Sift => false,
+            Detector::Orb | Detector::Akaze => true,
+        }
+    }
+}
+
+/// Converts SIFT descriptors to RootSIFT (Arandjelović & Zisserman, 2012): each descriptor is
+/// L1-normalized then square-rooted element-wise, so that the euclidean distance between them
+/// compares the original descriptors with the Hellinger kernel. The resulting descriptors have a unit
+/// L2 norm.
+fn to_root_sift(descriptors: &mut cv::core::Mat) -> Result<()> {
+    let descriptor_size = descriptors.cols() as usize;
+    if descriptor_size == 0 {
+        return Ok(());
+    }
+
+    for descriptor in descriptors
+        .data_typed_mut::<f32>()?
+        .chunks_exact_mut(descriptor_size)
+    {
+        let l1_norm = descriptor.iter().map(|value| value.abs()).sum::<f32>() + f32::EPSILON;
+        for value in descriptor {
+            *value = (*value / l1_norm).sqrt();
+        }
+    }
+
+    Ok(())
+}
 
 fn extract_features(
     images: &[Image],
+    image_paths: &[PathBuf],
+    detector: Detector,
+    options: &SfmOptions,
 ) -> Result<(
     Vec<cv::core::Vector<cv::core::KeyPoint>>,
     Vec<cv::core::Mat>,
 )> {
-    let mut sift_detector = cv::features2d::SiftFeatureDetector::create_def()?;
-
-    let mut keypoints = Vec::<_>::with_capacity(images.len());
-    let mut descriptors = Vec::<_>::with_capacity(images.len());
-
-    log::info!("Finding keypoints in images");
-    for (idx, image) in images.iter().enumerate() {
-        let mut img_keypoints = cv::core::Vector::<cv::core::KeyPoint>::new();
-        let mut img_descriptors = cv::core::Mat::default();
-        sift_detector.detect_and_compute_def(
-            &image,
-            &cv::core::no_array(),
-            &mut img_keypoints,
-            &mut img_descriptors,
+    log::info!("Finding keypoints in images using {:?}", detector);
+    if options.gpu && detector != Detector::Orb {
+        log::warn!(
+            "OpenCV has no CUDA implementation of {:?}, detecting features on the CPU",
+            detector
+        );
+    }
+    let cache = options
+        .feature_cache
+        .as_ref()
+        .map(|dir| {
+            // The features also depend on the detector's parameters, and on the masks
+            let mut detector_name = match detector {
+                Detector::Sift => format!("{:?} {:?}", detector, options.sift),
+                _ => format!("{:?}", detector),
+            };
+            // CUDA's ORB doesn't find exactly the same keypoints as the CPU one
+            if cfg!(feature = "cuda") && options.gpu && detector == Detector::Orb {
+                detector_name += " (CUDA)";
+            }
+            if let Some(mask_dir) = &options.mask_dir {
+                detector_name += &format!(" masked by {}", mask_dir.to_string_lossy());
+            }
+            // A half turn keeps the size of the images, which is part of the key
+            if options.rotate != ImageRotation::None {
+                detector_name += &format!(" rotated by {}", options.rotate.degrees());
+            }
+            FeatureCache::new(dir, &detector_name)
+        })
+        .transpose()?;
+    let images = ImageSet {
+        images,
+        paths: image_paths,
+        cache: cache.as_ref(),
+        mask_dir: options.mask_dir.as_deref(),
+        rotation: options.rotate,
+    };
+    let (keypoints, mut descriptors) = match detector {
+        Detector::Sift => detect_features(
+            detect_on_cpu(&mut cv::features2d::SiftFeatureDetector::create(
+                options.sift.sift_max_features,
+                3,
+                options.sift.sift_contrast_threshold,
+                options.sift.sift_edge_threshold,
+                options.sift.sift_sigma,
+                false,
+            )?),
+            &images,
+        ),
+        Detector::Orb => match options.gpu.then(|| detect_orb_on_gpu(&images)).flatten() {
+            Some(features) => Ok(features),
+            None => detect_features(
+                detect_on_cpu(&mut cv::features2d::ORB::create(
+                    5000,
+                    1.2,
+                    8,
+                    31,
+                    0,
+                    2,
+                    cv::features2d::ORB_ScoreType::HARRIS_SCORE,
+                    31,
+                    20,
+                )?),
+                &images,
+            ),
+        },
+        Detector::Akaze => detect_features(
+            detect_on_cpu(&mut cv::features2d::AKAZE::create_def()?),
+            &images,
+        ),
+    }?;
+
+    if options.rootsift {
+        if detector == Detector::Sift {
+            log::info!("Converting descriptors to RootSIFT");
+            for img_descriptors in &mut descriptors {
+                to_root_sift(img_descriptors)?;
+            }
+        } else {
+            log::warn!(
+                "RootSIFT only applies to SIFT descriptors, ignoring it for {:?}",
+                detector
+            );
+        }
+    }
+
+    Ok((keypoints, descriptors))
+}
+
+/// The images to extract features from, with the paths they were loaded from, the feature cache and
+/// the directory of the detection masks, along with the rotation that was applied to the images.
+struct ImageSet<'a> {
+    images: &'a [Image],
+    paths: &'a [PathBuf],
+    cache: Option<&'a FeatureCache>,
+    mask_dir: Option<&'a Path>,
+    rotation: ImageRotation,
+}
+
+/// Reads the detection mask of the image from `mask_dir`, rotated like the image and resized to it if
+/// the image was downscaled. Returns `None` (with a warning) if the image has no mask, or if its mask
+/// doesn't have the image's dimensions.
+fn load_mask(
+    mask_dir: &Path,
+    image_path: &Path,
+    image: &Image,
+    rotation: ImageRotation,
+) -> Result<Option<Mat>> {
+    let candidates = [
+        image_path.with_extension("png"),
+        PathBuf::from(format!("{}.png", image_path.to_string_lossy())),
+    ];
+    let Some(mask_path) = candidates
+        .iter()
+        .filter_map(|path| path.file_name())
+        .map(|name| mask_dir.join(name))
+        .find(|path| path.is_file())
+    else {
+        log::warn!(
+            "No mask found for {} in {}, detecting features in the whole image",
+            image_path.to_string_lossy(),
+            mask_dir.to_string_lossy()
+        );
+        return Ok(None);
+    };
+
+    let mut mask = cv::imgcodecs::imread(
+        &mask_path.to_string_lossy(),
+        cv::imgcodecs::IMREAD_GRAYSCALE,
+    )?;
+    if mask.empty() {
+        bail!("Failed to read mask {}", mask_path.to_string_lossy());
+    }
+    rotation.apply(&mut mask)?;
+
+    let (mask_size, image_size) = (mask.size()?, image.size()?);
+    if mask_size == image_size {
+        return Ok(Some(mask));
+    }
+    // A downscaled image keeps its aspect ratio, up to the rounding of its dimensions
+    let scale = image_size.width as f64 / mask_size.width as f64;
+    if (mask_size.height as f64 * scale - image_size.height as f64).abs() > 1.0 {
+        log::warn!(
+            "The mask {} is {}x{} but its image is {}x{}, ignoring it",
+            mask_path.to_string_lossy(),
+            mask_size.width,
+            mask_size.height,
+            image_size.width,
+            image_size.height
+        );
+        return Ok(None);
+    }
+
+    let mut resized = Mat::default();
+    cv::imgproc::resize(
+        &mask,
+        &mut resized,
+        image_size,
+        0.0,
+        0.0,
+        cv::imgproc::INTER_NEAREST,
+    )?;
+    Ok(Some(resized))
+}
+
+/// The keypoints and descriptors found in an image, given the mask of the regions to search.
+type Detection = Result<(cv::core::Vector<cv::core::KeyPoint>, cv::core::Mat)>;
+
+fn detect_on_cpu(
+    feature_detector: &mut impl cv::features2d::Feature2DTrait,
+) -> impl FnMut(&cv::core::Mat, &cv::core::Mat) -> Detection + '_ {
+    move |image: &cv::core::Mat, mask: &cv::core::Mat| {
+        let mut keypoints = cv::core::Vector::<cv::core::KeyPoint>::new();
+        let mut descriptors = cv::core::Mat::default();
+        feature_detector.detect_and_compute_def(image, mask, &mut keypoints, &mut descriptors)?;
+
+        Ok((keypoints, descriptors))
+    }
+}
+
+/// Detects ORB features with OpenCV's CUDA implementation, using the same parameters as the CPU
+/// detector. Returns `None` when the GPU can't be used, for the features to be detected on the CPU.
+#[cfg(feature = "cuda")]
+fn detect_orb_on_gpu(
+    images: &ImageSet,
+) -> Option<(
+    Vec<cv::core::Vector<cv::core::KeyPoint>>,
+    Vec<cv::core::Mat>,
+)> {
+    let detect = || -> Result<Option<_>> {
+        if cv::core::get_cuda_enabled_device_count()? == 0 {
+            return Ok(None);
+        }
+
+        let mut orb = cv::cudafeatures2d::CUDA_ORB::create(
+            5000,
+            1.2,
+            8,
+            31,
+            0,
+            2,
+            cv::features2d::ORB_ScoreType::HARRIS_SCORE as i32,
+            31,
+            20,
+            false,
         )?;
+        let features = detect_features(
+            |image: &cv::core::Mat, mask: &cv::core::Mat| {
+                // The CUDA detector only works on grayscale images
+                let gray_image = match image.channels() {
+                    1 => Cow::Borrowed(image),
+                    _ => {
+                        let mut gray_image = cv::core::Mat::default();
+                        cv::imgproc::cvt_color_def(
+                            image,
+                            &mut gray_image,
+                            cv::imgproc::COLOR_BGR2GRAY,
+                        )?;
+                        Cow::Owned(gray_image)
+                    }
+                };
+                let mut gpu_image = cv::core::GpuMat::default()?;
+                gpu_image.upload(&*gray_image)?;
+                let mut gpu_mask = cv::core::GpuMat::default()?;
+                if !mask.empty() {
+                    gpu_mask.upload(mask)?;
+                }
+
+                let mut keypoints = cv::core::Vector::<cv::core::KeyPoint>::new();
+                let mut gpu_descriptors = cv::core::GpuMat::default()?;
+                orb.detect_and_compute(
+                    &gpu_image,
+                    &gpu_mask,
+                    &mut keypoints,
+                    &mut gpu_descriptors,
+                    false,
+                )?;
+                let mut descriptors = cv::core::Mat::default();
+                gpu_descriptors.download(&mut descriptors)?;
+
+                Ok((keypoints, descriptors))
+            },
+            images,
+        )?;
+
+        Ok(Some(features))
+    };
+
+    match detect() {
+        Ok(Some(features)) => Some(features),
+        Ok(None) => {
+            log::warn!("No CUDA device found, falling back to CPU feature detection");
+            None
+        }
+        Err(error) => {
+            log::warn!(
+                "Failed to detect features on the GPU ({}), falling back to CPU feature detection",
+                error
+            );
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "cuda"))]
+fn detect_orb_on_gpu(
+    _images: &ImageSet,
+) -> Option<(
+    Vec<cv::core::Vector<cv::core::KeyPoint>>,
+    Vec<cv::core::Mat>,
+)> {
+    log::warn!("Built without the `cuda` feature, falling back to CPU feature detection");
+    None
+}
+
+fn detect_features(
+    mut detect: impl FnMut(&cv::core::Mat, &cv::core::Mat) -> Detection,
+    images: &ImageSet,
+) -> Result<(
+    Vec<cv::core::Vector<cv::core::KeyPoint>>,
+    Vec<cv::core::Mat>,
+)> {
+    let mut keypoints = Vec::<_>::with_capacity(images.images.len());
+    let mut descriptors = Vec::<_>::with_capacity(images.images.len());
+
+    let progress_bar = progress::bar(images.images.len(), "feature extraction");
+    let mut cache_hits = 0;
+    for (idx, image) in images.images.iter().enumerate() {
+        progress_bar.inc(1);
+        let cache = images
+            .cache
+            .zip(images.paths.get(idx).map(PathBuf::as_path));
+        if let Some((img_keypoints, img_descriptors)) =
+            cache.and_then(|(cache, path)| cache.load(path, image))
+        {
+            log::debug!(
+                "\tLoaded {} cached keypoints for image #{}",
+                img_keypoints.len(),
+                idx + 1
+            );
+            keypoints.push(img_keypoints);
+            descriptors.push(img_descriptors);
+            cache_hits += 1;
+            continue;
+        }
+
+        // An empty mask lets the detector use the whole image
+        let mask = match images.mask_dir.zip(images.paths.get(idx)) {
+            Some((mask_dir, path)) => load_mask(mask_dir, path, image, images.rotation)?,
+            None => None,
+        }
+        .unwrap_or_default();
+        let (img_keypoints, img_descriptors) = detect(&*to_8_bit(image)?, &mask)?;
 
         log::debug!(
             "\tFound {} keypoints in image #{}",
@@ -36,301 +630,2219 @@ fn extract_features(
             idx + 1
         );
 
+        if let Some((cache, path)) = cache {
+            if let Err(error) = cache.store(path, image, &img_keypoints, &img_descriptors) {
+                log::warn!(
+                    "Failed to cache the features of image #{}: {}",
+                    idx + 1,
+                    error
+                );
+            }
+        }
+
         keypoints.push(img_keypoints);
         descriptors.push(img_descriptors);
     }
+    progress_bar.finish_and_clear();
+    if images.cache.is_some() {
+        log::info!(
+            "Loaded the features of {} of {} images from the cache",
+            cache_hits,
+            images.images.len()
+        );
+    }
     log::info!("Computed keypoints in all images");
 
     Ok((keypoints, descriptors))
 }
 
-fn find_matches(
-    descriptors1: &cv::core::Mat,
-    descriptors2: &cv::core::Mat,
-) -> Result<cv::core::Vector<cv::core::DMatch>> {
-    let index_params = cv::flann::KDTreeIndexParams::new(5)?;
-    let search_params = cv::flann::SearchParams::new_def()?;
-    let matcher = cv::features2d::FlannBasedMatcher::new(
-        &cv::core::Ptr::new(index_params.into()),
-        &cv::core::Ptr::new(search_params),
-    )?;
+/// The distance the detector's descriptors are compared with.
+fn descriptor_norm(detector: Detector) -> i32 {
+    match detector.is_binary() {
+        true => cv::core::NORM_HAMMING,
+        false => cv::core::NORM_L2,
+    }
+}
+
+fn create_matcher(detector: Detector, options: &SfmOptions) -> Result<Box<dyn Matcher>> {
+    let norm_type = descriptor_norm(detector);
 
-    let mut matches = cv::core::Vector::<cv::core::Vector<cv::core::DMatch>>::new();
-    matcher.knn_train_match_def(descriptors1, descriptors2, &mut matches, 2)?;
+    if options.gpu_matching || options.gpu {
+        #[cfg(feature = "cuda")]
+        match crate::matcher::CudaMatcher::new(norm_type) {
+            Ok(Some(matcher)) => return Ok(Box::new(matcher)),
+            Ok(None) => log::warn!("No CUDA device found, falling back to CPU matching"),
+            Err(error) => log::warn!(
+                "Failed to create CUDA matcher ({}), falling back to CPU matching",
+                error
+            ),
+        }
+        #[cfg(not(feature = "cuda"))]
+        log::warn!("Built without the `cuda` feature, falling back to CPU matching");
+    }
+
+    // Binary descriptors can't be indexed by FLANN's KD-tree
+    if options.matcher == MatcherKind::Flann && detector.is_binary() {
+        log::warn!(
+            "FLANN can't match the binary descriptors of {:?}, using brute-force matching",
+            detector
+        );
+    }
+    Ok(match (options.matcher, detector.is_binary()) {
+        (MatcherKind::Flann, false) => Box::new(FlannMatcher::new()?),
+        _ => Box::new(BruteForceMatcher::new(norm_type)?),
+    })
+}
+
+/// Only keeps the matches whose train keypoint's best match, in the reverse direction, is their
+/// query keypoint.
+fn cross_check(
+    matches: &cv::core::Vector<cv::core::DMatch>,
+    reverse_matches: &cv::core::Vector<cv::core::DMatch>,
+) -> Vec<cv::core::DMatch> {
+    let reverse_best = reverse_matches
+        .iter()
+        .map(|img_match| (img_match.query_idx, img_match.train_idx))
+        .collect::<HashMap<_, _>>();
 
-    let mut matches: Vec<cv::core::DMatch> = matches
+    matches
         .iter()
-        .flat_map(|img_match| {
-            match img_match.get(0).unwrap().distance < 0.7 * img_match.get(1).unwrap().distance {
-                // match true {
-                true => Some(img_match.get(0).unwrap()),
-                false => None,
+        .filter(|img_match| reverse_best.get(&img_match.train_idx) == Some(&img_match.query_idx))
+        .collect()
+}
+
+impl SfmOptions {
+    /// Whether the match image of a pair with this many matches should be written. Without a band,
+    /// every pair is written.
+    fn is_debugged_pair(&self, match_count: usize) -> bool {
+        match (self.debug_matches_min, self.debug_matches_max) {
+            (None, None) => true,
+            (min, max) => {
+                min.is_some_and(|min| match_count < min) || max.is_some_and(|max| match_count > max)
             }
-        })
-        .collect();
+        }
+    }
+}
 
-    matches.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap());
-    let matches: Vector<DMatch> = matches.into_iter().take(100).collect();
+/// Number of matches of image pairs left after each filtering step, in the pipeline's order.
+#[derive(Clone, Copy, Default)]
+struct MatchCounts {
+    /// Nearest neighbor candidates, one per descriptor of the query image.
+    candidates: usize,
+    ratio_test: usize,
+    cross_check: usize,
+    geometric: usize,
+    capped: usize,
+}
 
-    log::debug!("\tfound {} matches", matches.len());
-    Ok(matches)
+impl std::ops::AddAssign for MatchCounts {
+    fn add_assign(&mut self, other: Self) {
+        self.candidates += other.candidates;
+        self.ratio_test += other.ratio_test;
+        self.cross_check += other.cross_check;
+        self.geometric += other.geometric;
+        self.capped += other.capped;
+    }
 }
 
-fn format_point(
-    cv_point: &cv::core::Mat,
-    image1: &cv::core::Mat,
-    image2: &cv::core::Mat,
-    keypoint1: &cv::core::Point2f,
-    keypoint2: &cv::core::Point2f,
-) -> Result<render_state::Point> {
-    let position = Vec3::new(
-        *cv_point.at::<f64>(0).unwrap() as f32,
-        *cv_point.at::<f64>(1).unwrap() as f32,
-        *cv_point.at::<f64>(2).unwrap() as f32,
-    );
+impl std::fmt::Display for MatchCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} candidates -> {} after ratio test -> {} after cross-check -> {} after RANSAC -> {} after cap",
+            self.candidates, self.ratio_test, self.cross_check, self.geometric, self.capped
+        )
+    }
+}
 
-    let color1 = image1.at_2d::<Vec3b>(keypoint1.y.floor() as i32, keypoint1.x.floor() as i32)?;
-    let color2 = image2.at_2d::<Vec3b>(keypoint2.y.floor() as i32, keypoint2.x.floor() as i32)?;
-    let color = Vec3::new(
-        (*color1.last().unwrap() as f32 + *color2.last().unwrap() as f32) / (2.0 * u8::MAX as f32),
-        (*color1.get(1).unwrap() as f32 + *color2.get(1).unwrap() as f32) / (2.0 * u8::MAX as f32),
-        (*color1.first().unwrap() as f32 + *color2.first().unwrap() as f32)
-            / (2.0 * u8::MAX as f32),
-    );
+/// Matches the descriptors of two images, sorted by distance, along with the counts of the matching
+/// steps. The counts of the geometric filtering and of the cap are left to the caller, as the
+/// filtering needs the keypoints.
+fn find_matches(
+    matcher: &dyn Matcher,
+    descriptors1: &cv::core::Mat,
+    descriptors2: &cv::core::Mat,
+    options: &SfmOptions,
+) -> Result<(cv::core::Vector<cv::core::DMatch>, MatchCounts)> {
+    let mut counts = MatchCounts::default();
+    let knn_matches = matcher.knn_match(descriptors1, descriptors2)?;
+    counts.candidates = knn_matches.len();
+    let reverse_knn_matches = match options.cross_check {
+        true => Some(matcher.knn_match(descriptors2, descriptors1)?),
+        false => None,
+    };
+
+    // The nearest neighbors don't change with the ratio, only the ratio test is run again
+    let min_matches = options.min_matches.unwrap_or(0);
+    let mut relaxed_ratios = RELAXED_RATIOS
+        .into_iter()
+        .filter(|ratio| *ratio > options.ratio_threshold);
+    let mut ratio = options.ratio_threshold;
+    let mut matches = loop {
+        let matches = ratio_test(&knn_matches, ratio);
+        counts.ratio_test = matches.len();
+        let matches = match &reverse_knn_matches {
+            Some(reverse_knn_matches) => {
+                cross_check(&matches, &ratio_test(reverse_knn_matches, ratio))
+            }
+            None => matches.to_vec(),
+        };
+
+        match relaxed_ratios.next() {
+            Some(relaxed_ratio) if matches.len() < min_matches => ratio = relaxed_ratio,
+            _ => break matches,
+        }
+    };
+    counts.cross_check = matches.len();
+    if ratio != options.ratio_threshold {
+        log::info!(
+            "\tfound too few matches, relaxed the ratio test to {} ({} matches)",
+            ratio,
+            matches.len()
+        );
+    }
+
+    matches.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap());
 
-    Ok(render_state::Point { position, color })
+    Ok((matches.into_iter().collect(), counts))
 }
 
-fn triangulate_points(
-    image1: &cv::core::Mat,
-    image2: &cv::core::Mat,
-    pose1: cv::core::Mat,
-    pose2: cv::core::Mat,
+/// Keeps the `max_matches` matches with the smallest descriptor distances. The matches are still
+/// sorted by distance after the geometric filtering, which only removes some of them.
+fn cap_matches(
+    matches: cv::core::Vector<cv::core::DMatch>,
+    options: &SfmOptions,
+) -> cv::core::Vector<cv::core::DMatch> {
+    match options.max_matches {
+        0 => matches,
+        max_matches => matches.into_iter().take(max_matches).collect(),
+    }
+}
+
+/// Drops the matches that are not consistent with the epipolar geometry of the pair, estimated with a
+/// RANSAC fit of the fundamental matrix, which is returned along with the inliers. Matches are
+/// returned unchanged (without a fundamental matrix) if there are too few of them to estimate it.
+fn filter_matches_geometric(
     keypoints1: &cv::core::Vector<cv::core::KeyPoint>,
     keypoints2: &cv::core::Vector<cv::core::KeyPoint>,
     matches: &cv::core::Vector<cv::core::DMatch>,
-) -> Result<Vec<render_state::Point>> {
-    let mut formatted_points = Vec::with_capacity(matches.len());
+) -> Result<(cv::core::Vector<cv::core::DMatch>, Option<cv::core::Mat>)> {
+    if matches.len() < 8 {
+        log::debug!(
+            "\tonly {} matches, skipping geometric filtering",
+            matches.len()
+        );
+        return Ok((matches.clone(), None));
+    }
 
-    let mut point_3d = cv::core::Mat::default();
-    let poses: cv::core::Vector<cv::core::Mat> = vec![pose1, pose2].into();
-    let mut points_2d = Vector::<Mat>::new();
-    points_2d.push(Mat::default());
-    points_2d.push(Mat::default());
+    let mut points1 = Vector::<Point2f>::with_capacity(matches.len());
+    let mut points2 = Vector::<Point2f>::with_capacity(matches.len());
     for img_match in matches {
-        let left_keypoint = keypoints1
-            .get(img_match.query_idx.try_into().unwrap())
-            .unwrap()
-            .pt();
-        let slice = [left_keypoint.x, left_keypoint.y];
-        points_2d.set(0, Mat::from_slice_rows_cols(&slice, 2, 1)?)?;
-
-        let right_keypoint = keypoints2
-            .get(img_match.train_idx.try_into().unwrap())
-            .unwrap()
-            .pt();
-        let slice = [right_keypoint.x, right_keypoint.y];
-        points_2d.set(1, Mat::from_slice_rows_cols(&slice, 2, 1)?)?;
-
-        cv::sfm::triangulate_points(&points_2d, &poses, &mut point_3d)?;
-        formatted_points.push(format_point(
-            &point_3d,
-            image1,
-            image2,
-            &left_keypoint,
-            &right_keypoint,
-        )?);
+        points1.push(keypoints1.get(img_match.query_idx as usize)?.pt());
+        points2.push(keypoints2.get(img_match.train_idx as usize)?.pt());
     }
 
-    Ok(formatted_points)
+    let mut inlier_mask = cv::core::Mat::default();
+    let fundamental = cv::calib3d::find_fundamental_mat(
+        &points1,
+        &points2,
+        cv::calib3d::FM_RANSAC,
+        3.0,
+        0.99,
+        1000,
+        &mut inlier_mask,
+    )?;
+
+    let mut inliers = Vector::<DMatch>::with_capacity(matches.len());
+    for (idx, img_match) in matches.iter().enumerate() {
+        if *inlier_mask.at::<u8>(idx as i32)? != 0 {
+            inliers.push(img_match);
+        }
+    }
+
+    // No solution is found for degenerate configurations
+    Ok((inliers, (fundamental.rows() == 3).then_some(fundamental)))
 }
 
-#[allow(dead_code)]
-pub fn hardcode_triangulation(
-    pose1: cv::core::Mat,
-    pose2: cv::core::Mat,
-) -> Result<Vec<render_state::Point>> {
-    let left_points: [[f32; 2]; 10] = [
-        [132.0, 112.0],
-        [431.0, 157.0],
-        [510.0, 197.0],
-        [151.0, 378.0],
-        [219.0, 200.0],
-        [129.0, 232.0],
-        [140.0, 322.0],
-        [406.0, 373.0],
-        [405.0, 285.0],
-        [427.0, 173.0],
-    ];
-    let right_points: [[f32; 2]; 10] = [
-        [144.0, 96.0],
-        [433.0, 128.0],
-        [513.0, 169.0],
-        [137.0, 374.0],
-        [219.0, 216.0],
-        [129.0, 202.0],
-        [136.0, 302.0],
-        [404.0, 380.0],
-        [404.0, 284.0],
-        [430.0, 144.0],
-    ];
+/// The keypoints and descriptors of both images of a pair.
+struct PairFeatures<'a> {
+    keypoints1: &'a cv::core::Vector<cv::core::KeyPoint>,
+    keypoints2: &'a cv::core::Vector<cv::core::KeyPoint>,
+    descriptors1: &'a cv::core::Mat,
+    descriptors2: &'a cv::core::Mat,
+}
 
-    let poses: cv::core::Vector<cv::core::Mat> = vec![pose1, pose2].into();
-    let mut cv_point = cv::core::Mat::default();
-    let mut points_2d = Vector::<Mat>::new();
-    let mut formatted_points = Vec::with_capacity(left_points.len());
+/// The images, keypoints and matches of a pair, from which its points are triangulated.
+struct PairObservations<'a> {
+    image1: &'a cv::core::Mat,
+    image2: &'a cv::core::Mat,
+    keypoints1: &'a cv::core::Vector<cv::core::KeyPoint>,
+    keypoints2: &'a cv::core::Vector<cv::core::KeyPoint>,
+    matches: &'a cv::core::Vector<cv::core::DMatch>,
+}
 
-    points_2d.push(Mat::default());
-    points_2d.push(Mat::default());
-    for match_ in left_points.iter().zip(right_points.iter()) {
-        points_2d.set(0, Mat::from_slice_rows_cols(match_.0, 2, 1)?)?;
-        points_2d.set(1, Mat::from_slice_rows_cols(match_.1, 2, 1)?)?;
+/// Color of the matches whose ratio test margin is large (the best candidate is much closer than the
+/// second best), as BGR.
+const CONFIDENT_MATCH_COLOR: (f64, f64, f64) = (0.0, 255.0, 0.0);
+/// Color of the matches that barely passed the ratio test, as BGR.
+const MARGINAL_MATCH_COLOR: (f64, f64, f64) = (0.0, 0.0, 255.0);
+const REJECTED_MATCH_COLOR: (f64, f64, f64) = (160.0, 160.0, 160.0);
+/// Opacity of the lines of the rejected matches.
+const REJECTED_MATCH_ALPHA: f64 = 0.3;
 
-        cv::sfm::triangulate_points(&points_2d, &poses, &mut cv_point)?;
+/// Draws the matches of a pair side by side and writes the image to `path`. The kept matches are
+/// colored by their ratio test margin, from green (confident) to red (at the ratio threshold). The
+/// nearest neighbors that were rejected, by the ratio test or a later filter, are drawn faintly.
+fn write_match_image(
+    path: &Path,
+    matcher: &dyn Matcher,
+    images: [&Image; 2],
+    features: &PairFeatures,
+    matches: &Vector<DMatch>,
+    ratio_threshold: f32,
+) -> Result<()> {
+    let knn_matches = matcher.knn_match(features.descriptors1, features.descriptors2)?;
 
-        let position = Vec3::new(
-            *cv_point.at::<f64>(0).unwrap() as f32,
-            *cv_point.at::<f64>(1).unwrap() as f32,
-            *cv_point.at::<f64>(2).unwrap() as f32,
+    let mut canvas = cv::core::Mat::default();
+    cv::features2d::draw_matches(
+        &*to_8_bit(images[0])?,
+        features.keypoints1,
+        &*to_8_bit(images[1])?,
+        features.keypoints2,
+        &Vector::<DMatch>::new(),
+        &mut canvas,
+        cv::core::Scalar::all(-1.0),
+        cv::core::Scalar::all(-1.0),
+        &Vector::<i8>::new(),
+        cv::features2d::DrawMatchesFlags::NOT_DRAW_SINGLE_POINTS,
+    )?;
+    // The images are drawn side by side, the second one starts after the first one's columns
+    let offset = images[0].cols();
+    let line_ends = |img_match: &DMatch| -> Result<(cv::core::Point, cv::core::Point)> {
+        let left = features.keypoints1.get(img_match.query_idx as usize)?.pt();
+        let right = features.keypoints2.get(img_match.train_idx as usize)?.pt();
+        Ok((
+            cv::core::Point::new(left.x.round() as i32, left.y.round() as i32),
+            cv::core::Point::new(right.x.round() as i32 + offset, right.y.round() as i32),
+        ))
+    };
+    let draw_line = |image: &mut cv::core::Mat,
+                     img_match: &DMatch,
+                     (blue, green, red): (f64, f64, f64)|
+     -> Result<()> {
+        let (start, end) = line_ends(img_match)?;
+        cv::imgproc::line(
+            image,
+            start,
+            end,
+            cv::core::Scalar::new(blue, green, red, 0.0),
+            1,
+            cv::imgproc::LINE_AA,
+            0,
+        )?;
+        Ok(())
+    };
+
+    let kept = matches
+        .iter()
+        .map(|img_match| (img_match.query_idx, img_match.train_idx))
+        .collect::<HashSet<_>>();
+    let mut ratios = HashMap::new();
+    let mut rejected_layer = canvas.try_clone()?;
+    for knn_match in &knn_matches {
+        let Ok(best) = knn_match.get(0) else {
+            continue;
+        };
+        let ratio = match knn_match.get(1) {
+            Ok(second) if second.distance > 0.0 => best.distance / second.distance,
+            _ => 0.0,
+        };
+        ratios.insert(best.query_idx, ratio);
+        if !kept.contains(&(best.query_idx, best.train_idx)) {
+            draw_line(&mut rejected_layer, &best, REJECTED_MATCH_COLOR)?;
+        }
+    }
+    let mut output_image = cv::core::Mat::default();
+    cv::core::add_weighted_def(
+        &rejected_layer,
+        REJECTED_MATCH_ALPHA,
+        &canvas,
+        1.0 - REJECTED_MATCH_ALPHA,
+        0.0,
+        &mut output_image,
+    )?;
+
+    let ratio_threshold = ratio_threshold.min(1.0);
+    for img_match in matches {
+        // Guided matches were not found by the nearest neighbors search, they count as marginal
+        let margin = ratios
+            .get(&img_match.query_idx)
+            .map_or(1.0, |ratio| (ratio / ratio_threshold).clamp(0.0, 1.0))
+            as f64;
+        let lerp = |confident: f64, marginal: f64| confident + (marginal - confident) * margin;
+        let color = (
+            lerp(CONFIDENT_MATCH_COLOR.0, MARGINAL_MATCH_COLOR.0),
+            lerp(CONFIDENT_MATCH_COLOR.1, MARGINAL_MATCH_COLOR.1),
+            lerp(CONFIDENT_MATCH_COLOR.2, MARGINAL_MATCH_COLOR.2),
         );
-        formatted_points.push(render_state::Point {
-            position,
-            color: Vec3::new(0.8, 0.2, 0.2),
-        });
+        draw_line(&mut output_image, &img_match, color)?;
     }
 
-    Ok(formatted_points)
+    cv::imgcodecs::imwrite_def(&path.to_string_lossy(), &output_image)?;
+    Ok(())
 }
 
-#[allow(dead_code)]
-pub fn test_triangulation(
-    camera1: Mat,
-    camera2: Mat,
-    point2d1: Point2f,
-    point2d2: Point2f,
-    expected3d: Vec3,
-) -> Result<()> {
-    log::info!("left match: {:?}", point2d1);
-    log::info!("right match: {:?}", point2d2);
+/// Looks for matches for the keypoints of the first image that have none, among the unmatched
+/// keypoints of the second image within `--guided-matching-distance` of their epipolar line through
+/// the fundamental matrix. The ratio test is applied among these candidates only, and each keypoint of
+/// the second image keeps its best match. The new matches are appended to the existing ones.
+fn guided_matching(
+    features: &PairFeatures,
+    fundamental: &cv::core::Mat,
+    matches: &cv::core::Vector<cv::core::DMatch>,
+    norm_type: i32,
+    options: &SfmOptions,
+) -> Result<cv::core::Vector<cv::core::DMatch>> {
+    let matched1 = matches
+        .iter()
+        .map(|img_match| img_match.query_idx)
+        .collect::<HashSet<_>>();
+    let matched2 = matches
+        .iter()
+        .map(|img_match| img_match.train_idx)
+        .collect::<HashSet<_>>();
+
+    let points1 = features
+        .keypoints1
+        .iter()
+        .map(|keypoint| keypoint.pt())
+        .collect::<Vector<Point2f>>();
+    let points2 = features
+        .keypoints2
+        .iter()
+        .map(|keypoint| keypoint.pt())
+        .collect::<Vec<_>>();
+    // The lines are normalized, so that a * x + b * y + c is the distance to the line
+    let mut epilines = Vector::<cv::core::Vec3f>::new();
+    cv::calib3d::compute_correspond_epilines(&points1, 1, fundamental, &mut epilines)?;
 
-    let points_2d_values_left = [point2d1.x, point2d1.y];
-    let points_2d_left = Mat::from_slice_rows_cols(&points_2d_values_left, 2, 1)?;
-    let points_2d_values_right = [point2d2.x, point2d2.y];
-    let points_2d_right = Mat::from_slice_rows_cols(&points_2d_values_right, 2, 1)?;
-    let points_2d: Vector<Mat> = vec![points_2d_left, points_2d_right].into();
+    let mut new_matches: HashMap<i32, DMatch> = HashMap::new();
+    for (query_idx, line) in epilines.iter().enumerate() {
+        let query_idx = query_idx as i32;
+        if matched1.contains(&query_idx) {
+            continue;
+        }
 
-    log::info!(
-        "camera1 ({:?}): {:?}",
-        camera1.size()?,
-        camera1
-            .iter::<f32>()?
-            .map(|(_, value)| value)
-            .collect::<Vec<_>>()
-    );
-    log::info!(
-        "camera2: {:?}",
-        camera2
-            .iter::<f32>()?
-            .map(|(_, value)| value)
-            .collect::<Vec<_>>()
-    );
-    let poses: Vector<Mat> = vec![camera1, camera2].into();
+        let query = features.descriptors1.row(query_idx)?;
+        let mut best: Option<(i32, f32)> = None;
+        let mut second_distance = f32::INFINITY;
+        for (train_idx, point) in points2.iter().enumerate() {
+            let train_idx = train_idx as i32;
+            let line_distance = (line[0] * point.x + line[1] * point.y + line[2]).abs();
+            if matched2.contains(&train_idx) || line_distance > options.guided_matching_distance {
+                continue;
+            }
 
-    let mut point_3d = cv::core::Mat::default();
-    cv::sfm::triangulate_points(&points_2d, &poses, &mut point_3d)?;
+            let distance = cv::core::norm2(
+                &query,
+                &features.descriptors2.row(train_idx)?,
+                norm_type,
+                &cv::core::no_array(),
+            )? as f32;
+            match best {
+                Some((_, best_distance)) if distance >= best_distance => {
+                    second_distance = second_distance.min(distance)
+                }
+                _ => {
+                    if let Some((_, best_distance)) = best {
+                        second_distance = best_distance;
+                    }
+                    best = Some((train_idx, distance));
+                }
+            }
+        }
 
-    let actual_3d = Vec3::new(
-        *point_3d.at::<f64>(0)? as f32,
-        *point_3d.at::<f64>(1)? as f32,
-        *point_3d.at::<f64>(2)? as f32,
+        let Some((train_idx, distance)) = best else {
+            continue;
+        };
+        if distance >= options.ratio_threshold * second_distance {
+            continue;
+        }
+        let candidate = DMatch {
+            query_idx,
+            train_idx,
+            img_idx: 0,
+            distance,
+        };
+        new_matches
+            .entry(train_idx)
+            .and_modify(|img_match| {
+                if distance < img_match.distance {
+                    *img_match = candidate;
+                }
+            })
+            .or_insert(candidate);
+    }
+
+    let mut guided_matches = matches.clone();
+    for img_match in new_matches
+        .into_values()
+        .sorted_unstable_by_key(|img_match| img_match.query_idx)
+    {
+        guided_matches.push(img_match);
+    }
+    log::debug!(
+        "\tguided matching found {} more matches",
+        guided_matches.len() - matches.len()
     );
 
-    log::info!("Expected {}, got {}", expected3d, actual_3d);
+    Ok(guided_matches)
+}
+
+/// The image itself if it is 8 bit, which the feature detectors and `draw_matches` expect, or a copy
+/// of it scaled to 8 bit. Floating-point images are assumed to be in [0, 1].
+fn to_8_bit(image: &cv::core::Mat) -> Result<Cow<cv::core::Mat>> {
+    let scale = match image.depth() {
+        cv::core::CV_8U => return Ok(Cow::Borrowed(image)),
+        cv::core::CV_16U => u8::MAX as f64 / u16::MAX as f64,
+        _ => u8::MAX as f64,
+    };
 
-    Ok(())
+    let mut converted = cv::core::Mat::default();
+    image.convert_to(&mut converted, cv::core::CV_8U, scale, 0.0)?;
+    Ok(Cow::Owned(converted))
 }
 
-pub fn generate_point_cloud(
-    images: Vec<Image>,
-    poses: Vec<cv::core::Mat>,
-) -> Result<Vec<render_state::Point>> {
-    let (keypoints, descriptors) = extract_features(&images)?;
-
-    let output_subfolder = "out";
-    let should_output_images = std::path::Path::new(output_subfolder).exists()
-        || match std::fs::create_dir(output_subfolder) {
-            Ok(_) => true,
-            Err(_) => {
-                log::error!("Failed to create output directory, no images will be generated");
-                false
-            }
-        };
+/// Values of a BGR or grayscale pixel as RGB, in the range of the image's depth.
+fn pixel_rgb<T>(image: &cv::core::Mat, row: i32, col: i32) -> Result<Vec3>
+where
+    T: cv::core::DataType + Into<f32>,
+    cv::core::VecN<T, 3>: cv::core::DataType,
+{
+    match image.channels() {
+        // OpenCV stores colors as BGR, the point cloud as RGB: a pure red pixel is [0, 0, 255]
+        3 => {
+            let bgr = image.at_2d::<cv::core::VecN<T, 3>>(row, col)?;
+            Ok(Vec3::new(bgr[2].into(), bgr[1].into(), bgr[0].into()))
+        }
+        1 => Ok(Vec3::splat((*image.at_2d::<T>(row, col)?).into())),
+        channels => bail!(
+            "Expected a grayscale or BGR image to sample keypoint colors, got {} channels",
+            channels
+        ),
+    }
+}
 
-    let mut points = vec![];
-    log::info!("Generating points");
-    // for index_pair in (0..images.len()).combinations(2) {
-    for index_pair in (0..images.len()).collect::<Vec<_>>().windows(2) {
-        let left_idx = index_pair[0];
-        let right_idx = index_pair[1];
-        log::debug!("\tmatching between {} and {}", left_idx, right_idx);
-        let matches = find_matches(&descriptors[left_idx], &descriptors[right_idx])?;
-
-        if should_output_images {
-            let mut output_image = cv::core::Mat::default();
-            cv::features2d::draw_matches_def(
-                &images[left_idx],
-                &keypoints[left_idx],
-                &images[right_idx],
-                &keypoints[right_idx],
-                &matches,
-                &mut output_image,
-            )?;
-            cv::imgcodecs::imwrite_def(
-                &format!("./{}/{}-{}.png", output_subfolder, left_idx, right_idx),
-                &output_image,
-            )?;
+/// Color of the pixel containing the keypoint, as normalized RGB. Grayscale images give shades of
+/// gray. 8 and 16 bit images are normalized by their maximum value, floating-point images are kept as
+/// is. Keypoint coordinates are sub-pixel, so the ones on the last row or column can round past the
+/// image's edge, they are clamped to it.
+fn pixel_color(image: &cv::core::Mat, keypoint: &cv::core::Point2f) -> Result<Vec3> {
+    if image.rows() <= 0 || image.cols() <= 0 {
+        bail!("Cannot sample the color of a keypoint in an empty image");
+    }
 
-            // let test_match = matches.get(7)?;
-            // cv::features2d::draw_matches_def(
-            //     &images[left_idx],
-            //     &keypoints[left_idx],
-            //     &images[right_idx],
-            //     &keypoints[right_idx],
-            //     &vec![test_match].into(),
-            //     &mut output_image,
-            // )?;
-            // cv::imgcodecs::imwrite_def(
-            //     &format!("./{}/{}-{}_TEST.png", output_subfolder, left_idx, right_idx),
-            //     &output_image,
-            // )?;
-        }
-
-        // test_triangulation(
-        //     poses[left_idx].clone(),
-        //     poses[right_idx].clone(),
-        //     keypoints[left_idx].get(test_match.query_idx as usize)?.pt(),
-        //     keypoints[right_idx]
-        //         .get(test_match.train_idx as usize)?
-        //         .pt(),
-        //     Vec3::new(0.0, 0.0, 0.0),
-        // )?;
-
-        points.append(&mut triangulate_points(
-            &images[left_idx],
-            &images[right_idx],
-            poses[left_idx].clone(),
-            poses[right_idx].clone(),
-            &keypoints[left_idx],
-            &keypoints[right_idx],
-            &matches,
-        )?);
+    let row = (keypoint.y.floor() as i32).clamp(0, image.rows() - 1);
+    let col = (keypoint.x.floor() as i32).clamp(0, image.cols() - 1);
+    Ok(match image.depth() {
+        cv::core::CV_8U => pixel_rgb::<u8>(image, row, col)? / u8::MAX as f32,
+        cv::core::CV_16U => pixel_rgb::<u16>(image, row, col)? / u16::MAX as f32,
+        cv::core::CV_32F => pixel_rgb::<f32>(image, row, col)?,
+        _ => bail!("Expected an 8 bit, 16 bit or floating-point image to sample keypoint colors"),
+    })
+}
 
-        // points.append(&mut hardcode_triangulation(
-        //     poses[left_idx].clone(),
-        //     poses[right_idx].clone(),
-        // )?);
+fn format_point(
+    position: Vec3,
+    image1: &cv::core::Mat,
+    image2: &cv::core::Mat,
+    keypoint1: &cv::core::Point2f,
+    keypoint2: &cv::core::Point2f,
+    color_mode: ColorCombination,
+) -> Result<Option<render_state::Point>> {
+    // Degenerate configurations (e.g. keypoints on the epipoles) give points at infinity
+    if !position.is_finite() {
+        return Ok(None);
     }
-    log::info!("Generated {} points", points.len());
 
-    Ok(points)
+    let color = color_mode.combine(vec![
+        pixel_color(image1, keypoint1)?,
+        pixel_color(image2, keypoint2)?,
+    ]);
+
+    Ok(Some(render_state::Point {
+        position,
+        color,
+        ..Default::default()
+    }))
+}
+
+/// Distance in pixels between the keypoint and the projection of the 3D point through the pose.
+fn reprojection_error(
+    pose: &cv::core::Mat,
+    point: &Vec3,
+    keypoint: &cv::core::Point2f,
+) -> Result<f32> {
+    let mut projected = [0.0; 3];
+    for (row, value) in projected.iter_mut().enumerate() {
+        let row = row as i32;
+        *value = *pose.at_2d::<f32>(row, 0)? * point.x
+            + *pose.at_2d::<f32>(row, 1)? * point.y
+            + *pose.at_2d::<f32>(row, 2)? * point.z
+            + *pose.at_2d::<f32>(row, 3)?;
+    }
+
+    let dx = projected[0] / projected[2] - keypoint.x;
+    let dy = projected[1] / projected[2] - keypoint.y;
+    Ok((dx * dx + dy * dy).sqrt())
+}
+
+/// The pixel coordinates of the points as a 2xN matrix, the x coordinates on the first row and the y
+/// coordinates on the second one.
+fn points_matrix(points: &[Point2f]) -> Result<Mat> {
+    let values = points
+        .iter()
+        .map(|point| point.x)
+        .chain(points.iter().map(|point| point.y))
+        .collect::<Vec<_>>();
+    Ok(Mat::from_slice_rows_cols(&values, 2, points.len())?)
+}
+
+/// Triangulates all the matches of a pair at once: `sfm::triangulate_points` takes a 2xN matrix of
+/// the pixel coordinates of the N points in each view, and returns their world coordinates as a 3xN
+/// `CV_64F` matrix.
+fn triangulate_points(
+    observations: &PairObservations,
+    pose1: cv::core::Mat,
+    pose2: cv::core::Mat,
+    color_mode: ColorCombination,
+) -> Result<Vec<render_state::Point>> {
+    let PairObservations {
+        image1,
+        image2,
+        keypoints1,
+        keypoints2,
+        matches,
+    } = *observations;
+    if matches.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut left_keypoints = Vec::with_capacity(matches.len());
+    let mut right_keypoints = Vec::with_capacity(matches.len());
+    for img_match in matches {
+        left_keypoints.push(keypoints1.get(img_match.query_idx as usize)?.pt());
+        right_keypoints.push(keypoints2.get(img_match.train_idx as usize)?.pt());
+    }
+    let points_2d: Vector<Mat> = vec![
+        points_matrix(&left_keypoints)?,
+        points_matrix(&right_keypoints)?,
+    ]
+    .into();
+    let poses: cv::core::Vector<cv::core::Mat> = vec![pose1, pose2].into();
+
+    let mut points_3d = cv::core::Mat::default();
+    cv::sfm::triangulate_points(&points_2d, &poses, &mut points_3d)?;
+
+    let (pose1, pose2) = (poses.get(0)?, poses.get(1)?);
+    let mut formatted_points = Vec::with_capacity(matches.len());
+    for (idx, (left_keypoint, right_keypoint)) in
+        left_keypoints.iter().zip(&right_keypoints).enumerate()
+    {
+        let idx = idx as i32;
+        let position = Vec3::new(
+            *points_3d.at_2d::<f64>(0, idx)? as f32,
+            *points_3d.at_2d::<f64>(1, idx)? as f32,
+            *points_3d.at_2d::<f64>(2, idx)? as f32,
+        );
+        let Some(mut point) = format_point(
+            position,
+            image1,
+            image2,
+            left_keypoint,
+            right_keypoint,
+            color_mode,
+        )?
+        else {
+            continue;
+        };
+
+        let error = (reprojection_error(&pose1, &point.position, left_keypoint)?
+            + reprojection_error(&pose2, &point.position, right_keypoint)?)
+            / 2.0;
+        point.reproj_error = Some(error);
+        point.num_views = Some(2);
+        formatted_points.push(point);
+    }
+    if formatted_points.len() < matches.len() {
+        log::debug!(
+            "\tdiscarded {} points with non-finite coordinates",
+            matches.len() - formatted_points.len()
+        );
+    }
+
+    Ok(formatted_points)
+}
+
+/// Triangulates a track from all of its views at once. Returns `None` if the point is not finite.
+fn triangulate_track(
+    images: &[Image],
+    poses: &[cv::core::Mat],
+    keypoints: &[cv::core::Vector<cv::core::KeyPoint>],
+    track: &[Observation],
+    color_mode: ColorCombination,
+) -> Result<Option<render_state::Point>> {
+    let mut track_poses = Vector::<Mat>::with_capacity(track.len());
+    let mut points_2d = Vector::<Mat>::with_capacity(track.len());
+    let mut track_keypoints = Vec::with_capacity(track.len());
+    for &(image_idx, keypoint_idx) in track {
+        let keypoint = keypoints[image_idx].get(keypoint_idx)?.pt();
+        track_poses.push(poses[image_idx].clone());
+        points_2d.push(Mat::from_slice_rows_cols(&[keypoint.x, keypoint.y], 2, 1)?);
+        track_keypoints.push(keypoint);
+    }
+
+    let mut point_3d = cv::core::Mat::default();
+    cv::sfm::triangulate_points(&points_2d, &track_poses, &mut point_3d)?;
+    let position = Vec3::new(
+        *point_3d.at::<f64>(0)? as f32,
+        *point_3d.at::<f64>(1)? as f32,
+        *point_3d.at::<f64>(2)? as f32,
+    );
+    if !position.is_finite() {
+        return Ok(None);
+    }
+
+    let mut colors = Vec::with_capacity(track.len());
+    let mut error = 0.0;
+    for (&(image_idx, _), keypoint) in track.iter().zip(&track_keypoints) {
+        colors.push(pixel_color(&images[image_idx], keypoint)?);
+        error += reprojection_error(&poses[image_idx], &position, keypoint)?;
+    }
+
+    Ok(Some(render_state::Point {
+        position,
+        color: color_mode.combine(colors),
+        reproj_error: Some(error / track.len() as f32),
+        num_views: Some(track.len() as u32),
+        ..Default::default()
+    }))
+}
+
+fn discard_inaccurate_points(points: &mut Vec<render_state::Point>, options: &SfmOptions) {
+    if let Some(max_reproj_error) = options.max_reproj_error {
+        let triangulated_count = points.len();
+        points.retain(|point| point.reproj_error.unwrap_or(0.0) <= max_reproj_error);
+        log::debug!(
+            "\tdiscarded {} of {} points above the reprojection error threshold",
+            triangulated_count - points.len(),
+            triangulated_count
+        );
+    }
+}
+
+/// The rotation and translation of each camera, mapping world coordinates to the camera frame.
+fn camera_extrinsics(poses: &[cv::core::Mat]) -> Result<Vec<(Mat3, Vec3)>> {
+    poses
+        .iter()
+        .map(|pose| -> Result<(Mat3, Vec3)> {
+            let (_, rotation, translation) = pose::decompose_projection(pose)?;
+            Ok((rotation, translation))
+        })
+        .collect()
+}
+
+/// Drops the points that are behind one of the cameras they were triangulated from (negative depth
+/// in the camera frame), given by `image_indices`.
+fn discard_points_behind<I: IntoIterator<Item = usize>>(
+    points: impl IntoIterator<Item = (render_state::Point, I)>,
+    extrinsics: &[(Mat3, Vec3)],
+) -> Vec<render_state::Point> {
+    points
+        .into_iter()
+        .filter_map(|(point, image_indices)| {
+            let in_front = image_indices.into_iter().all(|image_idx| {
+                let (rotation, translation) = extrinsics[image_idx];
+                (rotation * point.position + translation).z > 0.0
+            });
+            in_front.then_some(point)
+        })
+        .collect()
+}
+
+/// Drops the points farther than `--max-point-distance` from the nearest camera.
+fn discard_distant_points(
+    points: Vec<render_state::Point>,
+    poses: &[cv::core::Mat],
+    options: &SfmOptions,
+) -> Result<Vec<render_state::Point>> {
+    let Some(max_distance) = options.max_point_distance else {
+        return Ok(points);
+    };
+    if max_distance <= 0.0 {
+        bail!(
+            "The maximum point distance must be positive, got {}",
+            max_distance
+        );
+    }
+
+    let camera_centers = camera_extrinsics(poses)?
+        .into_iter()
+        .map(|(rotation, translation)| -(rotation.transpose() * translation))
+        .collect::<Vec<_>>();
+    Ok(filters::remove_distant_points(
+        points,
+        &camera_centers,
+        max_distance,
+    ))
+}
+
+fn log_reprojection_errors(points: &[render_state::Point]) {
+    let mut errors = points
+        .iter()
+        .filter_map(|point| point.reproj_error)
+        .collect::<Vec<_>>();
+    if errors.is_empty() {
+        return;
+    }
+
+    errors.sort_by(f32::total_cmp);
+    log::info!(
+        "Reprojection error: mean {:.3}px, median {:.3}px, max {:.3}px",
+        errors.iter().sum::<f32>() / errors.len() as f32,
+        errors[errors.len() / 2],
+        errors[errors.len() - 1]
+    );
+}
+
+/// Average number of matches kept over the first few consecutive pairs, after the geometric
+/// filtering like in `process_pair`, or `None` if there are not enough images to form a pair.
+fn probe_match_yield(
+    detector: Detector,
+    keypoints: &[cv::core::Vector<cv::core::KeyPoint>],
+    descriptors: &[cv::core::Mat],
+    options: &SfmOptions,
+) -> Result<Option<f32>> {
+    let probed_pairs = usize::min(
+        AUTO_DETECTOR_PROBE_PAIRS,
+        descriptors.len().saturating_sub(1),
+    );
+    if probed_pairs == 0 {
+        return Ok(None);
+    }
+
+    let matcher = create_matcher(detector, options)?;
+    let mut total_matches = 0;
+    for idx in 0..probed_pairs {
+        let (matches, _) = find_matches(
+            matcher.as_ref(),
+            &descriptors[idx],
+            &descriptors[idx + 1],
+            options,
+        )?;
+        let (matches, _) =
+            filter_matches_geometric(&keypoints[idx], &keypoints[idx + 1], &matches)?;
+        total_matches += cap_matches(matches, options).len();
+    }
+
+    Ok(Some(total_matches as f32 / probed_pairs as f32))
+}
+
+/// Keypoints and descriptors of every image, along with the detector that found them.
+pub struct Features {
+    detector: Detector,
+    keypoints: Vec<cv::core::Vector<cv::core::KeyPoint>>,
+    descriptors: Vec<cv::core::Mat>,
+}
+
+pub fn compute_features(
+    images: &[Image],
+    image_paths: &[PathBuf],
+    options: &SfmOptions,
+) -> Result<Features> {
+    let stage = Stage::start("feature_extraction");
+    let mut detector = options.detector;
+    let (mut keypoints, mut descriptors) =
+        extract_features(images, image_paths, detector, options)?;
+
+    if options.auto_detector {
+        match probe_match_yield(detector, &keypoints, &descriptors, options)? {
+            Some(match_yield) if match_yield < AUTO_DETECTOR_MIN_MATCHES => {
+                let fallback = detector.fallback();
+                log::warn!(
+                    "{:?} only yielded {:.1} matches per pair (threshold is {}), trying {:?}",
+                    detector,
+                    match_yield,
+                    AUTO_DETECTOR_MIN_MATCHES,
+                    fallback
+                );
+                let (fallback_keypoints, fallback_descriptors) =
+                    extract_features(images, image_paths, fallback, options)?;
+                // Both probes use the same pairs, so there is always a fallback yield here
+                let fallback_yield = probe_match_yield(
+                    fallback,
+                    &fallback_keypoints,
+                    &fallback_descriptors,
+                    options,
+                )?
+                .unwrap_or(0.0);
+
+                if fallback_yield > match_yield {
+                    log::warn!(
+                        "{:?} yielded {:.1} matches per pair against {:.1} for {:?}, switching to it",
+                        fallback,
+                        fallback_yield,
+                        match_yield,
+                        detector
+                    );
+                    (keypoints, descriptors) = (fallback_keypoints, fallback_descriptors);
+                    detector = fallback;
+                } else {
+                    log::warn!(
+                        "{:?} did not do better ({:.1} matches per pair against {:.1}), keeping {:?}",
+                        fallback,
+                        fallback_yield,
+                        match_yield,
+                        detector
+                    );
+                }
+            }
+            Some(match_yield) => log::info!(
+                "{:?} yielded {:.1} matches per pair, keeping it",
+                detector,
+                match_yield
+            ),
+            None => log::info!("Not enough images to probe the detector's match yield"),
+        }
+    }
+
+    stage.finish();
+    stats::record_keypoints(
+        keypoints
+            .iter()
+            .map(|image_keypoints| image_keypoints.len()),
+    );
+
+    Ok(Features {
+        detector,
+        keypoints,
+        descriptors,
+    })
+}
+
+/// Rotation and translation of the right camera relative to the left one, estimated from the
+/// essential matrix of their matches. Returns `None` if there are too few matches, or too few of them
+/// agree on the relative pose.
+fn estimate_relative_pose(
+    matcher: &dyn Matcher,
+    features: &Features,
+    intrinsics: &[cv::core::Mat],
+    left_idx: usize,
+    right_idx: usize,
+    options: &SfmOptions,
+) -> Result<Option<(Mat3, Vec3)>> {
+    let (matches, _) = find_matches(
+        matcher,
+        &features.descriptors[left_idx],
+        &features.descriptors[right_idx],
+        options,
+    )?;
+    if matches.len() < MIN_POSE_MATCHES {
+        return Ok(None);
+    }
+
+    let mut points1 = Vector::<Point2f>::with_capacity(matches.len());
+    let mut points2 = Vector::<Point2f>::with_capacity(matches.len());
+    for img_match in &matches {
+        points1.push(
+            features.keypoints[left_idx]
+                .get(img_match.query_idx as usize)?
+                .pt(),
+        );
+        points2.push(
+            features.keypoints[right_idx]
+                .get(img_match.train_idx as usize)?
+                .pt(),
+        );
+    }
+
+    // Working with normalized coordinates allows both images to have different intrinsics
+    let mut normalized1 = cv::core::Mat::default();
+    let mut normalized2 = cv::core::Mat::default();
+    cv::calib3d::undistort_points_def(
+        &points1,
+        &mut normalized1,
+        &intrinsics[left_idx],
+        &cv::core::no_array(),
+    )?;
+    cv::calib3d::undistort_points_def(
+        &points2,
+        &mut normalized2,
+        &intrinsics[right_idx],
+        &cv::core::no_array(),
+    )?;
+
+    let identity = cv::core::Mat::eye(3, 3, cv::core::CV_64F)?.to_mat()?;
+    let focal = *intrinsics[left_idx].at_2d::<f32>(0, 0)?;
+    let mut inlier_mask = cv::core::Mat::default();
+    let essential = cv::calib3d::find_essential_mat(
+        &normalized1,
+        &normalized2,
+        &identity,
+        cv::calib3d::RANSAC,
+        0.999,
+        // 1 pixel, in normalized coordinates
+        1.0 / focal.abs() as f64,
+        1000,
+        &mut inlier_mask,
+    )?;
+    if essential.rows() < 3 {
+        return Ok(None);
+    }
+    // Degenerate configurations can yield several solutions stacked vertically, keep the first one
+    let mut essential_vals = Vec::with_capacity(9);
+    for row in 0..3 {
+        for col in 0..3 {
+            essential_vals.push(*essential.at_2d::<f64>(row, col)?);
+        }
+    }
+    let essential = cv::core::Mat::from_slice_rows_cols(&essential_vals, 3, 3)?;
+
+    let mut rotation = cv::core::Mat::default();
+    let mut translation = cv::core::Mat::default();
+    let inliers = cv::calib3d::recover_pose_estimated(
+        &essential,
+        &normalized1,
+        &normalized2,
+        &identity,
+        &mut rotation,
+        &mut translation,
+        &mut inlier_mask,
+    )?;
+    log::debug!(
+        "\t{} of {} matches agree with the recovered pose",
+        inliers,
+        matches.len()
+    );
+    if (inliers as usize) < MIN_POSE_MATCHES {
+        return Ok(None);
+    }
+
+    let rotation = pose::mat_values(&rotation)?;
+    let translation = pose::mat_values(&translation)?;
+    Ok(Some((
+        Mat3::from_cols_slice(&rotation).transpose(),
+        Vec3::from_slice(&translation),
+    )))
+}
+
+/// Estimates the camera poses from the images themselves, with the first camera at the origin. The
+/// pose of each camera relative to the previous one is recovered from their matches, then chained
+/// onto the previous camera's pose. The scale of the relative translations can't be recovered, so
+/// each step is taken to have a unit length.
+pub fn recover_poses(
+    features: &Features,
+    intrinsics: &[cv::core::Mat],
+    options: &SfmOptions,
+) -> Result<(Vec<cv::core::Mat>, Vec<Vec3>)> {
+    let stage = Stage::start("pose_recovery");
+    let matcher = create_matcher(features.detector, options)?;
+    log::info!("Recovering camera poses from consecutive images");
+
+    let mut rotation = Mat3::IDENTITY;
+    let mut translation = Vec3::ZERO;
+    let mut poses = Vec::with_capacity(intrinsics.len());
+    let mut camera_positions = Vec::with_capacity(intrinsics.len());
+    for idx in 0..intrinsics.len() {
+        if idx > 0 {
+            match estimate_relative_pose(
+                matcher.as_ref(),
+                features,
+                intrinsics,
+                idx - 1,
+                idx,
+                options,
+            )? {
+                Some((relative_rotation, relative_translation)) => {
+                    rotation = relative_rotation * rotation;
+                    translation = relative_rotation * translation + relative_translation;
+                }
+                None => log::warn!(
+                    "\tfailed to recover the pose of image {} relative to image {}, reusing the previous pose",
+                    idx,
+                    idx - 1
+                ),
+            }
+        }
+
+        poses.push(pose::projection_matrix(
+            &intrinsics[idx],
+            &rotation,
+            &translation,
+        )?);
+        camera_positions.push(-(rotation.transpose() * translation));
+    }
+    log::info!("Recovered {} camera poses", poses.len());
+    stage.finish();
+
+    Ok((poses, camera_positions))
+}
+
+/// The matched keypoints of two images, as pairs of an observation in `image_idx` and one in
+/// `other_idx`.
+fn pair_observations(
+    pair_matches: &HashMap<(usize, usize), Vector<DMatch>>,
+    image_idx: usize,
+    other_idx: usize,
+) -> Vec<(Observation, Observation)> {
+    let (left_idx, right_idx) = (image_idx.min(other_idx), image_idx.max(other_idx));
+    let Some(matches) = pair_matches.get(&(left_idx, right_idx)) else {
+        return vec![];
+    };
+
+    matches
+        .iter()
+        .map(|img_match| {
+            let left = (left_idx, img_match.query_idx as usize);
+            let right = (right_idx, img_match.train_idx as usize);
+            match left_idx == image_idx {
+                true => (left, right),
+                false => (right, left),
+            }
+        })
+        .collect()
+}
+
+/// The registered cameras of an incremental reconstruction, and the points they see.
+struct IncrementalState {
+    /// Rotation and translation of each image's camera, once it is registered.
+    extrinsics: Vec<Option<(Mat3, Vec3)>>,
+    /// Projection matrices of the registered cameras, the others are left empty.
+    projections: Vec<cv::core::Mat>,
+    points: Vec<Vec3>,
+    /// The point each keypoint is a view of.
+    point_indices: HashMap<Observation, usize>,
+}
+
+impl IncrementalState {
+    fn new(image_count: usize) -> Self {
+        Self {
+            extrinsics: vec![None; image_count],
+            projections: vec![cv::core::Mat::default(); image_count],
+            points: vec![],
+            point_indices: HashMap::new(),
+        }
+    }
+
+    fn register(
+        &mut self,
+        image_idx: usize,
+        rotation: Mat3,
+        translation: Vec3,
+        intrinsics: &cv::core::Mat,
+    ) -> Result<()> {
+        self.projections[image_idx] = pose::projection_matrix(intrinsics, &rotation, &translation)?;
+        self.extrinsics[image_idx] = Some((rotation, translation));
+
+        Ok(())
+    }
+
+    fn is_registered(&self, image_idx: usize) -> bool {
+        self.extrinsics[image_idx].is_some()
+    }
+
+    fn is_in_front(&self, image_idx: usize, position: Vec3) -> bool {
+        self.extrinsics[image_idx]
+            .is_some_and(|(rotation, translation)| (rotation * position + translation).z > 0.0)
+    }
+
+    /// The keypoints of the image that are matched to a view of a point in a registered image, along
+    /// with that point, sorted by keypoint.
+    fn correspondences(
+        &self,
+        image_idx: usize,
+        pair_matches: &HashMap<(usize, usize), Vector<DMatch>>,
+    ) -> Vec<(usize, usize)> {
+        let mut correspondences = HashMap::new();
+        for other_idx in (0..self.extrinsics.len()).filter(|&idx| self.is_registered(idx)) {
+            for ((_, keypoint_idx), other_observation) in
+                pair_observations(pair_matches, image_idx, other_idx)
+            {
+                if let Some(&point_idx) = self.point_indices.get(&other_observation) {
+                    correspondences.entry(keypoint_idx).or_insert(point_idx);
+                }
+            }
+        }
+
+        correspondences.into_iter().sorted_unstable().collect()
+    }
+
+    /// Triangulates the matches between the newly registered image and the other registered images
+    /// that are not views of a point yet. Returns the number of new points.
+    fn triangulate_new_points(
+        &mut self,
+        image_idx: usize,
+        images: &[Image],
+        keypoints: &[cv::core::Vector<cv::core::KeyPoint>],
+        pair_matches: &HashMap<(usize, usize), Vector<DMatch>>,
+        max_reproj_error: f32,
+    ) -> Result<usize> {
+        let mut new_points = 0;
+        for other_idx in 0..self.extrinsics.len() {
+            if other_idx == image_idx || !self.is_registered(other_idx) {
+                continue;
+            }
+
+            for (observation, other_observation) in
+                pair_observations(pair_matches, image_idx, other_idx)
+            {
+                match (
+                    self.point_indices.get(&observation).copied(),
+                    self.point_indices.get(&other_observation).copied(),
+                ) {
+                    (Some(_), Some(_)) => {}
+                    // A new view of a known point
+                    (Some(point_idx), None) => {
+                        self.point_indices.insert(other_observation, point_idx);
+                    }
+                    (None, Some(point_idx)) => {
+                        self.point_indices.insert(observation, point_idx);
+                    }
+                    (None, None) => {
+                        // Only the positions of these points are used
+                        let Some(point) = triangulate_track(
+                            images,
+                            &self.projections,
+                            keypoints,
+                            &[observation, other_observation],
+                            ColorCombination::Mean,
+                        )?
+                        else {
+                            continue;
+                        };
+                        if point.reproj_error.unwrap_or(0.0) > max_reproj_error
+                            || !self.is_in_front(image_idx, point.position)
+                            || !self.is_in_front(other_idx, point.position)
+                        {
+                            continue;
+                        }
+
+                        self.points.push(point.position);
+                        self.point_indices
+                            .insert(observation, self.points.len() - 1);
+                        self.point_indices
+                            .insert(other_observation, self.points.len() - 1);
+                        new_points += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(new_points)
+    }
+}
+
+/// Pose of a camera from the correspondences between its keypoints and known points, estimated with
+/// a RANSAC PnP. Returns `None` if too few correspondences are inliers, and the inliers otherwise.
+fn solve_pnp(
+    points: &[Vec3],
+    correspondences: &[(usize, usize)],
+    keypoints: &cv::core::Vector<cv::core::KeyPoint>,
+    intrinsics: &cv::core::Mat,
+    max_reproj_error: f32,
+) -> Result<Option<(Mat3, Vec3, Vec<(usize, usize)>)>> {
+    let mut object_points = Vector::<cv::core::Point3f>::with_capacity(correspondences.len());
+    let mut image_points = Vector::<Point2f>::with_capacity(correspondences.len());
+    for &(keypoint_idx, point_idx) in correspondences {
+        let position = points[point_idx];
+        object_points.push(cv::core::Point3f::new(position.x, position.y, position.z));
+        image_points.push(keypoints.get(keypoint_idx)?.pt());
+    }
+
+    let mut rotation = cv::core::Mat::default();
+    let mut translation = cv::core::Mat::default();
+    let mut inliers = cv::core::Mat::default();
+    let found = cv::calib3d::solve_pnp_ransac(
+        &object_points,
+        &image_points,
+        intrinsics,
+        &cv::core::no_array(),
+        &mut rotation,
+        &mut translation,
+        false,
+        1000,
+        max_reproj_error,
+        0.99,
+        &mut inliers,
+        cv::calib3d::SOLVEPNP_ITERATIVE,
+    )?;
+    if !found || (inliers.rows() as usize) < MIN_POSE_MATCHES {
+        return Ok(None);
+    }
+    let inliers = (0..inliers.rows())
+        .map(|row| Ok(correspondences[*inliers.at::<i32>(row)? as usize]))
+        .collect::<Result<Vec<_>>>()?;
+
+    // PnP gives the rotation as a Rodrigues vector
+    let mut rotation_matrix = cv::core::Mat::default();
+    cv::calib3d::rodrigues_def(&rotation, &mut rotation_matrix)?;
+    let rotation = pose::mat_values(&rotation_matrix)?;
+    let translation = pose::mat_values(&translation)?;
+    Ok(Some((
+        Mat3::from_cols_slice(&rotation).transpose(),
+        Vec3::from_slice(&translation),
+        inliers,
+    )))
+}
+
+/// Estimates the camera poses incrementally. The pair of images with the most matches is
+/// reconstructed first, with the first camera at the origin and a unit baseline. Then, the image that
+/// sees the most of the triangulated points (the best next view) is registered against them by PnP,
+/// and its matches with the registered images are triangulated, until no image can be registered.
+/// Unlike with `recover_poses`, the cameras are all positioned relative to the same points, so the
+/// errors don't accumulate along the sequence. Images that can't be registered reuse the pose of the
+/// closest registered image.
+///
+/// The images with `known_extrinsics` (e.g. those of a resumed reconstruction) are registered first
+/// with these poses, in place of the initial pair, and the other images are registered against them.
+pub fn register_incremental(
+    images: &[Image],
+    features: &Features,
+    intrinsics: &[cv::core::Mat],
+    known_extrinsics: &[Option<(Mat3, Vec3)>],
+    options: &SfmOptions,
+) -> Result<(Vec<cv::core::Mat>, Vec<Vec3>)> {
+    let stage = Stage::start("incremental_registration");
+    let matcher = create_matcher(features.detector, options)?;
+    log::info!("Matching every pair of images for the incremental reconstruction");
+
+    let image_pairs = (0..images.len()).combinations(2).collect::<Vec<_>>();
+    let progress_bar = progress::bar(image_pairs.len(), "matching");
+    let mut pair_matches = HashMap::new();
+    for pair in image_pairs {
+        progress_bar.inc(1);
+        let (left_idx, right_idx) = (pair[0], pair[1]);
+        let (matches, _) = find_matches(
+            matcher.as_ref(),
+            &features.descriptors[left_idx],
+            &features.descriptors[right_idx],
+            options,
+        )?;
+        let (matches, _) = filter_matches_geometric(
+            &features.keypoints[left_idx],
+            &features.keypoints[right_idx],
+            &matches,
+        )?;
+        pair_matches.insert((left_idx, right_idx), cap_matches(matches, options));
+    }
+    progress_bar.finish_and_clear();
+
+    let max_reproj_error = options
+        .max_reproj_error
+        .unwrap_or(INCREMENTAL_MAX_REPROJ_ERROR);
+    let mut state = IncrementalState::new(images.len());
+
+    let known_images = known_extrinsics
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, extrinsics)| extrinsics.map(|extrinsics| (idx, extrinsics)))
+        .collect::<Vec<_>>();
+    let mut initialized = false;
+    if !known_images.is_empty() {
+        if known_images.len() < 2 {
+            bail!("At least two images of the reconstruction must be known to register the others");
+        }
+        let mut point_count = 0;
+        for &(image_idx, (rotation, translation)) in &known_images {
+            state.register(image_idx, rotation, translation, &intrinsics[image_idx])?;
+            point_count += state.triangulate_new_points(
+                image_idx,
+                images,
+                &features.keypoints,
+                &pair_matches,
+                max_reproj_error,
+            )?;
+        }
+        log::info!(
+            "Initialized the reconstruction from {} known images ({} points)",
+            known_images.len(),
+            point_count
+        );
+        initialized = true;
+    } else {
+        // The initial pair is the one with the most matches whose relative pose can be recovered
+        let mut candidate_pairs = pair_matches
+            .iter()
+            .map(|(&pair, matches)| (matches.len(), pair))
+            .collect::<Vec<_>>();
+        candidate_pairs
+            .sort_unstable_by_key(|&(match_count, pair)| (std::cmp::Reverse(match_count), pair));
+        for (match_count, (left_idx, right_idx)) in candidate_pairs {
+            if match_count < MIN_POSE_MATCHES {
+                break;
+            }
+            let Some((rotation, translation)) = estimate_relative_pose(
+                matcher.as_ref(),
+                features,
+                intrinsics,
+                left_idx,
+                right_idx,
+                options,
+            )?
+            else {
+                continue;
+            };
+
+            state.register(left_idx, Mat3::IDENTITY, Vec3::ZERO, &intrinsics[left_idx])?;
+            state.register(right_idx, rotation, translation, &intrinsics[right_idx])?;
+            let point_count = state.triangulate_new_points(
+                right_idx,
+                images,
+                &features.keypoints,
+                &pair_matches,
+                max_reproj_error,
+            )?;
+            log::info!(
+                "Initialized the reconstruction from images {} and {} ({} matches, {} points)",
+                left_idx,
+                right_idx,
+                match_count,
+                point_count
+            );
+            initialized = true;
+            break;
+        }
+    }
+    if !initialized {
+        bail!("Failed to find a pair of images to initialize the reconstruction from");
+    }
+
+    let mut failed = vec![false; images.len()];
+    loop {
+        let next_view = (0..images.len())
+            .filter(|&idx| !state.is_registered(idx) && !failed[idx])
+            .map(|idx| (idx, state.correspondences(idx, &pair_matches)))
+            .max_by_key(|(idx, correspondences)| (correspondences.len(), std::cmp::Reverse(*idx)));
+        let Some((image_idx, correspondences)) = next_view else {
+            break;
+        };
+        if correspondences.len() < MIN_POSE_MATCHES {
+            // No other image sees enough points either
+            break;
+        }
+
+        match solve_pnp(
+            &state.points,
+            &correspondences,
+            &features.keypoints[image_idx],
+            &intrinsics[image_idx],
+            max_reproj_error,
+        )? {
+            Some((rotation, translation, inliers)) => {
+                state.register(image_idx, rotation, translation, &intrinsics[image_idx])?;
+                for &(keypoint_idx, point_idx) in &inliers {
+                    state
+                        .point_indices
+                        .insert((image_idx, keypoint_idx), point_idx);
+                }
+                let point_count = state.triangulate_new_points(
+                    image_idx,
+                    images,
+                    &features.keypoints,
+                    &pair_matches,
+                    max_reproj_error,
+                )?;
+                log::info!(
+                    "\tregistered image {} from {} of {} points, triangulated {} new points",
+                    image_idx,
+                    inliers.len(),
+                    correspondences.len(),
+                    point_count
+                );
+            }
+            None => {
+                log::warn!(
+                    "\tfailed to register image {} from {} points",
+                    image_idx,
+                    correspondences.len()
+                );
+                failed[image_idx] = true;
+            }
+        }
+    }
+
+    let registered = (0..images.len())
+        .filter(|&idx| state.is_registered(idx))
+        .collect::<Vec<_>>();
+    let mut poses = Vec::with_capacity(images.len());
+    let mut camera_positions = Vec::with_capacity(images.len());
+    for idx in 0..images.len() {
+        let source_idx = *registered
+            .iter()
+            .min_by_key(|&&other_idx| other_idx.abs_diff(idx))
+            .expect("The initial pair is registered");
+        if source_idx != idx {
+            log::warn!(
+                "\tfailed to register image {}, reusing the pose of image {}",
+                idx,
+                source_idx
+            );
+        }
+
+        let (rotation, translation) =
+            state.extrinsics[source_idx].expect("The image is registered");
+        poses.push(pose::projection_matrix(
+            &intrinsics[idx],
+            &rotation,
+            &translation,
+        )?);
+        camera_positions.push(-(rotation.transpose() * translation));
+    }
+    log::info!(
+        "Registered {} of {} images from {} points",
+        registered.len(),
+        images.len(),
+        state.points.len()
+    );
+    stage.finish();
+
+    Ok((poses, camera_positions))
+}
+
+/// The read-only data shared by the threads matching and triangulating the image pairs.
+struct PairContext<'a> {
+    images: &'a [Image],
+    keypoints: &'a [Vector<cv::core::KeyPoint>],
+    descriptors: &'a [cv::core::Mat],
+    poses: &'a [cv::core::Mat],
+    detector: Detector,
+    /// The extrinsics of the cameras for `--cheirality-check`, empty without it.
+    extrinsics: &'a [(Mat3, Vec3)],
+    /// Whether the pairs are triangulated, rather than only matched to build the tracks.
+    triangulate: bool,
+    options: &'a SfmOptions,
+}
+
+/// What matching and triangulating a pair of images produced.
+struct PairResult {
+    counts: MatchCounts,
+    matching_time: std::time::Duration,
+    matches: Vector<DMatch>,
+    points: Vec<render_state::Point>,
+}
+
+/// Matches a pair of images, and triangulates the matches unless they are only used for the tracks.
+fn process_pair(
+    context: &PairContext,
+    matcher: &dyn Matcher,
+    left_idx: usize,
+    right_idx: usize,
+) -> Result<PairResult> {
+    let PairContext {
+        images,
+        keypoints,
+        descriptors,
+        poses,
+        detector,
+        extrinsics,
+        triangulate,
+        options,
+    } = *context;
+
+    let matching_start = std::time::Instant::now();
+    let (matches, mut pair_counts) = find_matches(
+        matcher,
+        &descriptors[left_idx],
+        &descriptors[right_idx],
+        options,
+    )?;
+    let matching_time = matching_start.elapsed();
+    timings::record("find_matches", matching_time);
+    let (matches, fundamental) =
+        filter_matches_geometric(&keypoints[left_idx], &keypoints[right_idx], &matches)?;
+    pair_counts.geometric = matches.len();
+    let matches = cap_matches(matches, options);
+    pair_counts.capped = matches.len();
+    log::debug!("\t{}", pair_counts);
+    let matches = match fundamental.filter(|_| options.guided_matching) {
+        Some(fundamental) => guided_matching(
+            &PairFeatures {
+                keypoints1: &keypoints[left_idx],
+                keypoints2: &keypoints[right_idx],
+                descriptors1: &descriptors[left_idx],
+                descriptors2: &descriptors[right_idx],
+            },
+            &fundamental,
+            &matches,
+            descriptor_norm(detector),
+            options,
+        )?,
+        None => matches,
+    };
+
+    // Each pair writes its own image, so the threads don't need to coordinate
+    let debug_dir = options
+        .debug_matches
+        .as_ref()
+        .filter(|_| options.is_debugged_pair(matches.len()));
+    if let Some(debug_dir) = debug_dir {
+        write_match_image(
+            &debug_dir.join(format!("{}-{}.png", left_idx, right_idx)),
+            matcher,
+            [&images[left_idx], &images[right_idx]],
+            &PairFeatures {
+                keypoints1: &keypoints[left_idx],
+                keypoints2: &keypoints[right_idx],
+                descriptors1: &descriptors[left_idx],
+                descriptors2: &descriptors[right_idx],
+            },
+            &matches,
+            options.ratio_threshold,
+        )?;
+    }
+
+    if !triangulate {
+        // The points are only triangulated once all the tracks are known
+        events::emit(Event::PairProcessed {
+            left: left_idx,
+            right: right_idx,
+            matches: matches.len(),
+            points: 0,
+        });
+        return Ok(PairResult {
+            counts: pair_counts,
+            matching_time,
+            matches,
+            points: vec![],
+        });
+    }
+
+    let mut pair_points = timings::time("triangulate_points", || {
+        triangulate_points(
+            &PairObservations {
+                image1: &images[left_idx],
+                image2: &images[right_idx],
+                keypoints1: &keypoints[left_idx],
+                keypoints2: &keypoints[right_idx],
+                matches: &matches,
+            },
+            poses[left_idx].clone(),
+            poses[right_idx].clone(),
+            options.color_mode,
+        )
+    })?;
+    discard_inaccurate_points(&mut pair_points, options);
+    if options.cheirality_check {
+        let point_count = pair_points.len();
+        pair_points = discard_points_behind(
+            pair_points
+                .into_iter()
+                .map(|point| (point, [left_idx, right_idx])),
+            extrinsics,
+        );
+        log::debug!(
+            "\tdiscarded {} of {} points behind the cameras",
+            point_count - pair_points.len(),
+            point_count
+        );
+    }
+    for point in &mut pair_points {
+        point.source_pair = Some((left_idx, right_idx));
+    }
+    events::emit(Event::PairProcessed {
+        left: left_idx,
+        right: right_idx,
+        matches: matches.len(),
+        points: pair_points.len(),
+    });
+
+    Ok(PairResult {
+        counts: pair_counts,
+        matching_time,
+        matches,
+        points: pair_points,
+    })
+}
+
+/// Receives the points of each batch of pairs as soon as they are triangulated.
+pub type PointSink<'a> = &'a mut dyn FnMut(Vec<render_state::Point>) -> Result<()>;
+
+/// Triangulates the point cloud from the images' features and camera poses. The poses are refined in
+/// place by the bundle adjustment, if enabled.
+///
+/// With a `sink`, the pairs are processed in small batches whose points are passed to the sink (in
+/// pair order) instead of being accumulated, so that the memory used by the points stays bounded.
+/// The returned cloud is then empty. The options that need the whole cloud can't be used with a
+/// sink.
+///
+/// The pairs of `resumed_images` are skipped, their points being part of the resumed cloud already.
+pub fn generate_point_cloud(
+    images: Vec<Image>,
+    features: Features,
+    poses: &mut [cv::core::Mat],
+    options: &SfmOptions,
+    resumed_images: &[bool],
+    mut sink: Option<PointSink<'_>>,
+) -> Result<Vec<render_state::Point>> {
+    if images.len() != poses.len() {
+        bail!(
+            "Found {} images but {} camera poses, each image needs exactly one pose",
+            images.len(),
+            poses.len()
+        );
+    }
+    if sink.is_some()
+        && (options.pair_mode == PairMode::Tracks
+            || options.denoise
+            || options.voxel_size.is_some()
+            || options.normals)
+    {
+        bail!(
+            "Streaming the points can't be combined with --pair-mode tracks, --denoise, \
+            --voxel-size or --normals, which need the whole cloud"
+        );
+    }
+
+    let Features {
+        detector,
+        keypoints,
+        descriptors,
+    } = features;
+
+    let matcher = create_matcher(detector, options)?;
+    log::info!("Matching descriptors using the {} backend", matcher.name());
+    log::debug!(
+        "\tusing a ratio test threshold of {}",
+        options.ratio_threshold
+    );
+
+    if let Some(debug_dir) = &options.debug_matches {
+        std::fs::create_dir_all(debug_dir).with_context(|| {
+            format!(
+                "Failed to create debug matches directory {}",
+                debug_dir.to_string_lossy()
+            )
+        })?;
+        log::info!(
+            "Writing debug match images to {}",
+            debug_dir.to_string_lossy()
+        );
+    }
+
+    let mut points = vec![];
+    log::info!("Generating points");
+    let stage = Stage::start("triangulation");
+    let mut matching_time = std::time::Duration::ZERO;
+    let mut matched_descriptors = 0;
+    let mut match_counts = MatchCounts::default();
+    let mut index_pairs: Vec<(usize, usize)> = match options.pair_mode {
+        PairMode::Sequential => (0..images.len()).tuple_windows().collect(),
+        PairMode::Exhaustive | PairMode::Tracks => (0..images.len())
+            .combinations(2)
+            .map(|pair| (pair[0], pair[1]))
+            .collect(),
+    };
+    let is_resumed = |idx: usize| resumed_images.get(idx).copied().unwrap_or(false);
+    index_pairs.retain(|&(left_idx, right_idx)| !(is_resumed(left_idx) && is_resumed(right_idx)));
+    let mut track_builder = (options.pair_mode == PairMode::Tracks).then(TrackBuilder::default);
+    if options.bundle_adjust && track_builder.is_none() {
+        log::warn!("Bundle adjustment needs the tracks of --pair-mode tracks, skipping it");
+    }
+    let extrinsics = match options.cheirality_check {
+        true => camera_extrinsics(poses)?,
+        false => vec![],
+    };
+    let context = PairContext {
+        images: &images,
+        keypoints: &keypoints,
+        descriptors: &descriptors,
+        poses,
+        detector,
+        extrinsics: &extrinsics,
+        triangulate: track_builder.is_none(),
+        options,
+    };
+    let progress_bar = progress::bar(index_pairs.len(), "triangulation");
+    let batch_size = match sink {
+        Some(_) => rayon::current_num_threads() * STREAMED_PAIRS_PER_THREAD,
+        None => index_pairs.len().max(1),
+    };
+    let mut streamed_points = 0;
+    for (batch_idx, batch) in index_pairs.chunks(batch_size).enumerate() {
+        // Each thread gets its own matcher, as they are not all thread-safe
+        let pair_results = batch
+            .par_iter()
+            .enumerate()
+            .map_init(
+                || create_matcher(detector, options),
+                |thread_matcher, (idx, &(left_idx, right_idx))| {
+                    let thread_matcher = thread_matcher
+                        .as_ref()
+                        .map_err(|error| anyhow!("Failed to create matcher: {:#}", error))?;
+                    let pair_idx = batch_idx * batch_size + idx;
+                    progress_bar.inc(1);
+                    log::info!(
+                        "\t[{}/{}] matching between {} and {}",
+                        pair_idx + 1,
+                        index_pairs.len(),
+                        left_idx,
+                        right_idx
+                    );
+                    // The random number generator is per thread, seeding it for each pair keeps
+                    // the results independent of which thread processes it
+                    if let Some(seed) = options.seed {
+                        cv::core::set_rng_seed(seed.wrapping_add(pair_idx as u64) as i32)?;
+                    }
+
+                    process_pair(&context, thread_matcher.as_ref(), left_idx, right_idx)
+                },
+            )
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut batch_points = vec![];
+        for (&(left_idx, right_idx), pair_result) in batch.iter().zip(pair_results) {
+            matching_time += pair_result.matching_time;
+            matched_descriptors += descriptors[left_idx].rows() as usize;
+            match_counts += pair_result.counts;
+            stats::record_pair(PairStats {
+                left: left_idx,
+                right: right_idx,
+                candidates: pair_result.counts.candidates,
+                ratio_test: pair_result.counts.ratio_test,
+                cross_check: pair_result.counts.cross_check,
+                geometric: pair_result.counts.geometric,
+                capped: pair_result.counts.capped,
+                points: pair_result.points.len(),
+            });
+            if let Some(track_builder) = &mut track_builder {
+                for img_match in &pair_result.matches {
+                    track_builder.add_match(
+                        (left_idx, img_match.query_idx as usize),
+                        (right_idx, img_match.train_idx as usize),
+                    );
+                }
+            }
+            batch_points.extend(pair_result.points);
+        }
+        match &mut sink {
+            Some(sink) => {
+                let batch_points = discard_distant_points(batch_points, poses, options)?;
+                streamed_points += batch_points.len();
+                sink(batch_points)?;
+            }
+            None => points.extend(batch_points),
+        }
+    }
+    progress_bar.finish_and_clear();
+    if sink.is_some() {
+        stage.finish();
+        log::info!("Matches over all pairs: {}", match_counts);
+        log::info!("Streamed {} points", streamed_points);
+        events::emit(Event::Summary {
+            images: images.len(),
+            points: streamed_points,
+        });
+        return Ok(vec![]);
+    }
+    if let Some(track_builder) = track_builder {
+        let tracks = track_builder.build();
+        let (triangulated_tracks, mut track_points): (Vec<_>, Vec<_>) =
+            timings::time("triangulate_tracks", || {
+                tracks
+                    .iter()
+                    .filter_map(|track| {
+                        triangulate_track(&images, poses, &keypoints, track, options.color_mode)
+                            .map(|point| point.map(|point| (track.as_slice(), point)))
+                            .transpose()
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })?
+            .into_iter()
+            .unzip();
+        if options.bundle_adjust {
+            let stage = Stage::start("bundle_adjustment");
+            bundle_adjustment::bundle_adjust(
+                poses,
+                &keypoints,
+                &triangulated_tracks,
+                &mut track_points,
+                options.bundle_adjust_iterations,
+            )?;
+            stage.finish();
+        }
+        if options.cheirality_check {
+            // The bundle adjustment moves the cameras
+            let extrinsics = camera_extrinsics(poses)?;
+            let point_count = track_points.len();
+            track_points = discard_points_behind(
+                track_points.into_iter().zip(
+                    triangulated_tracks
+                        .iter()
+                        .map(|track| track.iter().map(|&(image_idx, _)| image_idx)),
+                ),
+                &extrinsics,
+            );
+            log::debug!(
+                "\tdiscarded {} of {} points behind the cameras",
+                point_count - track_points.len(),
+                point_count
+            );
+        }
+        discard_inaccurate_points(&mut track_points, options);
+        points = track_points;
+    }
+    points = discard_distant_points(points, poses, options)?;
+    stage.finish();
+    log::info!(
+        "Matched {} descriptors in {:.2}s ({:.0} descriptors/s) using the {} backend",
+        matched_descriptors,
+        matching_time.as_secs_f64(),
+        matched_descriptors as f64 / matching_time.as_secs_f64().max(f64::EPSILON),
+        matcher.name()
+    );
+    log::info!("Matches over all pairs: {}", match_counts);
+    log::info!("Generated {} points", points.len());
+    log_reprojection_errors(&points);
+
+    if options.denoise {
+        let stage = Stage::start("denoise");
+        points = filters::remove_statistical_outliers(
+            points,
+            options.denoise_neighbors,
+            options.denoise_std_ratio,
+        );
+        stage.finish();
+    }
+    if let Some(voxel_size) = options.voxel_size {
+        if voxel_size <= 0.0 {
+            bail!("The voxel size must be positive, got {}", voxel_size);
+        }
+        let stage = Stage::start("voxel_downsample");
+        points = filters::voxel_downsample(points, voxel_size);
+        stage.finish();
+    }
+    if options.normals {
+        let stage = Stage::start("normals");
+        let camera_centers = poses
+            .iter()
+            .zip(&images)
+            .map(|(pose, image)| -> Result<Vec3> {
+                Ok(pose::camera_view(pose, image.size()?)?.center)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        filters::estimate_normals(&mut points, options.normal_neighbors, &camera_centers)?;
+        stage.finish();
+    }
+    match render_state::CloudStats::of(&points) {
+        Some(stats) => log::info!("Point cloud: {}", stats),
+        None => log::warn!("The point cloud is empty"),
+    }
+    events::emit(Event::Summary {
+        images: images.len(),
+        points: points.len(),
+    });
+
+    Ok(points)
+}
+
+/// Matches and triangulates a single pair of images, for debugging the core of the pipeline in
+/// isolation. The visualization of the matches is written to `matches_image` if given.
+pub fn triangulate_two_views(
+    images: &[Image; 2],
+    features: Features,
+    poses: &[cv::core::Mat; 2],
+    options: &SfmOptions,
+    matches_image: Option<&Path>,
+) -> Result<Vec<render_state::Point>> {
+    let Features {
+        detector,
+        keypoints,
+        descriptors,
+    } = features;
+    let matcher = create_matcher(detector, options)?;
+
+    let (matches, mut counts) =
+        find_matches(matcher.as_ref(), &descriptors[0], &descriptors[1], options)?;
+    let (matches, _) = filter_matches_geometric(&keypoints[0], &keypoints[1], &matches)?;
+    counts.geometric = matches.len();
+    let matches = cap_matches(matches, options);
+    counts.capped = matches.len();
+    log::info!("Matches: {}", counts);
+
+    if let Some(matches_image) = matches_image {
+        write_match_image(
+            matches_image,
+            matcher.as_ref(),
+            [&images[0], &images[1]],
+            &PairFeatures {
+                keypoints1: &keypoints[0],
+                keypoints2: &keypoints[1],
+                descriptors1: &descriptors[0],
+                descriptors2: &descriptors[1],
+            },
+            &matches,
+            options.ratio_threshold,
+        )?;
+        log::info!("Wrote the matches to {}", matches_image.to_string_lossy());
+    }
+
+    let mut points = triangulate_points(
+        &PairObservations {
+            image1: &images[0],
+            image2: &images[1],
+            keypoints1: &keypoints[0],
+            keypoints2: &keypoints[1],
+            matches: &matches,
+        },
+        poses[0].clone(),
+        poses[1].clone(),
+        options.color_mode,
+    )?;
+    discard_inaccurate_points(&mut points, options);
+    if options.cheirality_check {
+        points = discard_points_behind(
+            points.into_iter().map(|point| (point, [0, 1])),
+            &camera_extrinsics(poses)?,
+        );
+    }
+    points = discard_distant_points(points, poses, options)?;
+    for point in &mut points {
+        point.source_pair = Some((0, 1));
+    }
+    log::info!("Triangulated {} points", points.len());
+    log_reprojection_errors(&points);
+
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Largest distance between a triangulated point and the original one.
+    const TOLERANCE: f32 = 1e-3;
+
+    /// Two 3x4 `CV_32F` projection matrices P = K * [R|t] sharing the same intrinsics: the first
+    /// camera is at the origin looking down +Z, the second is one unit to its right and turned
+    /// slightly towards it.
+    fn synthetic_cameras() -> Result<(Mat, Mat)> {
+        let k = Mat::from_slice_rows_cols(
+            &[800.0f32, 0.0, 320.0, 0.0, 800.0, 240.0, 0.0, 0.0, 1.0],
+            3,
+            3,
+        )?;
+        let camera1 = pose::projection_matrix(&k, &Mat3::IDENTITY, &Vec3::ZERO)?;
+
+        let rotation = Mat3::from_rotation_y(-0.1);
+        let center = Vec3::new(1.0, 0.0, 0.0);
+        let camera2 = pose::projection_matrix(&k, &rotation, &(-(rotation * center)))?;
+
+        Ok((camera1, camera2))
+    }
+
+    fn synthetic_points() -> Vec<Vec3> {
+        (-2..=2)
+            .flat_map(|x| (-2..=2).map(move |y| (x, y)))
+            .map(|(x, y)| Vec3::new(x as f32 * 0.5, y as f32 * 0.5, 5.0 + (x + y) as f32 * 0.25))
+            .collect()
+    }
+
+    fn project(camera: &Mat, point: Vec3) -> Result<Point2f> {
+        let mut projected = [0.0; 3];
+        for (row, value) in projected.iter_mut().enumerate() {
+            let row = row as i32;
+            *value = *camera.at_2d::<f32>(row, 0)? * point.x
+                + *camera.at_2d::<f32>(row, 1)? * point.y
+                + *camera.at_2d::<f32>(row, 2)? * point.z
+                + *camera.at_2d::<f32>(row, 3)?;
+        }
+
+        Ok(Point2f::new(
+            projected[0] / projected[2],
+            projected[1] / projected[2],
+        ))
+    }
+
+    #[test]
+    fn triangulates_synthetic_points() -> Result<()> {
+        let (camera1, camera2) = synthetic_cameras()?;
+        let poses: Vector<Mat> = vec![camera1.clone(), camera2.clone()].into();
+
+        for expected in synthetic_points() {
+            // One 2x1 matrix of pixel coordinates per view, in the order of the poses
+            let mut points_2d = Vector::<Mat>::new();
+            for camera in [&camera1, &camera2] {
+                let pixel = project(camera, expected)?;
+                points_2d.push(Mat::from_slice_rows_cols(&[pixel.x, pixel.y], 2, 1)?);
+            }
+
+            // The result is a 3x1 CV_64F matrix of the point in world coordinates
+            let mut point_3d = Mat::default();
+            cv::sfm::triangulate_points(&points_2d, &poses, &mut point_3d)?;
+            assert_eq!(point_3d.typ(), cv::core::CV_64F);
+            assert_eq!(point_3d.total(), 3);
+            let actual = Vec3::new(
+                *point_3d.at::<f64>(0)? as f32,
+                *point_3d.at::<f64>(1)? as f32,
+                *point_3d.at::<f64>(2)? as f32,
+            );
+
+            assert!(
+                actual.distance(expected) < TOLERANCE,
+                "expected {}, got {}",
+                expected,
+                actual
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn triangulates_all_matches_at_once() -> Result<()> {
+        let (camera1, camera2) = synthetic_cameras()?;
+        let image = Mat::new_rows_cols_with_default(
+            480,
+            640,
+            cv::core::CV_8UC3,
+            cv::core::Scalar::all(0.0),
+        )?;
+        let expected = synthetic_points();
+        let keypoints = |camera: &Mat| -> Result<Vector<cv::core::KeyPoint>> {
+            expected
+                .iter()
+                .map(|point| {
+                    let pixel = project(camera, *point)?;
+                    Ok(cv::core::KeyPoint::new_coords(
+                        pixel.x, pixel.y, 1.0, -1.0, 0.0, 0, -1,
+                    )?)
+                })
+                .collect()
+        };
+        let matches = (0..expected.len() as i32)
+            .map(|idx| DMatch::new(idx, idx, 0.0))
+            .collect::<cv::Result<Vector<_>>>()?;
+
+        let points = triangulate_points(
+            &PairObservations {
+                image1: &image,
+                image2: &image,
+                keypoints1: &keypoints(&camera1)?,
+                keypoints2: &keypoints(&camera2)?,
+                matches: &matches,
+            },
+            camera1.clone(),
+            camera2.clone(),
+            ColorCombination::Mean,
+        )?;
+
+        // The points are returned in the order of the matches
+        assert_eq!(points.len(), expected.len());
+        for (point, expected) in points.iter().zip(&expected) {
+            assert!(
+                point.position.distance(*expected) < TOLERANCE,
+                "expected {}, got {}",
+                expected,
+                point.position
+            );
+            assert!(point.reproj_error.unwrap() < TOLERANCE);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn exact_projections_have_no_reprojection_error() -> Result<()> {
+        let (camera1, camera2) = synthetic_cameras()?;
+        for point in synthetic_points() {
+            for camera in [&camera1, &camera2] {
+                let pixel = project(camera, point)?;
+                assert!(reprojection_error(camera, &point, &pixel)? < TOLERANCE);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn combines_view_colors() {
+        let colors = vec![
+            Vec3::new(0.2, 0.9, 0.0),
+            Vec3::new(1.0, 0.1, 0.3),
+            Vec3::new(0.3, 0.2, 0.6),
+        ];
+
+        assert!(ColorCombination::Mean
+            .combine(colors.clone())
+            .abs_diff_eq(Vec3::new(0.5, 0.4, 0.3), 1e-6));
+        assert_eq!(ColorCombination::First.combine(colors.clone()), colors[0]);
+        // Each channel has its own median, the overexposed view doesn't pull the color up
+        assert_eq!(
+            ColorCombination::Median.combine(colors.clone()),
+            Vec3::new(0.3, 0.2, 0.3)
+        );
+        assert!(ColorCombination::Median
+            .combine(colors[..2].to_vec())
+            .abs_diff_eq(ColorCombination::Mean.combine(colors[..2].to_vec()), 1e-6));
+    }
+
+    #[test]
+    fn root_sift_rows_have_unit_norm() -> Result<()> {
+        let values = [
+            [0.0f32, 12.0, 3.0, 40.0, 7.0, 0.0, 1.0, 90.0],
+            [255.0, 255.0, 255.0, 255.0, 255.0, 255.0, 255.0, 255.0],
+            [0.0, 0.0, 0.0, 0.0, 0.5, 0.0, 0.0, 0.0],
+        ];
+        let mut descriptors = Mat::from_slice_rows_cols(&values.concat(), 3, 8)?;
+        to_root_sift(&mut descriptors)?;
+
+        for descriptor in descriptors.data_typed::<f32>()?.chunks_exact(8) {
+            assert!(descriptor.iter().all(|value| *value >= 0.0));
+            let l2_norm = descriptor
+                .iter()
+                .map(|value| value * value)
+                .sum::<f32>()
+                .sqrt();
+            assert!((l2_norm - 1.0).abs() < 1e-5, "{:?}", descriptor);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn samples_edge_pixels_for_subpixel_keypoints() -> Result<()> {
+        let (rows, cols) = (3, 4);
+        let mut image = Mat::new_rows_cols_with_default(
+            rows,
+            cols,
+            cv::core::CV_8UC3,
+            cv::core::Scalar::all(0.0),
+        )?;
+        *image.at_2d_mut::<cv::core::Vec3b>(0, 0)? = cv::core::VecN([0, 0, 255]);
+        *image.at_2d_mut::<cv::core::Vec3b>(rows - 1, cols - 1)? = cv::core::VecN([255, 0, 0]);
+
+        // Keypoints can sit less than a pixel outside of the image, past either edge
+        let color = pixel_color(&image, &Point2f::new(cols as f32 + 0.2, rows as f32 + 0.2))?;
+        assert_eq!(color, Vec3::new(0.0, 0.0, 1.0));
+        let color = pixel_color(&image, &Point2f::new(-0.4, -0.4))?;
+        assert_eq!(color, Vec3::new(1.0, 0.0, 0.0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn converts_bgr_pixels_to_rgb() -> Result<()> {
+        // OpenCV's pure red is (0, 0, 255)
+        let image = Mat::new_rows_cols_with_default(
+            1,
+            1,
+            cv::core::CV_8UC3,
+            cv::core::Scalar::new(0.0, 0.0, 255.0, 0.0),
+        )?;
+
+        let color = pixel_color(&image, &Point2f::new(0.5, 0.5))?;
+        assert_eq!(color, Vec3::new(1.0, 0.0, 0.0));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,175 @@
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::{
+    render_state::{CloudStats, Point},
+    timings,
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static STATS: Mutex<Stats> = Mutex::new(Stats {
+    keypoints: Vec::new(),
+    pairs: Vec::new(),
+    cloud: None,
+});
+
+/// Match counts of a pair of images at each step of the matching, and the points triangulated from
+/// it.
+#[derive(Serialize)]
+pub struct PairStats {
+    pub left: usize,
+    pub right: usize,
+    pub candidates: usize,
+    pub ratio_test: usize,
+    pub cross_check: usize,
+    pub geometric: usize,
+    pub capped: usize,
+    pub points: usize,
+}
+
+#[derive(Serialize)]
+struct BoundingBox {
+    min: [f32; 3],
+    max: [f32; 3],
+    centroid: [f32; 3],
+}
+
+#[derive(Serialize)]
+struct CloudSummary {
+    points: usize,
+    /// Mean over the points whose error is known, in pixels.
+    mean_reproj_error: Option<f32>,
+    bounding_box: Option<BoundingBox>,
+}
+
+struct Stats {
+    keypoints: Vec<usize>,
+    pairs: Vec<PairStats>,
+    cloud: Option<CloudSummary>,
+}
+
+#[derive(Serialize)]
+struct ImageReport<'a> {
+    index: usize,
+    name: Option<&'a str>,
+    keypoints: usize,
+}
+
+#[derive(Serialize)]
+struct TimingReport {
+    stage: &'static str,
+    total_ms: f64,
+    calls: usize,
+}
+
+/// The file written by `--stats-json`.
+#[derive(Serialize)]
+struct Report<'a> {
+    images: Vec<ImageReport<'a>>,
+    pairs: &'a [PairStats],
+    #[serde(flatten)]
+    cloud: Option<&'a CloudSummary>,
+    timings: Vec<TimingReport>,
+    runtime_ms: f64,
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Runs `f` on the statistics if they are collected.
+fn update(f: impl FnOnce(&mut Stats)) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    f(&mut STATS.lock().unwrap_or_else(|error| error.into_inner()));
+}
+
+/// Records the number of keypoints of each image, replacing those of a previous detector.
+pub fn record_keypoints(counts: impl IntoIterator<Item = usize>) {
+    update(|stats| stats.keypoints = counts.into_iter().collect());
+}
+
+pub fn record_pair(pair: PairStats) {
+    update(|stats| stats.pairs.push(pair));
+}
+
+/// Records the final point cloud.
+pub fn record_cloud(points: &[Point]) {
+    update(|stats| {
+        let errors = points
+            .iter()
+            .filter_map(|point| point.reproj_error)
+            .filter(|error| error.is_finite())
+            .collect::<Vec<_>>();
+        stats.cloud = Some(CloudSummary {
+            points: points.len(),
+            mean_reproj_error: (!errors.is_empty())
+                .then(|| errors.iter().sum::<f32>() / errors.len() as f32),
+            bounding_box: CloudStats::of(points).map(|cloud_stats| BoundingBox {
+                min: cloud_stats.min.to_array(),
+                max: cloud_stats.max.to_array(),
+                centroid: cloud_stats.centroid.to_array(),
+            }),
+        });
+    });
+}
+
+/// Records the size of a cloud that was streamed to disk rather than kept in memory.
+pub fn record_point_count(point_count: usize) {
+    update(|stats| {
+        stats.cloud = Some(CloudSummary {
+            points: point_count,
+            mean_reproj_error: None,
+            bounding_box: None,
+        });
+    });
+}
+
+/// Writes the collected statistics as JSON, with the time spent in each stage.
+pub fn write(path: &Path, image_names: &[String], runtime: Duration) -> Result<()> {
+    let stats = STATS.lock().unwrap_or_else(|error| error.into_inner());
+    let report = Report {
+        images: stats
+            .keypoints
+            .iter()
+            .enumerate()
+            .map(|(index, keypoints)| ImageReport {
+                index,
+                name: image_names.get(index).map(String::as_str),
+                keypoints: *keypoints,
+            })
+            .collect(),
+        pairs: &stats.pairs,
+        cloud: stats.cloud.as_ref(),
+        timings: timings::totals()
+            .into_iter()
+            .map(|(stage, total, calls)| TimingReport {
+                stage,
+                total_ms: total.as_secs_f64() * 1000.0,
+                calls,
+            })
+            .collect(),
+        runtime_ms: runtime.as_secs_f64() * 1000.0,
+    };
+
+    let contents = serde_json::to_string_pretty(&report)?;
+    std::fs::write(path, contents)
+        .with_context(|| format!("Failed to write statistics file {}", path.to_string_lossy()))?;
+    log::info!(
+        "Wrote the reconstruction statistics to {}",
+        path.to_string_lossy()
+    );
+
+    Ok(())
+}
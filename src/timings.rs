@@ -0,0 +1,95 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::events;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TIMINGS: Mutex<Vec<StageTiming>> = Mutex::new(Vec::new());
+
+struct StageTiming {
+    name: &'static str,
+    total: Duration,
+    calls: usize,
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Adds the duration to the total of the stage, if timings are enabled.
+pub fn record(name: &'static str, elapsed: Duration) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mut timings = TIMINGS.lock().unwrap_or_else(|error| error.into_inner());
+    match timings.iter_mut().find(|timing| timing.name == name) {
+        Some(timing) => {
+            timing.total += elapsed;
+            timing.calls += 1;
+        }
+        None => timings.push(StageTiming {
+            name,
+            total: elapsed,
+            calls: 1,
+        }),
+    }
+}
+
+pub fn time<T>(name: &'static str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    record(name, start.elapsed());
+
+    result
+}
+
+/// The total time spent in each stage and its number of calls, in the order they first ran.
+pub fn totals() -> Vec<(&'static str, Duration, usize)> {
+    let timings = TIMINGS.lock().unwrap_or_else(|error| error.into_inner());
+    timings
+        .iter()
+        .map(|timing| (timing.name, timing.total, timing.calls))
+        .collect()
+}
+
+/// Prints the total time spent in each stage, in the order they first ran. Stages can contain
+/// others (e.g. triangulation includes matching), so the percentages don't add up to 100.
+pub fn print_summary(runtime: Duration) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let timings = TIMINGS.lock().unwrap_or_else(|error| error.into_inner());
+    let mut lines = vec![format!(
+        "{:<24} {:>12} {:>8} {:>8}",
+        "stage", "total (ms)", "%", "calls"
+    )];
+    for timing in timings.iter() {
+        lines.push(format!(
+            "{:<24} {:>12.1} {:>8.1} {:>8}",
+            timing.name,
+            timing.total.as_secs_f64() * 1000.0,
+            100.0 * timing.total.as_secs_f64() / runtime.as_secs_f64().max(f64::EPSILON),
+            timing.calls
+        ));
+    }
+    lines.push(format!(
+        "{:<24} {:>12.1}",
+        "total",
+        runtime.as_secs_f64() * 1000.0
+    ));
+
+    for line in lines {
+        // stdout is reserved for the JSON events
+        match events::json_output() {
+            true => log::info!("{}", line),
+            false => println!("{}", line),
+        }
+    }
+}
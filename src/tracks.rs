@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+
+/// A keypoint of an image, as the index of the image and the index of the keypoint in it.
+pub type Observation = (usize, usize);
+
+/// Links the keypoint matches of all the image pairs into tracks, the sets of keypoints that are
+/// (transitively) matched together and should therefore be views of the same 3D point.
+#[derive(Default)]
+pub struct TrackBuilder {
+    nodes: HashMap<Observation, usize>,
+    observations: Vec<Observation>,
+    // Union-find forest over the observations
+    parents: Vec<usize>,
+}
+
+impl TrackBuilder {
+    fn node(&mut self, observation: Observation) -> usize {
+        *self.nodes.entry(observation).or_insert_with(|| {
+            self.observations.push(observation);
+            self.parents.push(self.parents.len());
+            self.parents.len() - 1
+        })
+    }
+
+    fn root(&mut self, mut node: usize) -> usize {
+        while self.parents[node] != node {
+            self.parents[node] = self.parents[self.parents[node]];
+            node = self.parents[node];
+        }
+
+        node
+    }
+
+    pub fn add_match(&mut self, left: Observation, right: Observation) {
+        let left = self.node(left);
+        let right = self.node(right);
+        let (left_root, right_root) = (self.root(left), self.root(right));
+        self.parents[right_root] = left_root;
+    }
+
+    /// The tracks, with their observations sorted by image. Tracks that contain several keypoints of
+    /// the same image are inconsistent (one of the matches is wrong) and are discarded.
+    pub fn build(mut self) -> Vec<Vec<Observation>> {
+        let mut tracks_by_root: HashMap<usize, Vec<Observation>> = HashMap::new();
+        for node in 0..self.observations.len() {
+            let root = self.root(node);
+            tracks_by_root
+                .entry(root)
+                .or_default()
+                .push(self.observations[node]);
+        }
+
+        let track_count = tracks_by_root.len();
+        let mut tracks = tracks_by_root
+            .into_values()
+            .filter_map(|mut track| {
+                track.sort_unstable();
+                let consistent = track.windows(2).all(|pair| pair[0].0 != pair[1].0);
+                consistent.then_some(track)
+            })
+            .collect::<Vec<_>>();
+        // The map's order is random, the points shouldn't be
+        tracks.sort_unstable();
+        log::info!(
+            "Built {} tracks from {} matched keypoints ({} inconsistent tracks discarded)",
+            tracks.len(),
+            self.observations.len(),
+            track_count - tracks.len()
+        );
+
+        tracks
+    }
+}
@@ -0,0 +1,76 @@
+use anyhow::{bail, Context, Result};
+use morrigu::math_types::{Mat3, Mat4, Vec4};
+
+use crate::{pose::CameraView, render_state::Point};
+
+const TOLERANCE: f32 = 1e-3;
+
+/// Parses a row-major 4x4 matrix from either a file path or the 16 values themselves (separated by
+/// whitespace or commas), and checks that it is a rigid transform, or a similarity if allowed.
+pub fn parse_transform(arg: &str, allow_similarity: bool) -> Result<Mat4> {
+    let path = std::path::Path::new(arg);
+    let contents = if path.is_file() {
+        std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read transform file {}", arg))?
+    } else {
+        arg.to_owned()
+    };
+
+    let values = contents
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|token| !token.is_empty())
+        .map(|token| {
+            token
+                .parse::<f32>()
+                .with_context(|| format!("Invalid transform value \"{}\"", token))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if values.len() != 16 {
+        bail!("Expected 16 transform values, found {}", values.len());
+    }
+
+    let transform = Mat4::from_cols_slice(&values).transpose();
+    if !transform.row(3).abs_diff_eq(Vec4::W, TOLERANCE) {
+        bail!("The last row of the transform must be 0 0 0 1");
+    }
+
+    let linear = Mat3::from_mat4(transform);
+    let determinant = linear.determinant();
+    if determinant <= 0.0 {
+        bail!(
+            "The transform must preserve orientation (determinant is {})",
+            determinant
+        );
+    }
+    let scale = determinant.cbrt();
+    if !allow_similarity && (scale - 1.0).abs() > TOLERANCE {
+        bail!(
+            "The transform has a scale factor of {}, use --allow-similarity to accept it",
+            scale
+        );
+    }
+    let rotation = linear * (1.0 / scale);
+    if !(rotation.transpose() * rotation).abs_diff_eq(Mat3::IDENTITY, TOLERANCE) {
+        bail!("The upper 3x3 block of the transform is not a (scaled) rotation");
+    }
+
+    Ok(transform)
+}
+
+pub fn apply_transform(transform: &Mat4, points: &mut [Point], cameras: &mut [CameraView]) {
+    for point in points {
+        point.position = transform.transform_point3(point.position);
+        if let Some(normal) = &mut point.normal {
+            *normal = transform.transform_vector3(*normal).normalize();
+        }
+    }
+
+    // Keep the projections consistent with the new world frame: P' * (M * X) = P * X
+    let inverse = transform.inverse();
+    for camera in cameras {
+        camera.center = transform.transform_point3(camera.center);
+        camera.forward = transform.transform_vector3(camera.forward).normalize();
+        camera.up = transform.transform_vector3(camera.up).normalize();
+        camera.projection *= inverse;
+    }
+}
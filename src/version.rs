@@ -0,0 +1,81 @@
+use std::fmt::Write;
+
+use anyhow::Result;
+use itertools::Itertools;
+use opencv as cv;
+
+/// The manifest, to report the versions of the dependencies the binary was built against.
+const MANIFEST: &str = include_str!("../Cargo.toml");
+/// The lines of OpenCV's build information worth reporting: the modules that were built, and the
+/// acceleration libraries.
+const OPENCV_BUILD_KEYS: [&str; 9] = [
+    "To be built:",
+    "Parallel framework:",
+    "Baseline:",
+    "Dispatched code generation:",
+    "Intel IPP:",
+    "Lapack:",
+    "OpenCL:",
+    "NVIDIA CUDA:",
+    "cuDNN:",
+];
+
+/// The requirement of the dependency in the manifest, e.g. `"0.88"`.
+fn dependency_spec(name: &str) -> Option<&'static str> {
+    MANIFEST.lines().find_map(|line| {
+        let spec = line.strip_prefix(name)?.trim_start().strip_prefix('=')?;
+        Some(spec.trim())
+    })
+}
+
+/// The versions of the program, of its dependencies and of the linked OpenCV library, with the
+/// highlights of OpenCV's build configuration.
+pub fn detailed_version() -> Result<String> {
+    let mut report = String::new();
+    writeln!(
+        report,
+        "{} {}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    )?;
+    writeln!(
+        report,
+        "morrigu: {}",
+        dependency_spec("morrigu").unwrap_or("unknown")
+    )?;
+    writeln!(
+        report,
+        "opencv crate: {}",
+        dependency_spec("opencv").unwrap_or("unknown")
+    )?;
+
+    writeln!(report, "OpenCV: {}", cv::core::get_version_string()?)?;
+    // SIFT moved from xfeatures2d to the main repository in 4.4
+    let sift_available = (
+        cv::core::get_version_major()?,
+        cv::core::get_version_minor()?,
+    ) >= (4, 4);
+    writeln!(
+        report,
+        "SIFT in features2d: {}",
+        if sift_available { "yes" } else { "no" }
+    )?;
+    #[cfg(feature = "cuda")]
+    writeln!(
+        report,
+        "cuda feature: enabled, {} CUDA device(s)",
+        cv::core::get_cuda_enabled_device_count()?
+    )?;
+    #[cfg(not(feature = "cuda"))]
+    writeln!(report, "cuda feature: disabled")?;
+
+    writeln!(report, "OpenCV build:")?;
+    for line in cv::core::get_build_information()?.lines() {
+        let line = line.trim();
+        if OPENCV_BUILD_KEYS.iter().any(|key| line.starts_with(key)) {
+            writeln!(report, "  {}", line.split_whitespace().join(" "))?;
+        }
+    }
+
+    Ok(report)
+}